@@ -0,0 +1,604 @@
+use super::{
+    api::{authorize, authorize_bearer, client_ip, Access, Response},
+    archive::parse_date,
+    audit::AuditLog,
+    backup::{checksum, create_backup, restore_backup},
+    config::Config,
+    creds::{Auth, Role},
+    data::{read_from_json, write_to_json, AppData, JsonData},
+    import::{import, parse_csv, ConflictPolicy},
+    ledger::Ledger,
+    retention::enforce,
+    tokens::{ApiKeys, Scope},
+};
+use chrono::prelude::*;
+use ntex::{
+    http::header,
+    util::Bytes,
+    web::{
+        get, post,
+        types::{Json, Query, State},
+        Error as WebError, HttpRequest, HttpResponse,
+    },
+};
+use ntex_session::Session;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::{io::ErrorKind, path::Path, sync::Arc};
+use tokio::sync::Mutex;
+
+/*
+ * struct ImportQuery {
+ *   policy: ConflictPolicy,
+ *   preview: bool,
+ * }
+ *
+ * Query for /api/admin/import, both optional, defaulting to skipping conflicts and applying the import.
+ */
+
+#[derive(Deserialize)]
+struct ImportQuery {
+    #[serde(default)]
+    policy: ConflictPolicy,
+    #[serde(default)]
+    preview: bool,
+}
+
+/*
+ * struct RetentionQuery {
+ *   dry_run: bool,
+ * }
+ *
+ * Query for POST /api/admin/retention, dry_run only reports what would be deleted.
+ */
+
+#[derive(Deserialize)]
+struct RetentionQuery {
+    #[serde(default)]
+    dry_run: bool,
+}
+
+/*
+ * struct AuditQuery {
+ *   page: usize,
+ *   per_page: usize,
+ *   user: Option<String>,
+ *   action: Option<String>,
+ * }
+ *
+ * Query for /api/admin/audit, pages start at 1 with 50 entries each unless asked otherwise.
+ * user and action only keep the entries matching them.
+ */
+
+#[derive(Deserialize)]
+#[serde(default)]
+struct AuditQuery {
+    page: usize,
+    per_page: usize,
+    user: Option<String>,
+    action: Option<String>,
+}
+
+impl Default for AuditQuery {
+    fn default() -> Self {
+        AuditQuery {
+            page: 1,
+            per_page: 50,
+            user: None,
+            action: None,
+        }
+    }
+}
+
+/*
+ * const MAX_AUDIT_PER_PAGE: usize
+ *
+ * The most audit entries a single page can ask for.
+ */
+
+const MAX_AUDIT_PER_PAGE: usize = 500;
+
+/*
+ * struct CorrectionPost {
+ *   date: String,
+ *   delta: i32,
+ *   reason: String,
+ * }
+ *
+ * The JSON request data for correcting a day's count by `delta`.
+ */
+
+#[derive(Deserialize)]
+struct CorrectionPost {
+    date: String,
+    delta: i32,
+    reason: String,
+}
+
+/*
+ * struct ImportErrorResponse {
+ *   title: String,
+ *   message: String,
+ *   errors: Vec<String>,
+ * }
+ *
+ * Response for a CSV that didn't validate, with every problem found.
+ */
+
+#[derive(Serialize)]
+struct ImportErrorResponse {
+    title: String,
+    message: String,
+    errors: Vec<String>,
+}
+
+/*
+ * fn bad_request(message: String) -> HttpResponse {}
+ *
+ * Used when the uploaded data is rejected, with the reason as the message.
+ */
+
+fn bad_request(message: String) -> HttpResponse {
+    HttpResponse::BadRequest()
+        .content_type("application/json")
+        .json(&Response {
+            title: "Bad Request".to_string(),
+            message,
+        })
+}
+
+/*
+ * https://url.tld/api/admin/backup
+ *
+ * Downloads everything under state/ but the keys and live stores (see backup.rs) as a single .tar.gz with a manifest.json inside,
+ * the SHA-256 of the whole archive is sent along in X-Checksum-Sha256.
+ */
+
+#[get("/api/admin/backup")]
+pub async fn backup(
+    req: HttpRequest,
+    session: Session,
+    auth: State<Arc<Auth>>,
+    audit: State<Arc<AuditLog>>,
+    data: State<Arc<Mutex<AppData>>>,
+) -> Result<HttpResponse, WebError> {
+    let user = match authorize(&session, &auth, Role::Admin)? {
+        Access::Granted(login) => login.username,
+        Access::Denied(response) => return Ok(response),
+    };
+
+    // Hold the lock so no scan writes to the state while it's being packed.
+    let _app_data = data.lock().await;
+    let archive = create_backup(Path::new("./state"))?;
+    audit.record(
+        &user,
+        &client_ip(&req),
+        "backup",
+        json!({ "bytes": archive.len() }),
+    )?;
+
+    Ok(HttpResponse::Ok()
+        .content_type("application/gzip")
+        .header(
+            header::CONTENT_DISPOSITION,
+            format!(
+                "attachment; filename=\"qrcode-analytic-{}.tar.gz\"",
+                Utc::now().format("%Y%m%dT%H%M%SZ")
+            ),
+        )
+        .header("X-Checksum-Sha256", checksum(&archive))
+        .body(archive))
+}
+
+/*
+ * https://url.tld/api/admin/restore - POST
+ *
+ * Takes a backup made by /api/admin/backup as the request body,
+ * optionally checked against the X-Checksum-Sha256 header.
+ * The current state is snapshotted to snapshots/ before the backup is swapped in,
+ * the current keys and live stores are kept.
+ */
+
+#[post("/api/admin/restore")]
+pub async fn restore(
+    req: HttpRequest,
+    session: Session,
+    auth: State<Arc<Auth>>,
+    audit: State<Arc<AuditLog>>,
+    data: State<Arc<Mutex<AppData>>>,
+    ledger: State<Arc<Mutex<Ledger>>>,
+    body: Bytes,
+) -> Result<HttpResponse, WebError> {
+    let user = match authorize(&session, &auth, Role::Admin)? {
+        Access::Granted(login) => login.username,
+        Access::Denied(response) => return Ok(response),
+    };
+
+    if let Some(expected) = req.headers().get("X-Checksum-Sha256") {
+        if expected.to_str().unwrap_or_default() != checksum(&body) {
+            return Ok(bad_request(
+                "Checksum mismatch, the upload is corrupted.".to_string(),
+            ));
+        }
+    }
+
+    let path = Path::new("./state");
+    let mut app_data = data.lock().await;
+
+    let snapshot = match restore_backup(path, &body) {
+        Ok(snapshot) => snapshot,
+        Err(error) if error.kind() == ErrorKind::InvalidData => {
+            return Ok(bad_request(error.to_string()));
+        }
+        Err(error) => return Err(error.into()),
+    };
+
+    let restored = read_from_json(path).await.unwrap_or_default();
+    app_data.state = AppData::from(restored).state;
+    app_data.touch();
+    *ledger.lock().await = Ledger::load(path)?;
+    audit.record(
+        &user,
+        &client_ip(&req),
+        "restore",
+        json!({ "snapshot": snapshot.display().to_string() }),
+    )?;
+
+    tracing::info!(
+        "Restored state from backup, previous state is in {}",
+        snapshot.display()
+    );
+
+    Ok(HttpResponse::Ok()
+        .content_type("application/json")
+        .json(&Response {
+            title: "Restored".to_string(),
+            message: format!(
+                "Backup restored, the previous state was saved to {}. Restart to apply configuration changes.",
+                snapshot.display()
+            ),
+        }))
+}
+
+/*
+ * https://url.tld/api/admin/import?policy=skip|overwrite|add&preview=true - POST
+ *
+ * Imports daily counts from a CSV body with `date` and `count` columns.
+ * With preview=true nothing gets written and the response lists the conflicts the import would resolve.
+ */
+
+#[post("/api/admin/import")]
+pub async fn import_csv(
+    req: HttpRequest,
+    session: Session,
+    auth: State<Arc<Auth>>,
+    audit: State<Arc<AuditLog>>,
+    data: State<Arc<Mutex<AppData>>>,
+    config: State<Arc<Config>>,
+    query: Query<ImportQuery>,
+    body: Bytes,
+) -> Result<HttpResponse, WebError> {
+    let user = match authorize(&session, &auth, Role::Admin)? {
+        Access::Granted(login) => login.username,
+        Access::Denied(response) => return Ok(response),
+    };
+
+    let rows = match parse_csv(&body[..]) {
+        Ok(rows) => rows,
+        Err(errors) => {
+            return Ok(HttpResponse::BadRequest()
+                .content_type("application/json")
+                .json(&ImportErrorResponse {
+                    title: "Bad Request".to_string(),
+                    message: "The CSV didn't validate, nothing was imported.".to_string(),
+                    errors,
+                }));
+        }
+    };
+
+    let path = Path::new("./state");
+    let mut app_data = data.lock().await;
+    let report = import(
+        path,
+        &mut app_data,
+        &rows,
+        query.policy,
+        config.archive_after_days,
+        !query.preview,
+    )?;
+
+    if report.applied {
+        write_to_json(path, JsonData::from(&*app_data)).await?;
+        audit.record(
+            &user,
+            &client_ip(&req),
+            "import",
+            json!({
+                "rows": report.rows,
+                "added": report.added,
+                "conflicts": report.conflicts.len(),
+                "policy": format!("{:?}", query.policy),
+            }),
+        )?;
+        tracing::info!(
+            "Imported {} rows, {} new days, {} conflicts.",
+            report.rows,
+            report.added,
+            report.conflicts.len()
+        );
+    }
+
+    Ok(HttpResponse::Ok()
+        .content_type("application/json")
+        .json(&report))
+}
+
+/*
+ * https://url.tld/api/admin/retention
+ *
+ * Dry run of the retention policy, reports what the next run would delete without touching anything.
+ */
+
+#[get("/api/admin/retention")]
+pub async fn retention_report(
+    session: Session,
+    auth: State<Arc<Auth>>,
+    data: State<Arc<Mutex<AppData>>>,
+    ledger: State<Arc<Mutex<Ledger>>>,
+    config: State<Arc<Config>>,
+) -> Result<HttpResponse, WebError> {
+    if let Access::Denied(response) = authorize(&session, &auth, Role::Admin)? {
+        return Ok(response);
+    }
+
+    let mut app_data = data.lock().await;
+    let mut ledger = ledger.lock().await;
+    let report = enforce(
+        Path::new("./state"),
+        &mut app_data,
+        &mut ledger,
+        &config,
+        true,
+    )?;
+
+    Ok(HttpResponse::Ok()
+        .content_type("application/json")
+        .json(&report))
+}
+
+/*
+ * https://url.tld/api/admin/retention?dry_run=true - POST
+ *
+ * Enforces the retention policy right away instead of waiting for the scheduled run.
+ */
+
+#[post("/api/admin/retention")]
+pub async fn enforce_retention(
+    req: HttpRequest,
+    session: Session,
+    auth: State<Arc<Auth>>,
+    audit: State<Arc<AuditLog>>,
+    data: State<Arc<Mutex<AppData>>>,
+    ledger: State<Arc<Mutex<Ledger>>>,
+    config: State<Arc<Config>>,
+    query: Query<RetentionQuery>,
+) -> Result<HttpResponse, WebError> {
+    let user = match authorize(&session, &auth, Role::Admin)? {
+        Access::Granted(login) => login.username,
+        Access::Denied(response) => return Ok(response),
+    };
+
+    let path = Path::new("./state");
+    let mut app_data = data.lock().await;
+    let mut ledger = ledger.lock().await;
+    let report = enforce(path, &mut app_data, &mut ledger, &config, query.dry_run)?;
+
+    if !report.dry_run && report.days_deleted > 0 {
+        write_to_json(path, JsonData::from(&*app_data)).await?;
+    }
+    if !report.dry_run {
+        audit.record(
+            &user,
+            &client_ip(&req),
+            "retention",
+            json!({
+                "events_deleted": report.events_deleted,
+                "days_deleted": report.days_deleted,
+            }),
+        )?;
+    }
+    tracing::info!(
+        "Retention triggered by an admin, {} events and {} days{}.",
+        report.events_deleted,
+        report.days_deleted,
+        if report.dry_run {
+            " would be deleted"
+        } else {
+            " deleted"
+        }
+    );
+
+    Ok(HttpResponse::Ok()
+        .content_type("application/json")
+        .json(&report))
+}
+
+/*
+ * https://url.tld/api/admin/ledger
+ *
+ * Walks the scan event and correction chains and reports the first broken link, if any,
+ * and every day whose stored count doesn't match its scan events.
+ */
+
+#[get("/api/admin/ledger")]
+pub async fn verify_ledger(
+    session: Session,
+    auth: State<Arc<Auth>>,
+    data: State<Arc<Mutex<AppData>>>,
+    ledger: State<Arc<Mutex<Ledger>>>,
+) -> Result<HttpResponse, WebError> {
+    if let Access::Denied(response) = authorize(&session, &auth, Role::Admin)? {
+        return Ok(response);
+    }
+
+    let app_data = data.lock().await;
+    let verification = ledger
+        .lock()
+        .await
+        .verify(Path::new("./state"), &app_data.state)?;
+    if let Some(broken) = &verification.first_broken {
+        tracing::warn!(
+            "Ledger verification failed at {} #{}: {}",
+            broken.store,
+            broken.entry,
+            broken.reason
+        );
+    }
+    for day in &verification.mismatched_days {
+        tracing::warn!(
+            "{} has {} scans stored but {} scan events.",
+            day.date,
+            day.stored,
+            day.events
+        );
+    }
+
+    Ok(HttpResponse::Ok()
+        .content_type("application/json")
+        .json(&verification))
+}
+
+/*
+ * https://url.tld/api/admin/corrections
+ *
+ * Lists every correction made so far.
+ */
+
+#[get("/api/admin/corrections")]
+pub async fn list_corrections(
+    session: Session,
+    auth: State<Arc<Auth>>,
+    data: State<Arc<Mutex<AppData>>>,
+    ledger: State<Arc<Mutex<Ledger>>>,
+) -> Result<HttpResponse, WebError> {
+    if let Access::Denied(response) = authorize(&session, &auth, Role::Admin)? {
+        return Ok(response);
+    }
+
+    let _app_data = data.lock().await;
+    let ledger = ledger.lock().await;
+
+    Ok(HttpResponse::Ok()
+        .content_type("application/json")
+        .json(&ledger.corrections))
+}
+
+/*
+ * https://url.tld/api/admin/corrections - POST
+ *
+ * Records a signed correction of a day's count, data.json itself is never rewritten.
+ */
+
+#[post("/api/admin/corrections")]
+pub async fn add_correction(
+    req: HttpRequest,
+    session: Session,
+    auth: State<Arc<Auth>>,
+    audit: State<Arc<AuditLog>>,
+    data: State<Arc<Mutex<AppData>>>,
+    ledger: State<Arc<Mutex<Ledger>>>,
+    json: Json<CorrectionPost>,
+) -> Result<HttpResponse, WebError> {
+    let user = match authorize(&session, &auth, Role::Admin)? {
+        Access::Granted(login) => login.username,
+        Access::Denied(response) => return Ok(response),
+    };
+
+    if parse_date(&json.date).is_none() {
+        return Ok(bad_request(
+            "Dates have to be formatted as YYYY-MM-DD.".to_string(),
+        ));
+    }
+    if json.delta == 0 || json.reason.trim().is_empty() {
+        return Ok(bad_request(
+            "A correction needs a non-zero delta and a reason.".to_string(),
+        ));
+    }
+
+    let mut app_data = data.lock().await;
+    let correction = ledger.lock().await.record_correction(
+        Path::new("./state"),
+        user,
+        json.date.clone(),
+        json.delta,
+        json.reason.trim().to_string(),
+    )?;
+    app_data.touch();
+    audit.record(
+        &correction.user,
+        &client_ip(&req),
+        "correction",
+        json!({
+            "date": correction.date,
+            "delta": correction.delta,
+            "reason": correction.reason,
+        }),
+    )?;
+
+    tracing::info!(
+        "{} corrected {} by {}: {}",
+        correction.user,
+        correction.date,
+        correction.delta,
+        correction.reason
+    );
+
+    Ok(HttpResponse::Ok()
+        .content_type("application/json")
+        .json(&correction))
+}
+
+/*
+ * https://url.tld/api/admin/audit?page=1&per_page=50&user=&action=
+ *
+ * Pages through the audit log, newest entries first, along with the total number of matching entries.
+ * Also takes a JWT from /api/token with the audit:read scope, to ship the log elsewhere.
+ */
+
+#[get("/api/admin/audit")]
+pub async fn audit_log(
+    req: HttpRequest,
+    session: Session,
+    auth: State<Arc<Auth>>,
+    api_keys: State<Arc<ApiKeys>>,
+    audit: State<Arc<AuditLog>>,
+    query: Query<AuditQuery>,
+) -> Result<HttpResponse, WebError> {
+    match authorize_bearer(&req, &api_keys, Scope::AuditRead) {
+        Some(Access::Granted(_)) => {}
+        Some(Access::Denied(response)) => return Ok(response),
+        None => {
+            if let Access::Denied(response) = authorize(&session, &auth, Role::Admin)? {
+                return Ok(response);
+            }
+        }
+    }
+
+    if query.page == 0 || query.per_page == 0 || query.per_page > MAX_AUDIT_PER_PAGE {
+        return Ok(bad_request(format!(
+            "page starts at 1 and per_page has to be between 1 and {}.",
+            MAX_AUDIT_PER_PAGE
+        )));
+    }
+
+    let page = audit.page(
+        query.page,
+        query.per_page,
+        query.user.as_deref(),
+        query.action.as_deref(),
+    )?;
+
+    Ok(HttpResponse::Ok()
+        .content_type("application/json")
+        .json(&page))
+}
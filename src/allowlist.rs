@@ -0,0 +1,439 @@
+use super::{api::Response, config::AllowlistConfig, csrf::decoded_path};
+use ipnet::IpNet;
+use ntex::{
+    service::{Middleware, Service, ServiceCtx},
+    web::{Error as WebError, HttpResponse, WebRequest, WebResponse},
+};
+use std::{
+    io::{Error, ErrorKind},
+    net::{IpAddr, SocketAddr},
+    rc::Rc,
+};
+
+/*
+ * pub struct ClientIp(pub IpAddr);
+ *
+ * The address a request came from once trusted proxies are looked through,
+ * stored in the request's extensions for client_ip() and the session store.
+ */
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ClientIp(pub IpAddr);
+
+/*
+ * pub enum RouteGroup {
+ *   Dashboard,
+ *   AdminApi,
+ *   ScanApi,
+ * }
+ *
+ * The parts of the site that get their own allowlist.
+ * ScanApi is /api itself, AdminApi everything under /api/admin, Dashboard the dashboard,
+ * logging in and out, the rest of /api and any other path. The public pages aren't in any group.
+ */
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RouteGroup {
+    Dashboard,
+    AdminApi,
+    ScanApi,
+}
+
+/*
+ * fn below(path: &str, route: &str) -> bool {}
+ *
+ * Whether `path` is `route` or something under it.
+ */
+
+fn below(path: &str, route: &str) -> bool {
+    path.strip_prefix(route)
+        .is_some_and(|rest| rest.is_empty() || rest.starts_with('/'))
+}
+
+/*
+ * pub fn route_group(path: &str) -> Option<RouteGroup> {}
+ *
+ * Which group a request path belongs to, None for the public pages and the files under /html.
+ * Anything else falls under Dashboard, so a path nobody thought of is restricted rather than open.
+ */
+
+pub fn route_group(path: &str) -> Option<RouteGroup> {
+    let path = path.trim_end_matches('/');
+    if path == "/api" {
+        Some(RouteGroup::ScanApi)
+    } else if below(path, "/api/admin") {
+        Some(RouteGroup::AdminApi)
+    } else if ["", "/privacy", "/contact"].contains(&path)
+        || (below(path, "/html") && !path.split('/').any(|segment| segment.starts_with('.')))
+    {
+        None
+    } else {
+        Some(RouteGroup::Dashboard)
+    }
+}
+
+/*
+ * fn parse_networks(field: &str, entries: &[String]) -> Result<Vec<IpNet>, Error> {}
+ *
+ * Parses CIDR ranges ("10.0.0.0/8") and single addresses from the `field` list in config.json.
+ */
+
+fn parse_networks(field: &str, entries: &[String]) -> Result<Vec<IpNet>, Error> {
+    entries
+        .iter()
+        .map(|entry| {
+            let entry = entry.trim();
+            entry
+                .parse::<IpNet>()
+                .or_else(|_| entry.parse::<IpAddr>().map(IpNet::from))
+                .map_err(|_| {
+                    Error::new(
+                        ErrorKind::InvalidInput,
+                        format!(
+                            "ip_allowlist.{} in config.json has \"{}\", which isn't an address or CIDR range.",
+                            field, entry
+                        ),
+                    )
+                })
+        })
+        .collect()
+}
+
+/*
+ * fn parse_hop(hop: &str) -> Option<IpAddr> {}
+ *
+ * One X-Forwarded-For entry, with or without a port.
+ */
+
+fn parse_hop(hop: &str) -> Option<IpAddr> {
+    let hop = hop.trim();
+    hop.parse::<IpAddr>()
+        .or_else(|_| hop.parse::<SocketAddr>().map(|address| address.ip()))
+        .ok()
+        .map(|ip| ip.to_canonical())
+}
+
+/*
+ * pub struct IpAllowlist {
+ *   dashboard: Vec<IpNet>,
+ *   admin_api: Vec<IpNet>,
+ *   scan_api: Vec<IpNet>,
+ *   trusted_proxies: Vec<IpNet>,
+ * }
+ *
+ * Middleware that only lets each route group be reached from its configured networks.
+ * An empty list lets everyone through, but admin_api falls back to dashboard when it's empty,
+ * so the admin endpoints are never more open than the dashboard.
+ * It goes outside the session middleware, so refused requests never get a session.
+ */
+
+#[derive(Clone)]
+pub struct IpAllowlist {
+    dashboard: Vec<IpNet>,
+    admin_api: Vec<IpNet>,
+    scan_api: Vec<IpNet>,
+    trusted_proxies: Vec<IpNet>,
+}
+
+/*
+ * impl IpAllowlist {
+ *   pub fn new(config: &AllowlistConfig) -> Result<Self, Error> {}
+ *   fn trusted(&self, ip: IpAddr) -> bool {}
+ *   pub fn client_ip(&self, peer: Option<IpAddr>, forwarded_for: &[&str]) -> Option<IpAddr> {}
+ *   pub fn allows(&self, group: RouteGroup, ip: Option<IpAddr>) -> bool {}
+ * }
+ *
+ * Assorted implementations accessed through `IpAllowlist::function(args)`
+ */
+
+impl IpAllowlist {
+    /*
+     * pub IpAllowlist::new(config: &AllowlistConfig) -> Result<Self, Error> {}
+     *
+     * The allowlists in `config`, an error for entries that don't parse so a typo can't open things up.
+     */
+
+    pub fn new(config: &AllowlistConfig) -> Result<Self, Error> {
+        let dashboard = parse_networks("dashboard", &config.dashboard)?;
+        let admin_api = match parse_networks("admin_api", &config.admin_api)? {
+            admin_api if admin_api.is_empty() => dashboard.clone(),
+            admin_api => admin_api,
+        };
+
+        Ok(IpAllowlist {
+            dashboard,
+            admin_api,
+            scan_api: parse_networks("scan_api", &config.scan_api)?,
+            trusted_proxies: parse_networks("trusted_proxies", &config.trusted_proxies)?,
+        })
+    }
+
+    /*
+     * IpAllowlist::trusted(&self, ip: IpAddr) -> bool {}
+     *
+     * Whether `ip` is one of our reverse proxies.
+     */
+
+    fn trusted(&self, ip: IpAddr) -> bool {
+        self.trusted_proxies
+            .iter()
+            .any(|network| network.contains(&ip))
+    }
+
+    /*
+     * pub IpAllowlist::client_ip(&self, peer: Option<IpAddr>, forwarded_for: &[&str]) -> Option<IpAddr> {}
+     *
+     * The address of whoever made the request. X-Forwarded-For is only believed as far as it was
+     * appended by trusted proxies: it's read from the right, and the first hop that isn't one of
+     * our proxies is the client. Anything further left could have been made up by the client.
+     * None when the connection has no address or a hop we need doesn't parse.
+     */
+
+    pub fn client_ip(&self, peer: Option<IpAddr>, forwarded_for: &[&str]) -> Option<IpAddr> {
+        let mut ip = peer?.to_canonical();
+        let mut hops = forwarded_for
+            .iter()
+            .flat_map(|header| header.split(','))
+            .collect::<Vec<_>>()
+            .into_iter()
+            .rev();
+
+        while self.trusted(ip) {
+            let Some(hop) = hops.next() else {
+                break;
+            };
+            ip = parse_hop(hop)?;
+        }
+        Some(ip)
+    }
+
+    /*
+     * pub IpAllowlist::allows(&self, group: RouteGroup, ip: Option<IpAddr>) -> bool {}
+     *
+     * Whether `ip` may reach `group`. An unknown address only gets into groups open to everyone.
+     */
+
+    pub fn allows(&self, group: RouteGroup, ip: Option<IpAddr>) -> bool {
+        let networks = match group {
+            RouteGroup::Dashboard => &self.dashboard,
+            RouteGroup::AdminApi => &self.admin_api,
+            RouteGroup::ScanApi => &self.scan_api,
+        };
+        networks.is_empty()
+            || ip.is_some_and(|ip| networks.iter().any(|network| network.contains(&ip)))
+    }
+}
+
+/*
+ * impl<S> Middleware<S> for IpAllowlist {}
+ *
+ * Wraps a service so every request is checked against the allowlists first.
+ */
+
+impl<S> Middleware<S> for IpAllowlist {
+    type Service = IpAllowlistMiddleware<S>;
+
+    fn create(&self, service: S) -> Self::Service {
+        IpAllowlistMiddleware {
+            service,
+            inner: Rc::new(self.clone()),
+        }
+    }
+}
+
+/*
+ * pub struct IpAllowlistMiddleware<S> {
+ *   service: S,
+ *   inner: Rc<IpAllowlist>,
+ * }
+ *
+ * The service IpAllowlist creates.
+ */
+
+pub struct IpAllowlistMiddleware<S> {
+    service: S,
+    inner: Rc<IpAllowlist>,
+}
+
+/*
+ * impl<S, Err> Service<WebRequest<Err>> for IpAllowlistMiddleware<S> {}
+ *
+ * Works out the client's address, stores it as ClientIp and refuses the request with a 403
+ * in the usual Response shape when its route group isn't open to that address.
+ * The group comes from the decoded path, so /%61pi/admin is still the admin API.
+ */
+
+impl<S, Err> Service<WebRequest<Err>> for IpAllowlistMiddleware<S>
+where
+    S: Service<WebRequest<Err>, Response = WebResponse, Error = WebError>,
+{
+    type Response = WebResponse;
+    type Error = WebError;
+
+    ntex::service::forward_poll_ready!(service);
+    ntex::service::forward_poll_shutdown!(service);
+
+    async fn call(
+        &self,
+        req: WebRequest<Err>,
+        ctx: ServiceCtx<'_, Self>,
+    ) -> Result<Self::Response, Self::Error> {
+        let forwarded_for = req
+            .headers()
+            .get_all("X-Forwarded-For")
+            .filter_map(|value| value.to_str().ok())
+            .collect::<Vec<_>>();
+        let ip = self
+            .inner
+            .client_ip(req.peer_addr().map(|address| address.ip()), &forwarded_for);
+        if let Some(ip) = ip {
+            req.extensions_mut().insert(ClientIp(ip));
+        }
+
+        if let Some(group) = route_group(&decoded_path(req.path())) {
+            if !self.inner.allows(group, ip) {
+                tracing::warn!(
+                    "Refused {} from {} by the {:?} allowlist.",
+                    req.path(),
+                    ip.map(|ip| ip.to_string()).unwrap_or_default(),
+                    group
+                );
+                let response = HttpResponse::Forbidden()
+                    .content_type("application/json")
+                    .json(&Response {
+                        title: "Forbidden".to_string(),
+                        message: "This part of the site can't be reached from your network."
+                            .to_string(),
+                    });
+                return Ok(req.into_response(response));
+            }
+        }
+
+        ctx.call(&self.service, req).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn allowlist() -> IpAllowlist {
+        IpAllowlist::new(&AllowlistConfig {
+            dashboard: vec!["10.0.0.0/8".to_string(), "192.168.1.20".to_string()],
+            admin_api: vec!["10.1.0.0/16".to_string()],
+            scan_api: Vec::new(),
+            trusted_proxies: vec!["172.16.0.1".to_string(), "172.16.0.2".to_string()],
+        })
+        .unwrap()
+    }
+
+    fn ip(value: &str) -> Option<IpAddr> {
+        Some(value.parse().unwrap())
+    }
+
+    #[test]
+    fn paths_are_grouped() {
+        assert_eq!(route_group("/api"), Some(RouteGroup::ScanApi));
+        assert_eq!(route_group("/api/"), Some(RouteGroup::ScanApi));
+        assert_eq!(route_group("/api/admin/users"), Some(RouteGroup::AdminApi));
+        assert_eq!(route_group("/api/get_data"), Some(RouteGroup::Dashboard));
+        assert_eq!(route_group("/dashboard"), Some(RouteGroup::Dashboard));
+        assert_eq!(route_group("/login/totp"), Some(RouteGroup::Dashboard));
+        assert_eq!(route_group("/"), None);
+        assert_eq!(route_group("/privacy"), None);
+        assert_eq!(route_group("/contact/"), None);
+        assert_eq!(route_group("/html/style.css"), None);
+        assert_eq!(route_group("/apidocs"), Some(RouteGroup::Dashboard));
+    }
+
+    #[test]
+    fn unknown_paths_are_restricted() {
+        assert_eq!(
+            route_group("/state/keys/jwt.key"),
+            Some(RouteGroup::Dashboard)
+        );
+        assert_eq!(
+            route_group("/admin_login.json"),
+            Some(RouteGroup::Dashboard)
+        );
+        assert_eq!(route_group("/htmlx/page"), Some(RouteGroup::Dashboard));
+        assert_eq!(
+            route_group(&decoded_path("/html/%2e%2e/state/sessions.json")),
+            Some(RouteGroup::Dashboard)
+        );
+    }
+
+    #[test]
+    fn encoded_paths_are_grouped_decoded() {
+        assert_eq!(
+            route_group(&decoded_path("/%61pi/admin/users")),
+            Some(RouteGroup::AdminApi)
+        );
+        assert_eq!(
+            route_group(&decoded_path("/api/%61dmin")),
+            Some(RouteGroup::AdminApi)
+        );
+        assert_eq!(
+            route_group(&decoded_path("/d%61shboard")),
+            Some(RouteGroup::Dashboard)
+        );
+    }
+
+    #[test]
+    fn forwarded_for_is_only_believed_from_trusted_proxies() {
+        let allowlist = allowlist();
+
+        assert_eq!(
+            allowlist.client_ip(ip("203.0.113.9"), &["10.0.0.5"]),
+            ip("203.0.113.9")
+        );
+        assert_eq!(
+            allowlist.client_ip(ip("172.16.0.1"), &["1.2.3.4, 10.0.0.5"]),
+            ip("10.0.0.5")
+        );
+        assert_eq!(
+            allowlist.client_ip(ip("172.16.0.1"), &["10.0.0.5", "203.0.113.9, 172.16.0.2"]),
+            ip("203.0.113.9")
+        );
+        assert_eq!(
+            allowlist.client_ip(ip("172.16.0.1"), &["10.0.0.5:51234"]),
+            ip("10.0.0.5")
+        );
+        assert_eq!(allowlist.client_ip(ip("172.16.0.1"), &[]), ip("172.16.0.1"));
+        assert_eq!(allowlist.client_ip(ip("172.16.0.1"), &["bogus"]), None);
+        assert_eq!(
+            allowlist.client_ip(ip("::ffff:10.0.0.5"), &[]),
+            ip("10.0.0.5")
+        );
+    }
+
+    #[test]
+    fn groups_have_their_own_networks() {
+        let allowlist = allowlist();
+
+        assert!(allowlist.allows(RouteGroup::Dashboard, ip("10.20.30.40")));
+        assert!(allowlist.allows(RouteGroup::Dashboard, ip("192.168.1.20")));
+        assert!(!allowlist.allows(RouteGroup::Dashboard, ip("192.168.1.21")));
+        assert!(!allowlist.allows(RouteGroup::Dashboard, None));
+
+        assert!(allowlist.allows(RouteGroup::AdminApi, ip("10.1.2.3")));
+        assert!(!allowlist.allows(RouteGroup::AdminApi, ip("10.2.0.1")));
+
+        assert!(allowlist.allows(RouteGroup::ScanApi, ip("203.0.113.9")));
+        assert!(allowlist.allows(RouteGroup::ScanApi, None));
+
+        let fallback = IpAllowlist::new(&AllowlistConfig {
+            dashboard: vec!["10.0.0.0/8".to_string()],
+            ..AllowlistConfig::default()
+        })
+        .unwrap();
+        assert!(fallback.allows(RouteGroup::AdminApi, ip("10.2.0.1")));
+        assert!(!fallback.allows(RouteGroup::AdminApi, ip("203.0.113.9")));
+
+        assert!(IpAllowlist::new(&AllowlistConfig {
+            dashboard: vec!["10.0.0.0/33".to_string()],
+            ..AllowlistConfig::default()
+        })
+        .is_err());
+    }
+}
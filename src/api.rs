@@ -1,20 +1,37 @@
 use super::{
-    creds::Login,
-    data::{read_from_json, write_to_json, AppData, AppState, JsonData, JsonState},
+    allowlist::ClientIp,
+    archive::{compact, parse_date, read_range},
+    audit::AuditLog,
+    config::Config,
+    creds::{Auth, Login, Role, MIN_PASSWORD_LENGTH},
+    csrf::session_token,
+    data::{write_to_json, AppData, AppState, JsonData},
+    export::{ndjson_chunks, Format},
+    hashing::hash,
+    ledger::{apply_corrections, Ledger},
+    mail::Mailer,
+    oidc::{random_token, Oidc, PENDING_SECONDS},
+    resets::PasswordResets,
+    sessions::SessionStore,
+    throttle::{LoginThrottle, Subject},
+    tokens::{ApiKey, ApiKeys, Scope},
+    users::respond,
 };
-use bcrypt::{hash, DEFAULT_COST};
-
 use chrono::{prelude::*, Duration};
+use futures::stream;
 use ntex::{
-    http::header::HeaderValue,
+    http::header,
+    util::Bytes,
     web::{
         get, post,
-        types::{Json, State},
+        types::{Json, Query, State},
         Error as WebError, HttpRequest, HttpResponse,
     },
 };
 use ntex_session::Session;
 use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::borrow::Cow;
 use std::path::Path;
 use std::sync::Arc;
 use tokio::sync::Mutex;
@@ -103,6 +120,340 @@ struct LoginPost {
     password: String,
 }
 
+/*
+ * struct SecondFactorPost {
+ *   code: String,
+ * }
+ *
+ * The JSON request data for the second login step, a TOTP code or a recovery code.
+ */
+
+#[derive(Deserialize)]
+struct SecondFactorPost {
+    code: String,
+}
+
+/*
+ * const PENDING_LOGIN_SECONDS: i64
+ *
+ * How long after the password step the second factor can still be entered.
+ */
+
+const PENDING_LOGIN_SECONDS: i64 = 5 * 60;
+
+/*
+ * const PENDING_LOGIN_ATTEMPTS: i64
+ *
+ * How many wrong codes a pending login gets before the password has to be entered again.
+ */
+
+const PENDING_LOGIN_ATTEMPTS: i64 = 5;
+
+/*
+ * struct OidcCallback {
+ *   code: Option<String>,
+ *   state: Option<String>,
+ *   error: Option<String>,
+ * }
+ *
+ * Query the identity provider redirects back to /login/oidc/callback with,
+ * either a code and the state we sent or an error.
+ */
+
+#[derive(Deserialize)]
+struct OidcCallback {
+    code: Option<String>,
+    state: Option<String>,
+    error: Option<String>,
+}
+
+/*
+ * struct DataQuery {
+ *   from: Option<String>,
+ *   to: Option<String>,
+ *   format: Option<String>,
+ * }
+ *
+ * Optional query for /api/get_data.
+ * from and to are an inclusive date range formatted as YYYY-MM-DD,
+ * format overrides the Accept header with json, csv, ndjson or msgpack.
+ */
+
+#[derive(Deserialize)]
+struct DataQuery {
+    from: Option<String>,
+    to: Option<String>,
+    format: Option<String>,
+}
+
+/*
+ * struct TokenPost {
+ *   api_key: String,
+ * }
+ *
+ * The JSON request data for exchanging an API key for a JWT.
+ */
+
+#[derive(Deserialize)]
+struct TokenPost {
+    api_key: String,
+}
+
+/*
+ * struct TokenResponse {
+ *   access_token: String,
+ *   token_type: String,
+ *   expires_in: i64,
+ * }
+ *
+ * A JWT from /api/token, shaped like an OAuth 2.0 token response so HTTP client libraries understand it.
+ */
+
+#[derive(Serialize)]
+struct TokenResponse {
+    access_token: String,
+    token_type: String,
+    expires_in: i64,
+}
+
+/*
+ * struct CsrfResponse {
+ *   token: String,
+ * }
+ *
+ * Response for /api/csrf.
+ */
+
+#[derive(Serialize)]
+struct CsrfResponse {
+    token: String,
+}
+
+/*
+ * struct ForgotPost {
+ *   email: String,
+ * }
+ *
+ * The JSON request data for asking for a password reset link.
+ */
+
+#[derive(Deserialize)]
+struct ForgotPost {
+    email: String,
+}
+
+/*
+ * struct ResetPost {
+ *   token: String,
+ *   new_password: String,
+ * }
+ *
+ * The JSON request data for choosing a new password with the token from a reset link.
+ */
+
+#[derive(Deserialize)]
+struct ResetPost {
+    token: String,
+    new_password: String,
+}
+
+/*
+ * pub enum Access<T = Login> {
+ *   Granted(T),
+ *   Denied(HttpResponse),
+ * }
+ *
+ * Outcome of authorize() and authorize_bearer(), either the logged in account (or API key)
+ * or the response to send back instead.
+ */
+
+pub enum Access<T = Login> {
+    Granted(T),
+    Denied(HttpResponse),
+}
+
+/*
+ * fn end_login(session: &Session) {}
+ *
+ * Forgets who is logged in on this session, and the CSRF token that went with it.
+ * The rest of the session is left alone, it also holds the scan cooldown from can_user_enter().
+ */
+
+fn end_login(session: &Session) {
+    for key in [
+        "hash",
+        "token",
+        "user",
+        "created",
+        "seen",
+        "pending_user",
+        "pending_token",
+        "pending_at",
+        "pending_attempts",
+        "oidc_state",
+        "oidc_nonce",
+        "oidc_verifier",
+        "oidc_at",
+        "csrf",
+    ] {
+        session.remove(key);
+    }
+}
+
+/*
+ * pub fn client_ip(req: &HttpRequest) -> String {}
+ *
+ * The address the request came from, looking through trusted proxies (see allowlist.rs),
+ * or as far as the connection tells when that couldn't be worked out.
+ */
+
+pub fn client_ip(req: &HttpRequest) -> String {
+    if let Some(ClientIp(ip)) = req.extensions().get::<ClientIp>() {
+        return ip.to_string();
+    }
+    req.peer_addr()
+        .map(|address| address.ip().to_string())
+        .unwrap_or_default()
+}
+
+/*
+ * fn too_many_attempts(seconds: i64) -> HttpResponse {}
+ *
+ * The 429 sent while an IP or username is locked out of logging in.
+ */
+
+fn too_many_attempts(seconds: i64) -> HttpResponse {
+    HttpResponse::TooManyRequests()
+        .header(header::RETRY_AFTER, seconds.to_string())
+        .content_type("application/json")
+        .json(&Response {
+            title: "Too Many Requests".to_string(),
+            message: format!("Too many failed logins, try again in {} seconds.", seconds),
+        })
+}
+
+/*
+ * fn start_login(session: &Session, auth: &Auth, login: &Login) -> Result<(), WebError> {}
+ *
+ * Marks the session as logged in to `login`, once every login step has passed.
+ * The session gets a new cookie, so one planted before logging in is worth nothing afterwards.
+ */
+
+fn start_login(session: &Session, auth: &Auth, login: &Login) -> Result<(), WebError> {
+    let now = Utc::now().timestamp();
+    end_login(session);
+    session.renew();
+    session.set("token", auth.token(login))?;
+    session.set("user", login.username.clone())?;
+    session.set("created", now)?;
+    session.set("seen", now)?;
+    Ok(())
+}
+
+/*
+ * pub fn authorize(session: &Session, auth: &Auth, role: Role) -> Result<Access, WebError> {}
+ *
+ * Checks that the session carries a user and a token that verifies against the stored account,
+ * that it hasn't expired, and that the account's role allows `role`.
+ * A session without any credentials is never let through.
+ * The last activity time is refreshed at most once a minute so not every response rewrites the cookie.
+ */
+
+pub fn authorize(session: &Session, auth: &Auth, role: Role) -> Result<Access, WebError> {
+    let unauthorized = || {
+        Access::Denied(
+            HttpResponse::Unauthorized()
+                .content_type("application/json")
+                .json(&Response {
+                    title: "Unauthorized".to_string(),
+                    message: "You need to be logged in to do this.".to_string(),
+                }),
+        )
+    };
+
+    let (Some(token), Some(user)) = (
+        session.get::<String>("token")?,
+        session.get::<String>("user")?,
+    ) else {
+        return Ok(unauthorized());
+    };
+
+    let Some(login) = auth.find(&user) else {
+        return Ok(unauthorized());
+    };
+    if !auth.verify_token(&login, &token) {
+        return Ok(unauthorized());
+    }
+
+    let now = Utc::now().timestamp();
+    let created = session.get::<i64>("created")?.unwrap_or_default();
+    let seen = session.get::<i64>("seen")?.unwrap_or_default();
+    if auth.is_expired(created, seen, now) {
+        end_login(session);
+        return Ok(Access::Denied(
+            HttpResponse::Unauthorized()
+                .content_type("application/json")
+                .json(&Response {
+                    title: "Unauthorized".to_string(),
+                    message: "Your session has expired, log in again.".to_string(),
+                }),
+        ));
+    }
+    if now - seen >= 60 {
+        session.set("seen", now)?;
+    }
+
+    if !login.role.allows(role) {
+        return Ok(Access::Denied(
+            HttpResponse::Forbidden()
+                .content_type("application/json")
+                .json(&Response {
+                    title: "Forbidden".to_string(),
+                    message: "Your account isn't allowed to do this.".to_string(),
+                }),
+        ));
+    }
+
+    Ok(Access::Granted(login))
+}
+
+/*
+ * pub fn authorize_bearer(req: &HttpRequest, api_keys: &ApiKeys, scope: Scope) -> Option<Access<ApiKey>> {}
+ *
+ * Checks the JWT in an `Authorization: Bearer` header against `scope`, see tokens.rs.
+ * None when the request doesn't carry one, so the endpoint can fall back to the session.
+ */
+
+pub fn authorize_bearer(
+    req: &HttpRequest,
+    api_keys: &ApiKeys,
+    scope: Scope,
+) -> Option<Access<ApiKey>> {
+    let token = req
+        .headers()
+        .get(header::AUTHORIZATION)?
+        .to_str()
+        .ok()?
+        .strip_prefix("Bearer ")?
+        .trim();
+
+    Some(
+        match api_keys.verify(token, scope, Utc::now().timestamp()) {
+            Some(api_key) => Access::Granted(api_key),
+            None => Access::Denied(
+                HttpResponse::Unauthorized()
+                    .header(header::WWW_AUTHENTICATE, "Bearer error=\"invalid_token\"")
+                    .content_type("application/json")
+                    .json(&Response {
+                        title: "Unauthorized".to_string(),
+                        message: "The token is invalid, expired or not allowed to do this."
+                            .to_string(),
+                    }),
+            ),
+        },
+    )
+}
+
 /*
  * async fn can_user_enter(session: ntex_session::Session) -> Result<bool, WebError> {}
  *
@@ -138,18 +489,14 @@ async fn can_user_enter(session: ntex_session::Session) -> Result<bool, WebError
 pub async fn main_endpoint(
     session: Session,
     data: State<Arc<Mutex<AppData>>>,
-    req: HttpRequest,
+    ledger: State<Arc<Mutex<Ledger>>>,
+    config: State<Arc<Config>>,
 ) -> Result<HttpResponse, WebError> {
-    if req.headers().get("Request-Source").is_none()
-        && req.headers().get("Request-Source") != Some(&HeaderValue::from_static("qrcode-analytic"))
-    {
-        return Ok(HttpResponse::Forbidden().finish());
-    }
-
     let current_date = Local::now().date_naive().to_string();
     let current_time = Local::now().time().format("%H:%M:%S").to_string();
     let current_dotw = Local::now().weekday().to_string();
-    let data = &mut data.lock().await.state;
+    let mut app_data = data.lock().await;
+    let data = &mut app_data.state;
     let current_data = data.last().expect("Can't get latest entry");
 
     let blocked = BlockedResponse {
@@ -192,104 +539,204 @@ pub async fn main_endpoint(
         time: current_time.clone(),
     };
 
-    if new_data.date == current_data.date {
+    let new_day = new_data.date != current_data.date;
+
+    if new_day {
+        data.push(new_data);
+    } else {
         let last_app_state = data.last_mut().expect("Can't get latest entry");
         last_app_state.time.clone_from(&new_data.time);
         last_app_state.last_time = new_data.last_time;
         last_app_state.counter = new_data.counter;
         last_app_state.count_since_yesterday = count_since_yesterday;
-    } else {
-        data.push(new_data);
     }
 
-    let json_data = JsonData { state: Vec::new() };
-    let mut json_state = json_data.state;
+    app_data.touch();
 
-    for entry in data {
-        json_state.push(JsonState {
-            date: entry.date.clone(),
-            last_count: entry.counter,
-            count_since_yesterday: entry.count_since_yesterday,
-            dotw: entry.dotw.clone(),
-            last_time: entry.time.clone(),
-        })
-    }
+    let path = Path::new("./state");
 
-    let json_data = JsonData { state: json_state };
+    if new_day {
+        compact(path, &mut app_data, config.archive_after_days)?;
+    }
 
-    let path = Path::new("./state");
+    let json_data = JsonData::from(&*app_data);
 
     write_to_json(path, json_data).await?;
+    ledger
+        .lock()
+        .await
+        .record_scan(path, Local::now().to_rfc3339())?;
     Ok(HttpResponse::Ok().json(&success))
 }
 
+/*
+ * fn etag_matches(if_none_match: &str, etag: &str) -> bool {}
+ *
+ * Checks an If-None-Match header against our current ETag,
+ * handles lists of tags, weak tags and the `*` wildcard.
+ */
+
+fn etag_matches(if_none_match: &str, etag: &str) -> bool {
+    if_none_match.split(',').any(|tag| {
+        let tag = tag.trim();
+        tag == "*" || tag.trim_start_matches("W/") == etag
+    })
+}
+
+/*
+ * fn not_modified_since(if_modified_since: &str, last_modified: DateTime<Utc>) -> bool {}
+ *
+ * Checks an If-Modified-Since header against the time of the last change,
+ * HTTP dates only have second precision so we compare on that.
+ */
+
+fn not_modified_since(if_modified_since: &str, last_modified: DateTime<Utc>) -> bool {
+    match DateTime::parse_from_rfc2822(if_modified_since) {
+        Ok(since) => last_modified.timestamp() <= since.timestamp(),
+        Err(_) => false,
+    }
+}
+
 /*
  * https://url.tld/api/get_data
  *
  * Returns state info for dashboard.
  * Checks if authenticated and such.
+ *
+ * Served straight from the in-memory state, with an ETag and Last-Modified
+ * so polling dashboards get a 304 Not Modified when nothing has changed.
+ * Answers in JSON, CSV, NDJSON or MessagePack depending on `?format=` or the Accept header,
+ * NDJSON is streamed a chunk of days at a time.
+ * Corrections from the ledger are applied on top of the stored counts.
+ * Anything but JSON counts as an export and is written to the audit log.
+ * Scripts can use a JWT from /api/token with the data:read scope instead of a session.
  */
 
 #[get("/api/get_data")]
 async fn get_state(
     req: HttpRequest,
     session: ntex_session::Session,
+    auth: State<Arc<Auth>>,
+    api_keys: State<Arc<ApiKeys>>,
+    audit: State<Arc<AuditLog>>,
+    data: State<Arc<Mutex<AppData>>>,
+    ledger: State<Arc<Mutex<Ledger>>>,
+    query: Query<DataQuery>,
 ) -> Result<HttpResponse, WebError> {
-    if req.headers().get("Request-Source").is_none()
-        && req.headers().get("Request-Source") != Some(&HeaderValue::from_static("qrcode-analytic"))
-    {
-        return Ok(HttpResponse::Forbidden().finish());
-    }
+    let user = match authorize_bearer(&req, &api_keys, Scope::DataRead) {
+        Some(Access::Granted(api_key)) => format!("apikey:{}", api_key.id),
+        Some(Access::Denied(response)) => return Ok(response),
+        None => match authorize(&session, &auth, Role::Viewer)? {
+            Access::Granted(login) => login.username,
+            Access::Denied(response) => return Ok(response),
+        },
+    };
 
-    let credentials = Login::get();
-    if let Some(hash) = session.get::<String>("hash")? {
-        if let Some(user) = session.get::<String>("user")? {
-            if !credentials.verify(hash, user) {
-                return Ok(HttpResponse::Unauthorized()
+    let format = match &query.format {
+        Some(format) => match Format::from_query(format) {
+            Some(format) => format,
+            None => {
+                return Ok(HttpResponse::BadRequest()
                     .content_type("application/json")
                     .json(&Response {
-                        title: "Unauthorized".to_string(),
-                        message: "You're not allowed to retrieve this data.".to_string(),
+                        title: "Bad Request".to_string(),
+                        message: "Format has to be json, csv, ndjson or msgpack.".to_string(),
                     }));
             }
-        }
+        },
+        None => match req.headers().get(header::ACCEPT) {
+            Some(accept) => match Format::from_accept(accept.to_str().unwrap_or_default()) {
+                Some(format) => format,
+                None => {
+                    return Ok(HttpResponse::NotAcceptable()
+                        .content_type("application/json")
+                        .json(&Response {
+                            title: "Not Acceptable".to_string(),
+                            message: "Available formats are application/json, text/csv, application/x-ndjson and application/msgpack.".to_string(),
+                        }));
+                }
+            },
+            None => Format::Json,
+        },
+    };
+
+    let from = query.from.as_deref().map(parse_date);
+    let to = query.to.as_deref().map(parse_date);
+    if matches!(from, Some(None)) || matches!(to, Some(None)) {
+        return Ok(HttpResponse::BadRequest()
+            .content_type("application/json")
+            .json(&Response {
+                title: "Bad Request".to_string(),
+                message: "Dates have to be formatted as YYYY-MM-DD.".to_string(),
+            }));
     }
+    let (from, to) = (from.flatten(), to.flatten());
 
-    let current_dir = std::env::current_dir()?;
-    let state_path = current_dir.join("state");
+    let app_data = data.lock().await;
+    let etag = app_data.etag(format.name());
+    let last_modified = app_data
+        .last_modified
+        .format("%a, %d %b %Y %H:%M:%S GMT")
+        .to_string();
 
-    let last_data = if read_from_json(&state_path).await.is_ok() {
-        read_from_json(&state_path).await?.state
-    } else {
-        JsonData::default().state
+    let not_modified = match req.headers().get(header::IF_NONE_MATCH) {
+        Some(if_none_match) => etag_matches(if_none_match.to_str().unwrap_or_default(), &etag),
+        None => match req.headers().get(header::IF_MODIFIED_SINCE) {
+            Some(since) => {
+                not_modified_since(since.to_str().unwrap_or_default(), app_data.last_modified)
+            }
+            None => false,
+        },
     };
 
-    let app_data = AppData { state: Vec::new() };
-    let mut app_state = app_data.state;
-
-    for entry in last_data {
-        app_state.push(AppState {
-            date: entry.date.clone(),
-            last_date: entry.date,
-            counter: entry.last_count,
-            count_since_yesterday: entry.count_since_yesterday,
-            dotw: entry.dotw,
-            time: entry.last_time.clone(),
-            last_time: entry.last_time,
-        })
+    if not_modified {
+        return Ok(HttpResponse::NotModified()
+            .header(header::ETAG, etag)
+            .header(header::LAST_MODIFIED, last_modified)
+            .header(header::VARY, "Accept")
+            .finish());
     }
 
-    let new_data = AppData { state: app_state };
+    if !matches!(format, Format::Json) {
+        audit.record(
+            &user,
+            &client_ip(&req),
+            "export",
+            json!({ "format": format.name(), "from": query.from, "to": query.to }),
+        )?;
+    }
 
-    Ok(HttpResponse::Ok()
-        .content_type("application/json")
-        .json(&new_data))
+    let ledger = ledger.lock().await;
+    let corrections = &ledger.corrections;
+    let rows = if from.is_none() && to.is_none() && corrections.is_empty() {
+        Cow::Borrowed(app_data.state.as_slice())
+    } else {
+        let mut rows = read_range(Path::new("./state"), &app_data.state, from, to)?;
+        apply_corrections(&mut rows, corrections);
+        Cow::Owned(rows)
+    };
+
+    let mut response = HttpResponse::Ok();
+    response
+        .content_type(format.content_type())
+        .header(header::ETAG, etag)
+        .header(header::LAST_MODIFIED, last_modified)
+        .header(header::CACHE_CONTROL, "private, no-cache")
+        .header(header::VARY, "Accept");
+
+    if format == Format::Ndjson {
+        let chunks = ndjson_chunks(rows.into_owned()).map(|chunk| chunk.map(Bytes::from));
+        return Ok(response.streaming(stream::iter(chunks)));
+    }
+    Ok(response.body(format.render(&rows)?))
 }
 
 /*
  * https://url.tld/login - POST
  * The function that parses and verifies the data send when pressing submit on the login form.
  * Uses cookies and doesn't need to be used often since it saves your login and autoredirects you.
+ * Accounts with two-factor authentication get a 202 instead, and finish through /login/totp.
+ * Failed attempts are throttled per IP and per username, see throttle.rs.
  */
 
 #[post("/login")]
@@ -297,26 +744,60 @@ pub async fn authenticate(
     req: HttpRequest,
     json: Json<LoginPost>,
     session: ntex_session::Session,
+    auth: State<Arc<Auth>>,
+    audit: State<Arc<AuditLog>>,
+    throttle: State<Arc<LoginThrottle>>,
 ) -> Result<HttpResponse, WebError> {
-    if req.headers().get("Request-Source").is_none()
-        && req.headers().get("Request-Source") != Some(&HeaderValue::from_static("qrcode-analytic"))
-    {
-        return Ok(HttpResponse::Forbidden().finish());
+    let ip = client_ip(&req);
+    let subjects = [Subject::Ip(&ip), Subject::User(&json.username)];
+    let now = Utc::now().timestamp();
+    if let Some(seconds) = throttle.locked(&subjects, now) {
+        return Ok(too_many_attempts(seconds));
     }
 
-    let credentials = Login::get();
-    let hashed = hash(&json.password, DEFAULT_COST).expect("Can't hash password");
-    if !credentials.verify(hashed.clone(), json.username.clone()) {
+    let Some(login) = auth.authenticate(&json.username, &json.password) else {
+        audit.record(
+            &json.username,
+            &ip,
+            "login_failed",
+            json!({ "reason": "password" }),
+        )?;
+        if let Some(seconds) = throttle.failed(&subjects, now) {
+            return Ok(too_many_attempts(seconds));
+        }
         return Ok(HttpResponse::Unauthorized()
             .content_type("application/json")
             .json(&Response {
                 title: "Unauthorized".to_string(),
                 message: "Invalid username or password.".to_string(),
             }));
+    };
+
+    if login.requires_second_factor() {
+        end_login(&session);
+        session.set("pending_user", login.username.clone())?;
+        session.set("pending_token", auth.token(&login))?;
+        session.set("pending_at", Utc::now().timestamp())?;
+        session.set("pending_attempts", 0)?;
+
+        return Ok(HttpResponse::Accepted()
+            .content_type("application/json")
+            .json(&Response {
+                title: "Two-factor code needed".to_string(),
+                message:
+                    "Enter the code from your authenticator app, or one of your recovery codes."
+                        .to_string(),
+            }));
     }
 
-    session.set("hash", hashed.clone())?;
-    session.set("user", json.username.clone())?;
+    throttle.succeeded(Subject::User(&login.username));
+    start_login(&session, &auth, &login)?;
+    audit.record(
+        &login.username,
+        &ip,
+        "login",
+        json!({ "method": "password", "second_factor": false }),
+    )?;
 
     Ok(HttpResponse::Ok()
         .content_type("application/json")
@@ -328,39 +809,542 @@ pub async fn authenticate(
 }
 
 /*
- * https://url.tld/api/can_i_login
+ * https://url.tld/login/totp - POST
  *
- * Helping function that the login page requests to for auto login.
+ * Second login step for accounts with two-factor authentication,
+ * takes a TOTP code or a recovery code after /login accepted the password.
+ * Wrong codes count towards the same throttling as wrong passwords.
  */
 
-#[get("/api/can_i_login")]
-pub async fn can_login(
+#[post("/login/totp")]
+pub async fn verify_second_factor(
     req: HttpRequest,
+    json: Json<SecondFactorPost>,
     session: ntex_session::Session,
+    auth: State<Arc<Auth>>,
+    audit: State<Arc<AuditLog>>,
+    throttle: State<Arc<LoginThrottle>>,
 ) -> Result<HttpResponse, WebError> {
-    if req.headers().get("Request-Source").is_none()
-        && req.headers().get("Request-Source") != Some(&HeaderValue::from_static("qrcode-analytic"))
-    {
-        return Ok(HttpResponse::Forbidden().finish());
+    let unauthorized = |message: &str| {
+        HttpResponse::Unauthorized()
+            .content_type("application/json")
+            .json(&Response {
+                title: "Unauthorized".to_string(),
+                message: message.to_string(),
+            })
+    };
+
+    let (Some(user), Some(token), Some(pending_at)) = (
+        session.get::<String>("pending_user")?,
+        session.get::<String>("pending_token")?,
+        session.get::<i64>("pending_at")?,
+    ) else {
+        return Ok(unauthorized("Log in with your password first."));
+    };
+    let attempts = session.get::<i64>("pending_attempts")?.unwrap_or_default();
+
+    let ip = client_ip(&req);
+    let subjects = [Subject::Ip(&ip), Subject::User(&user)];
+    let now = Utc::now().timestamp();
+    if let Some(seconds) = throttle.locked(&subjects, now) {
+        return Ok(too_many_attempts(seconds));
     }
+    let login = auth.find(&user);
+    let valid = matches!(&login, Some(login) if auth.verify_token(login, &token));
+    if !valid || now - pending_at > PENDING_LOGIN_SECONDS || attempts >= PENDING_LOGIN_ATTEMPTS {
+        end_login(&session);
+        return Ok(unauthorized(
+            "Your login has expired, log in with your password again.",
+        ));
+    }
+
+    let accepted = auth.update(|accounts| {
+        let Some(login) = accounts
+            .users
+            .iter_mut()
+            .find(|login| login.username == user)
+        else {
+            return Err(None);
+        };
+        let Some(second_factor) = login.second_factor.as_mut() else {
+            return Err(None);
+        };
 
-    let credentials = Login::get();
-    if let Some(hash) = session.get::<String>("hash")? {
-        if let Some(user) = session.get::<String>("user")? {
-            if credentials.verify(hash, user) {
-                return Ok(HttpResponse::Ok().content_type("application/json").json(
-                    &RoutingResponse {
-                        title: "You're already logged in!".to_string(),
-                        message: "Redirecting to dashboard...".to_string(),
-                        route: "/dashboard".to_string(),
-                    },
-                ));
+        let totp_accepted = second_factor.verify(&user, &json.code, now as u64);
+        if !totp_accepted && !second_factor.use_recovery_code(&json.code) {
+            return Err(Some(()));
+        }
+        Ok((login.clone(), totp_accepted))
+    })?;
+    let (login, totp_accepted) = match accepted {
+        Ok(accepted) => accepted,
+        Err(None) => {
+            end_login(&session);
+            return Ok(unauthorized(
+                "Your login has expired, log in with your password again.",
+            ));
+        }
+        Err(Some(())) => {
+            audit.record(
+                &user,
+                &ip,
+                "login_failed",
+                json!({ "reason": "second_factor" }),
+            )?;
+            session.set("pending_attempts", attempts + 1)?;
+            if let Some(seconds) = throttle.failed(&subjects, now) {
+                return Ok(too_many_attempts(seconds));
             }
+            return Ok(unauthorized("That code isn't valid."));
         }
+    };
+    throttle.succeeded(Subject::User(&login.username));
+    start_login(&session, &auth, &login)?;
+    audit.record(
+        &login.username,
+        &ip,
+        "login",
+        json!({
+            "method": "password",
+            "second_factor": true,
+            "recovery_code": !totp_accepted,
+        }),
+    )?;
+
+    Ok(HttpResponse::Ok()
+        .content_type("application/json")
+        .json(&RoutingResponse {
+            title: "Logged in!".to_string(),
+            message: "Redirecting to dashboard...".to_string(),
+            route: "/dashboard".to_string(),
+        }))
+}
+
+/*
+ * https://url.tld/login/forgot - POST
+ *
+ * Mails a single-use reset link to the account with the given address.
+ * The answer is the same whether or not there is such an account, and the mail is sent
+ * in the background so the response time doesn't tell either.
+ * Requests are limited per IP and per address, see resets.rs.
+ */
+
+#[post("/login/forgot")]
+pub async fn forgot_password(
+    req: HttpRequest,
+    json: Json<ForgotPost>,
+    auth: State<Arc<Auth>>,
+    audit: State<Arc<AuditLog>>,
+    mailer: State<Arc<Mailer>>,
+    resets: State<Arc<PasswordResets>>,
+) -> Result<HttpResponse, WebError> {
+    if !mailer.enabled() {
+        return Ok(respond(
+            HttpResponse::NotFound(),
+            "Not Found",
+            "Password resets by email aren't set up, ask an admin to reset your password.",
+        ));
+    }
+
+    let ip = client_ip(&req);
+    let now = Utc::now().timestamp();
+    let email = json.email.trim().to_string();
+    if !resets.allow(&ip, &email, now) {
+        return Ok(respond(
+            HttpResponse::TooManyRequests(),
+            "Too Many Requests",
+            "Too many reset requests, try again in an hour.",
+        ));
+    }
+
+    let sent = respond(
+        HttpResponse::Accepted(),
+        "Check your inbox",
+        "If an account uses that address, a reset link is on its way.",
+    );
+
+    let Some(login) = auth
+        .accounts()
+        .find_by_email(&email)
+        .filter(|login| !login.external)
+        .cloned()
+    else {
+        audit.record(
+            "",
+            &ip,
+            "password_reset_requested",
+            json!({ "email": email, "account": false }),
+        )?;
+        return Ok(sent);
+    };
+
+    let token = resets.issue(&login.username, now, mailer.reset_token_minutes())?;
+    audit.record(
+        &login.username,
+        &ip,
+        "password_reset_requested",
+        json!({ "email": email, "account": true }),
+    )?;
+
+    let mailer = Arc::clone(&mailer);
+    ntex::rt::spawn(async move {
+        match mailer.send_reset(&email, &login.username, &token).await {
+            Ok(()) => tracing::info!("Sent a password reset link to {}.", login.username),
+            Err(error) => tracing::error!(
+                "Failed to send a password reset link to {}: {}",
+                login.username,
+                error
+            ),
+        }
+    });
+
+    Ok(sent)
+}
+
+/*
+ * https://url.tld/login/reset - POST
+ *
+ * Sets a new password with the token from a reset link, which can't be used again afterwards.
+ * Every session of the account is logged out, a second factor is still asked for on the next login.
+ */
+
+#[post("/login/reset")]
+pub async fn reset_password(
+    req: HttpRequest,
+    json: Json<ResetPost>,
+    auth: State<Arc<Auth>>,
+    audit: State<Arc<AuditLog>>,
+    throttle: State<Arc<LoginThrottle>>,
+    resets: State<Arc<PasswordResets>>,
+    sessions: State<Arc<SessionStore>>,
+) -> Result<HttpResponse, WebError> {
+    if json.new_password.chars().count() < MIN_PASSWORD_LENGTH {
+        return Ok(respond(
+            HttpResponse::BadRequest(),
+            "Bad Request",
+            &format!(
+                "Passwords have to be at least {} characters.",
+                MIN_PASSWORD_LENGTH
+            ),
+        ));
     }
 
-    session.set("hash", "".to_string())?;
-    session.set("user", "".to_string())?;
+    let invalid = || {
+        respond(
+            HttpResponse::BadRequest(),
+            "Bad Request",
+            "The reset link is invalid, expired or already used, ask for a new one.",
+        )
+    };
+
+    let Some(username) = resets.redeem(&json.token, Utc::now().timestamp())? else {
+        return Ok(invalid());
+    };
+
+    let password_hash = hash(&json.new_password);
+    let reset = auth.update(|accounts| {
+        let Some(account) = accounts
+            .users
+            .iter_mut()
+            .find(|account| account.username == username && !account.external)
+        else {
+            return Err(());
+        };
+        account.password_hash = password_hash;
+        Ok(())
+    })?;
+    if reset.is_err() {
+        return Ok(invalid());
+    }
+    sessions.remove_user(&username);
+    throttle.succeeded(Subject::User(&username));
+
+    audit.record(
+        &username,
+        &client_ip(&req),
+        "password_reset",
+        json!({ "method": "email" }),
+    )?;
+    tracing::info!("{} reset their password through a mailed link.", username);
+
+    Ok(respond(
+        HttpResponse::Ok(),
+        "Password changed",
+        "Your password has been reset, log in with the new one.",
+    ))
+}
+
+/*
+ * https://url.tld/api/token - POST
+ *
+ * Exchanges an API key for a short-lived JWT to send as `Authorization: Bearer <token>`.
+ * Meant for scripts, so it's exempt from the CSRF check, see csrf.rs.
+ * Wrong keys count towards the login throttling of the IP.
+ */
+
+#[post("/api/token")]
+pub async fn issue_token(
+    req: HttpRequest,
+    json: Json<TokenPost>,
+    api_keys: State<Arc<ApiKeys>>,
+    audit: State<Arc<AuditLog>>,
+    throttle: State<Arc<LoginThrottle>>,
+) -> Result<HttpResponse, WebError> {
+    let ip = client_ip(&req);
+    let subjects = [Subject::Ip(&ip)];
+    let now = Utc::now().timestamp();
+    if let Some(seconds) = throttle.locked(&subjects, now) {
+        return Ok(too_many_attempts(seconds));
+    }
+
+    let Some((access_token, expires_in)) = api_keys.exchange(&json.api_key, now)? else {
+        audit.record("", &ip, "login_failed", json!({ "method": "api_key" }))?;
+        if let Some(seconds) = throttle.failed(&subjects, now) {
+            return Ok(too_many_attempts(seconds));
+        }
+        return Ok(respond(
+            HttpResponse::Unauthorized(),
+            "Unauthorized",
+            "The API key is invalid, expired or revoked.",
+        ));
+    };
+
+    Ok(HttpResponse::Ok()
+        .content_type("application/json")
+        .header(header::CACHE_CONTROL, "no-store")
+        .json(&TokenResponse {
+            access_token,
+            token_type: "Bearer".to_string(),
+            expires_in,
+        }))
+}
+
+/*
+ * https://url.tld/login/oidc
+ *
+ * Starts single sign-on, sending the browser to the identity provider.
+ * The state, nonce and PKCE verifier wait in the session for the callback.
+ * This is a plain browser navigation, GETs aren't CSRF checked.
+ */
+
+#[get("/login/oidc")]
+pub async fn oidc_login(
+    session: ntex_session::Session,
+    oidc: State<Arc<Oidc>>,
+) -> Result<HttpResponse, WebError> {
+    if !oidc.enabled() {
+        return Ok(HttpResponse::NotFound()
+            .content_type("application/json")
+            .json(&Response {
+                title: "Not Found".to_string(),
+                message: "Single sign-on isn't set up.".to_string(),
+            }));
+    }
+
+    let (state, nonce, verifier) = (random_token(), random_token(), random_token());
+    let url = match oidc.authorization_url(&state, &nonce, &verifier).await {
+        Ok(url) => url,
+        Err(error) => {
+            tracing::error!("Couldn't reach the identity provider: {}", error);
+            return Ok(HttpResponse::BadGateway()
+                .content_type("application/json")
+                .json(&Response {
+                    title: "Bad Gateway".to_string(),
+                    message: "The identity provider can't be reached, try again later.".to_string(),
+                }));
+        }
+    };
+
+    session.set("oidc_state", state)?;
+    session.set("oidc_nonce", nonce)?;
+    session.set("oidc_verifier", verifier)?;
+    session.set("oidc_at", Utc::now().timestamp())?;
+
+    Ok(HttpResponse::Found().header(header::LOCATION, url).finish())
+}
+
+/*
+ * https://url.tld/login/oidc/callback
+ *
+ * Where the identity provider sends the browser back to. Exchanges the code for an ID token,
+ * maps the groups in it to a role and logs the session in to the matching external account.
+ */
+
+#[get("/login/oidc/callback")]
+pub async fn oidc_callback(
+    req: HttpRequest,
+    session: ntex_session::Session,
+    query: Query<OidcCallback>,
+    auth: State<Arc<Auth>>,
+    audit: State<Arc<AuditLog>>,
+    oidc: State<Arc<Oidc>>,
+) -> Result<HttpResponse, WebError> {
+    if !oidc.enabled() {
+        return Ok(HttpResponse::NotFound()
+            .content_type("application/json")
+            .json(&Response {
+                title: "Not Found".to_string(),
+                message: "Single sign-on isn't set up.".to_string(),
+            }));
+    }
+
+    let ip = client_ip(&req);
+    let (Some(expected_state), Some(nonce), Some(verifier), Some(started)) = (
+        session.get::<String>("oidc_state")?,
+        session.get::<String>("oidc_nonce")?,
+        session.get::<String>("oidc_verifier")?,
+        session.get::<i64>("oidc_at")?,
+    ) else {
+        return Ok(respond(
+            HttpResponse::Unauthorized(),
+            "Unauthorized",
+            "Start the login from the login page.",
+        ));
+    };
+    for key in ["oidc_state", "oidc_nonce", "oidc_verifier", "oidc_at"] {
+        session.remove(key);
+    }
+
+    if let Some(error) = &query.error {
+        audit.record(
+            "",
+            &ip,
+            "login_failed",
+            json!({ "method": "oidc", "reason": error }),
+        )?;
+        return Ok(respond(
+            HttpResponse::Unauthorized(),
+            "Unauthorized",
+            "The identity provider didn't let you log in.",
+        ));
+    }
+    let (Some(code), Some(state)) = (&query.code, &query.state) else {
+        return Ok(respond(
+            HttpResponse::BadRequest(),
+            "Bad Request",
+            "The identity provider didn't send a code.",
+        ));
+    };
+    if *state != expected_state || Utc::now().timestamp() - started > PENDING_SECONDS {
+        return Ok(respond(
+            HttpResponse::Unauthorized(),
+            "Unauthorized",
+            "This login has expired, start again from the login page.",
+        ));
+    }
+
+    let identity = match oidc.exchange(code, &verifier, &nonce).await {
+        Ok(identity) => identity,
+        Err(error) => {
+            tracing::warn!("Single sign-on failed: {}", error);
+            audit.record(
+                "",
+                &ip,
+                "login_failed",
+                json!({ "method": "oidc", "reason": error.to_string() }),
+            )?;
+            return Ok(respond(
+                HttpResponse::Unauthorized(),
+                "Unauthorized",
+                "Single sign-on failed, try again.",
+            ));
+        }
+    };
+
+    let Some(role) = oidc.role(&identity.groups) else {
+        audit.record(
+            &identity.username,
+            &ip,
+            "login_failed",
+            json!({ "method": "oidc", "reason": "no mapped group", "groups": identity.groups }),
+        )?;
+        return Ok(respond(
+            HttpResponse::Forbidden(),
+            "Forbidden",
+            "Your account isn't in a group that's allowed to use the dashboard.",
+        ));
+    };
+
+    let Some(login) = auth.external_login(&identity.subject, &identity.username, role)? else {
+        audit.record(
+            &identity.username,
+            &ip,
+            "login_failed",
+            json!({
+                "method": "oidc",
+                "reason": "another account with the same name",
+                "subject": identity.subject,
+            }),
+        )?;
+        return Ok(respond(
+            HttpResponse::Conflict(),
+            "Conflict",
+            "Another account already uses your username, ask an admin to sort it out.",
+        ));
+    };
+
+    start_login(&session, &auth, &login)?;
+    audit.record(
+        &login.username,
+        &ip,
+        "login",
+        json!({ "method": "oidc", "role": login.role, "groups": identity.groups }),
+    )?;
+
+    Ok(HttpResponse::Found()
+        .header(header::LOCATION, "/dashboard")
+        .finish())
+}
+
+/*
+ * https://url.tld/logout - POST
+ *
+ * Logs the session out, the cookie can't be used to get back in afterwards.
+ */
+
+#[post("/logout")]
+pub async fn logout(
+    req: HttpRequest,
+    session: ntex_session::Session,
+    audit: State<Arc<AuditLog>>,
+) -> Result<HttpResponse, WebError> {
+    if let Some(user) = session.get::<String>("user")? {
+        tracing::info!("{} logged out.", user);
+        audit.record(&user, &client_ip(&req), "logout", json!({}))?;
+    }
+    end_login(&session);
+
+    Ok(HttpResponse::Ok()
+        .content_type("application/json")
+        .json(&RoutingResponse {
+            title: "Logged out!".to_string(),
+            message: "Redirecting to login...".to_string(),
+            route: "/login".to_string(),
+        }))
+}
+
+/*
+ * https://url.tld/api/can_i_login
+ *
+ * Helping function that the login page requests to for auto login.
+ */
+
+#[get("/api/can_i_login")]
+pub async fn can_login(
+    session: ntex_session::Session,
+    auth: State<Arc<Auth>>,
+) -> Result<HttpResponse, WebError> {
+    if let Access::Granted(_) = authorize(&session, &auth, Role::Viewer)? {
+        return Ok(HttpResponse::Ok()
+            .content_type("application/json")
+            .json(&RoutingResponse {
+                title: "You're already logged in!".to_string(),
+                message: "Redirecting to dashboard...".to_string(),
+                route: "/dashboard".to_string(),
+            }));
+    }
+
+    end_login(&session);
 
     return Ok(HttpResponse::Unauthorized()
         .content_type("application/json")
@@ -369,3 +1353,19 @@ pub async fn can_login(
             message: "Has your credentials reset?, Resetting your cookies.".to_string(),
         }));
 }
+
+/*
+ * https://url.tld/api/csrf
+ *
+ * Hands out the session's CSRF token, pages send it back in X-CSRF-Token on every POST, PUT and DELETE.
+ */
+
+#[get("/api/csrf")]
+pub async fn csrf_token(session: ntex_session::Session) -> Result<HttpResponse, WebError> {
+    Ok(HttpResponse::Ok()
+        .content_type("application/json")
+        .header(header::CACHE_CONTROL, "no-store")
+        .json(&CsrfResponse {
+            token: session_token(&session)?,
+        }))
+}
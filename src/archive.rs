@@ -0,0 +1,354 @@
+use super::data::{AppData, AppState, JsonData, JsonState};
+use chrono::{prelude::*, Duration};
+use flate2::{read::GzDecoder, write::GzEncoder, Compression};
+use serde_json::{from_reader, to_writer};
+use std::{
+    collections::BTreeMap,
+    fs::{create_dir_all, read_dir, remove_file, rename, File},
+    io::{ErrorKind, Write},
+    path::{Path, PathBuf},
+};
+
+/*
+ * fn archive_dir(path: &Path) -> PathBuf {}
+ *
+ * The directory archived years live in, state/archive/.
+ */
+
+fn archive_dir(path: &Path) -> PathBuf {
+    path.join("archive")
+}
+
+/*
+ * pub fn parse_date(date: &str) -> Option<NaiveDate> {}
+ *
+ * Parses the dates we store, YYYY-MM-DD.
+ */
+
+pub fn parse_date(date: &str) -> Option<NaiveDate> {
+    NaiveDate::parse_from_str(date, "%Y-%m-%d").ok()
+}
+
+/*
+ * pub fn archived_years(path: &Path) -> Result<Vec<i32>, std::io::Error> {}
+ *
+ * Lists every year that has an archive file, oldest first.
+ */
+
+pub fn archived_years(path: &Path) -> Result<Vec<i32>, std::io::Error> {
+    let dir = archive_dir(path);
+    if !dir.is_dir() {
+        return Ok(Vec::new());
+    }
+
+    let mut years = Vec::new();
+    for entry in read_dir(dir)? {
+        let file_name = entry?.file_name();
+        let file_name = file_name.to_string_lossy();
+        if let Some(year) = file_name.strip_suffix(".json.gz") {
+            if let Ok(year) = year.parse::<i32>() {
+                years.push(year);
+            }
+        }
+    }
+    years.sort();
+    Ok(years)
+}
+
+/*
+ * pub fn read_archive(path: &Path, year: i32) -> Result<JsonData, std::io::Error> {}
+ *
+ * Reads and decompresses state/archive/<year>.json.gz,
+ * a missing archive is treated as an empty one.
+ */
+
+pub fn read_archive(path: &Path, year: i32) -> Result<JsonData, std::io::Error> {
+    let file_path = archive_dir(path).join(format!("{}.json.gz", year));
+    if !file_path.is_file() {
+        return Ok(JsonData { state: Vec::new() });
+    }
+    let file = File::open(file_path)?;
+    let json_data: JsonData = from_reader(GzDecoder::new(file))?;
+    Ok(json_data)
+}
+
+/*
+ * pub fn write_archive(path: &Path, year: i32, json_data: &JsonData) -> Result<(), std::io::Error> {}
+ *
+ * Compresses and writes state/archive/<year>.json.gz,
+ * goes through a temporary file so a crash never leaves a half written archive behind.
+ */
+
+pub fn write_archive(path: &Path, year: i32, json_data: &JsonData) -> Result<(), std::io::Error> {
+    let dir = archive_dir(path);
+    create_dir_all(&dir)?;
+
+    let file_path = dir.join(format!("{}.json.gz", year));
+    let tmp_path = dir.join(format!("{}.json.gz.tmp", year));
+
+    let mut encoder = GzEncoder::new(File::create(&tmp_path)?, Compression::best());
+    to_writer(&mut encoder, json_data)?;
+    encoder.finish()?.flush()?;
+
+    rename(tmp_path, file_path)
+}
+
+/*
+ * pub fn remove_archive(path: &Path, year: i32) -> Result<(), std::io::Error> {}
+ *
+ * Deletes the archive of a year, used when nothing in it is left to keep.
+ */
+
+pub fn remove_archive(path: &Path, year: i32) -> Result<(), std::io::Error> {
+    let file_path = archive_dir(path).join(format!("{}.json.gz", year));
+    if file_path.is_file() {
+        remove_file(file_path)?;
+    }
+    Ok(())
+}
+
+/*
+ * pub fn compact(path: &Path, app_data: &mut AppData, archive_after_days: i64) -> Result<usize, std::io::Error> {}
+ *
+ * Moves every day older than `archive_after_days` out of the hot state and into the archive of its year.
+ * The latest entry always stays hot since the counter continues from it.
+ * Returns how many days got archived, the caller is responsible for writing data.json afterwards.
+ */
+
+pub fn compact(
+    path: &Path,
+    app_data: &mut AppData,
+    archive_after_days: i64,
+) -> Result<usize, std::io::Error> {
+    let cutoff = Duration::try_days(archive_after_days)
+        .and_then(|days| Local::now().date_naive().checked_sub_signed(days))
+        .ok_or_else(|| {
+            std::io::Error::new(
+                ErrorKind::InvalidInput,
+                format!("archive_after_days is out of range: {}", archive_after_days),
+            )
+        })?;
+    let keep_from = app_data.state.len().saturating_sub(1);
+
+    let mut by_year: BTreeMap<i32, Vec<JsonState>> = BTreeMap::new();
+    let mut hot = Vec::new();
+
+    for (index, entry) in app_data.state.drain(..).enumerate() {
+        match parse_date(&entry.date) {
+            Some(date) if date < cutoff && index < keep_from => {
+                by_year.entry(date.year()).or_default().push(JsonState {
+                    date: entry.date,
+                    dotw: entry.dotw,
+                    last_count: entry.counter,
+                    count_since_yesterday: entry.count_since_yesterday,
+                    last_time: entry.time,
+                });
+            }
+            _ => hot.push(entry),
+        }
+    }
+    app_data.state = hot;
+
+    let mut archived = 0;
+    for (year, entries) in by_year {
+        let mut json_data = read_archive(path, year)?;
+        archived += entries.len();
+        for entry in entries {
+            json_data
+                .state
+                .retain(|existing| existing.date != entry.date);
+            json_data.state.push(entry);
+        }
+        json_data.state.sort_by(|a, b| a.date.cmp(&b.date));
+        write_archive(path, year, &json_data)?;
+    }
+
+    if archived > 0 {
+        app_data.touch();
+    }
+    Ok(archived)
+}
+
+/*
+ * pub fn read_range(path: &Path, hot: &[AppState], from: Option<NaiveDate>, to: Option<NaiveDate>) -> Result<Vec<AppState>, std::io::Error> {}
+ *
+ * Returns every day between `from` and `to` (both inclusive).
+ * Archives are only opened when the range reaches further back than the hot state does,
+ * which a range with only a `to` always does.
+ */
+
+pub fn read_range(
+    path: &Path,
+    hot: &[AppState],
+    from: Option<NaiveDate>,
+    to: Option<NaiveDate>,
+) -> Result<Vec<AppState>, std::io::Error> {
+    let in_range = |entry: &AppState| match parse_date(&entry.date) {
+        Some(date) => {
+            !matches!(from, Some(from) if date < from) && !matches!(to, Some(to) if date > to)
+        }
+        None => from.is_none() && to.is_none(),
+    };
+
+    let oldest_hot = hot.first().and_then(|entry| parse_date(&entry.date));
+    let needs_archive = match (from, to, oldest_hot) {
+        (None, None, _) => false,
+        (Some(from), _, Some(oldest_hot)) => from < oldest_hot,
+        _ => true,
+    };
+
+    let mut rows = Vec::new();
+
+    if needs_archive {
+        let from_year = from.map(|from| from.year()).unwrap_or(i32::MIN);
+        let to_year = to.map(|to| to.year()).unwrap_or(i32::MAX);
+        for year in archived_years(path)? {
+            if year < from_year || year > to_year {
+                continue;
+            }
+            let archived = AppData::from(read_archive(path, year)?);
+            rows.extend(archived.state.into_iter().filter(|entry| {
+                in_range(entry) && !hot.iter().any(|hot_entry| hot_entry.date == entry.date)
+            }));
+        }
+    }
+
+    rows.extend(hot.iter().filter(|entry| in_range(entry)).cloned());
+    rows.sort_by(|a, b| a.date.cmp(&b.date));
+    Ok(rows)
+}
+
+/*
+ * pub fn read_all(path: &Path, hot: &[AppState]) -> Result<Vec<AppState>, std::io::Error> {}
+ *
+ * Returns the full history, every archived year followed by the hot state.
+ */
+
+pub fn read_all(path: &Path, hot: &[AppState]) -> Result<Vec<AppState>, std::io::Error> {
+    let mut rows = Vec::new();
+
+    for year in archived_years(path)? {
+        let archived = AppData::from(read_archive(path, year)?);
+        rows.extend(
+            archived
+                .state
+                .into_iter()
+                .filter(|entry| !hot.iter().any(|hot_entry| hot_entry.date == entry.date)),
+        );
+    }
+
+    rows.extend(hot.iter().cloned());
+    rows.sort_by(|a, b| a.date.cmp(&b.date));
+    Ok(rows)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn day(date: NaiveDate, counter: i32) -> AppState {
+        AppState {
+            last_date: date.to_string(),
+            date: date.to_string(),
+            dotw: date.weekday().to_string(),
+            counter,
+            count_since_yesterday: 1,
+            time: "12:00:00".to_string(),
+            last_time: "12:00:00".to_string(),
+        }
+    }
+
+    fn state_dir() -> PathBuf {
+        let path = std::env::temp_dir().join(format!(
+            "archive-test-{}-{}",
+            std::process::id(),
+            rand::random::<u32>()
+        ));
+        create_dir_all(&path).unwrap();
+        path
+    }
+
+    fn dates(rows: &[AppState]) -> Vec<String> {
+        rows.iter().map(|entry| entry.date.clone()).collect()
+    }
+
+    #[test]
+    fn compact_moves_old_days_into_yearly_archives() {
+        let path = state_dir();
+        let today = Local::now().date_naive();
+        let old = NaiveDate::from_ymd_opt(2020, 12, 31).unwrap();
+        let older = NaiveDate::from_ymd_opt(2019, 6, 1).unwrap();
+        let mut app_data = AppData::from(JsonData { state: Vec::new() });
+        app_data.state = vec![day(older, 1), day(old, 2), day(today, 3)];
+
+        assert_eq!(compact(&path, &mut app_data, 30).unwrap(), 2);
+        assert_eq!(dates(&app_data.state), vec![today.to_string()]);
+        assert_eq!(app_data.version, 1);
+        assert_eq!(archived_years(&path).unwrap(), vec![2019, 2020]);
+        assert_eq!(read_archive(&path, 2020).unwrap().state[0].last_count, 2);
+
+        assert_eq!(compact(&path, &mut app_data, 30).unwrap(), 0);
+        assert_eq!(
+            dates(&read_all(&path, &app_data.state).unwrap()),
+            vec![older.to_string(), old.to_string(), today.to_string()]
+        );
+
+        std::fs::remove_dir_all(path).unwrap();
+    }
+
+    #[test]
+    fn compact_refuses_out_of_range_days() {
+        let path = state_dir();
+        let mut app_data = AppData::from(JsonData { state: Vec::new() });
+        app_data.state = vec![day(Local::now().date_naive(), 1)];
+
+        for days in [i64::MAX, i64::MIN, 1_000_000_000] {
+            assert!(compact(&path, &mut app_data, days).is_err(), "{days}");
+        }
+        assert_eq!(app_data.state.len(), 1);
+
+        std::fs::remove_dir_all(path).unwrap();
+    }
+
+    #[test]
+    fn compact_keeps_the_latest_day_hot() {
+        let path = state_dir();
+        let old = NaiveDate::from_ymd_opt(2020, 1, 1).unwrap();
+        let mut app_data = AppData::from(JsonData { state: Vec::new() });
+        app_data.state = vec![day(old, 1)];
+
+        assert_eq!(compact(&path, &mut app_data, 30).unwrap(), 0);
+        assert_eq!(dates(&app_data.state), vec![old.to_string()]);
+
+        std::fs::remove_dir_all(path).unwrap();
+    }
+
+    #[test]
+    fn ranges_reach_into_the_archives() {
+        let path = state_dir();
+        let archived = |month| NaiveDate::from_ymd_opt(2020, month, 1).unwrap();
+        let hot_day = NaiveDate::from_ymd_opt(2021, 1, 1).unwrap();
+        let mut app_data = AppData::from(JsonData { state: Vec::new() });
+        app_data.state = vec![day(archived(1), 1), day(archived(2), 2)];
+        let json_data = JsonData::from(&app_data);
+        write_archive(&path, 2020, &json_data).unwrap();
+        let hot = vec![day(hot_day, 3)];
+
+        let to_only = read_range(&path, &hot, None, Some(archived(1))).unwrap();
+        assert_eq!(dates(&to_only), vec![archived(1).to_string()]);
+
+        let from_only = read_range(&path, &hot, Some(archived(2)), None).unwrap();
+        assert_eq!(
+            dates(&from_only),
+            vec![archived(2).to_string(), hot_day.to_string()]
+        );
+
+        let hot_only = read_range(&path, &hot, Some(hot_day), Some(hot_day)).unwrap();
+        assert_eq!(dates(&hot_only), vec![hot_day.to_string()]);
+
+        let unbounded = read_range(&path, &hot, None, None).unwrap();
+        assert_eq!(dates(&unbounded), vec![hot_day.to_string()]);
+
+        std::fs::remove_dir_all(path).unwrap();
+    }
+}
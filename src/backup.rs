@@ -0,0 +1,384 @@
+use super::{config::Config, data::JsonData};
+use chrono::prelude::*;
+use flate2::{read::GzDecoder, write::GzEncoder, Compression};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::{
+    collections::BTreeMap,
+    ffi::OsStr,
+    fs::{copy, create_dir_all, read, read_dir, remove_dir_all, rename, File},
+    io::{Error, ErrorKind, Read, Write},
+    path::{Component, Path, PathBuf},
+};
+use tar::{Archive, Builder, Header};
+
+/*
+ * pub struct Manifest {
+ *   pub created_at: String,
+ *   pub files: Vec<ManifestEntry>,
+ * }
+ *
+ * Written as manifest.json at the root of every backup,
+ * lists every file in the backup together with its size and checksum.
+ */
+
+#[derive(Serialize, Deserialize)]
+pub struct Manifest {
+    pub created_at: String,
+    pub files: Vec<ManifestEntry>,
+}
+
+/*
+ * pub struct ManifestEntry {
+ *   pub path: String,
+ *   pub size: u64,
+ *   pub sha256: String,
+ * }
+ *
+ * A single file in the manifest, path is relative to the state directory.
+ */
+
+#[derive(Serialize, Deserialize)]
+pub struct ManifestEntry {
+    pub path: String,
+    pub size: u64,
+    pub sha256: String,
+}
+
+/*
+ * const KEPT_OUT: [&str; 6]
+ *
+ * What in the state directory never goes into a backup and is never restored from one.
+ * keys/ holds the ledger, token and session keys, anyone holding a backup could forge with them.
+ * admin_login.json holds the password hashes and TOTP secrets, and restoring it would bring back old passwords.
+ * The audit log is append-only, a restore mustn't be a way to replace it.
+ * The rest are live stores the running server keeps in memory and would write over a restored copy.
+ * Restoring keeps the current ones instead.
+ */
+
+const KEPT_OUT: [&str; 6] = [
+    "keys",
+    "admin_login.json",
+    "audit.ndjson",
+    "sessions.json",
+    "api_keys.json",
+    "password_resets.json",
+];
+
+/*
+ * fn kept_out(file: &str) -> bool {}
+ *
+ * Whether a path relative to the state directory is, or is inside, something in KEPT_OUT.
+ */
+
+fn kept_out(file: &str) -> bool {
+    KEPT_OUT.iter().any(|kept| {
+        file.strip_prefix(kept)
+            .is_some_and(|rest| rest.is_empty() || rest.starts_with('/'))
+    })
+}
+
+/*
+ * pub fn checksum(bytes: &[u8]) -> String {}
+ *
+ * Hex encoded SHA-256 of the provided bytes.
+ */
+
+pub fn checksum(bytes: &[u8]) -> String {
+    hex::encode(Sha256::digest(bytes))
+}
+
+/*
+ * fn collect_files(root: &Path, dir: &Path, files: &mut Vec<PathBuf>) -> Result<(), Error> {}
+ *
+ * Recursively collects every file under `dir`, as paths relative to `root`.
+ * Temporary files from interrupted writes and everything in KEPT_OUT are skipped.
+ */
+
+fn collect_files(root: &Path, dir: &Path, files: &mut Vec<PathBuf>) -> Result<(), Error> {
+    for entry in read_dir(dir)? {
+        let entry_path = entry?.path();
+        let relative = entry_path
+            .strip_prefix(root)
+            .map_err(|_| Error::from(ErrorKind::InvalidInput))?;
+        if kept_out(&relative.to_string_lossy().replace('\\', "/")) {
+            continue;
+        }
+        if entry_path.is_dir() {
+            collect_files(root, &entry_path, files)?;
+        } else if entry_path.extension() != Some(OsStr::new("tmp")) {
+            files.push(relative.to_path_buf());
+        }
+    }
+    Ok(())
+}
+
+/*
+ * fn copy_entry(from: &Path, to: &Path) -> Result<(), Error> {}
+ *
+ * Copies a file, or a directory and everything in it, permissions included.
+ */
+
+fn copy_entry(from: &Path, to: &Path) -> Result<(), Error> {
+    if from.is_dir() {
+        create_dir_all(to)?;
+        for entry in read_dir(from)? {
+            let entry = entry?;
+            copy_entry(&entry.path(), &to.join(entry.file_name()))?;
+        }
+        return Ok(());
+    }
+    copy(from, to)?;
+    Ok(())
+}
+
+/*
+ * pub fn create_backup(path: &Path) -> Result<Vec<u8>, Error> {}
+ *
+ * Packs everything in the state directory but KEPT_OUT into a single .tar.gz,
+ * with manifest.json as the first entry.
+ */
+
+pub fn create_backup(path: &Path) -> Result<Vec<u8>, Error> {
+    let mut files = Vec::new();
+    collect_files(path, path, &mut files)?;
+    files.sort();
+
+    let mut contents = Vec::new();
+    let mut manifest = Manifest {
+        created_at: Utc::now().to_rfc3339(),
+        files: Vec::new(),
+    };
+
+    for file in files {
+        let bytes = read(path.join(&file))?;
+        let file = file.to_string_lossy().replace('\\', "/");
+        manifest.files.push(ManifestEntry {
+            path: file.clone(),
+            size: bytes.len() as u64,
+            sha256: checksum(&bytes),
+        });
+        contents.push((file, bytes));
+    }
+
+    let manifest = serde_json::to_vec_pretty(&manifest)?;
+    let mut builder = Builder::new(GzEncoder::new(Vec::new(), Compression::default()));
+
+    let mut append = |name: &str, bytes: &[u8]| -> Result<(), Error> {
+        let mut header = Header::new_gnu();
+        header.set_size(bytes.len() as u64);
+        header.set_mode(0o600);
+        header.set_mtime(Utc::now().timestamp() as u64);
+        builder.append_data(&mut header, name, bytes)
+    };
+
+    append("manifest.json", &manifest)?;
+    for (file, bytes) in &contents {
+        append(&format!("state/{}", file), bytes)?;
+    }
+
+    builder.into_inner()?.finish()
+}
+
+/*
+ * fn invalid(message: &str) -> Error {}
+ *
+ * Shorthand for the errors a rejected backup produces.
+ */
+
+fn invalid(message: &str) -> Error {
+    Error::new(ErrorKind::InvalidData, message.to_string())
+}
+
+/*
+ * fn safe_relative_path(name: &str) -> Option<PathBuf> {}
+ *
+ * Only accepts plain relative paths, so a crafted backup can't write outside the state directory.
+ */
+
+fn safe_relative_path(name: &str) -> Option<PathBuf> {
+    let path = Path::new(name);
+    if path
+        .components()
+        .all(|component| matches!(component, Component::Normal(_)))
+    {
+        return Some(path.to_path_buf());
+    }
+    None
+}
+
+/*
+ * pub fn unpack_backup(bytes: &[u8], target: &Path) -> Result<Manifest, Error> {}
+ *
+ * Unpacks a backup into `target` and validates it:
+ * every file has to be listed in the manifest with a matching size and checksum,
+ * nothing listed may be missing, and data.json and config.json have to parse.
+ * Anything in KEPT_OUT, from backups made before it was kept out or crafted ones, is left unpacked.
+ * Errors with ErrorKind::InvalidData when the backup is rejected.
+ */
+
+pub fn unpack_backup(bytes: &[u8], target: &Path) -> Result<Manifest, Error> {
+    let mut archive = Archive::new(GzDecoder::new(bytes));
+    let mut manifest: Option<Manifest> = None;
+    let mut contents: BTreeMap<String, Vec<u8>> = BTreeMap::new();
+
+    for entry in archive
+        .entries()
+        .map_err(|_| invalid("Not a valid backup archive."))?
+    {
+        let mut entry = entry.map_err(|_| invalid("Not a valid backup archive."))?;
+        if !entry.header().entry_type().is_file() {
+            continue;
+        }
+        let name = entry.path()?.to_string_lossy().replace('\\', "/");
+        let mut buffer = Vec::new();
+        entry.read_to_end(&mut buffer)?;
+
+        if name == "manifest.json" {
+            manifest = Some(
+                serde_json::from_slice(&buffer).map_err(|_| invalid("Manifest is malformed."))?,
+            );
+        } else if let Some(file) = name.strip_prefix("state/") {
+            contents.insert(file.to_string(), buffer);
+        } else {
+            return Err(invalid("Backup contains files outside of state/."));
+        }
+    }
+
+    let manifest = manifest.ok_or_else(|| invalid("Backup is missing its manifest."))?;
+
+    if manifest.files.len() != contents.len() {
+        return Err(invalid("Backup doesn't match its manifest."));
+    }
+
+    for file in &manifest.files {
+        let bytes = contents
+            .get(&file.path)
+            .ok_or_else(|| invalid("Backup is missing a file listed in the manifest."))?;
+        if bytes.len() as u64 != file.size || checksum(bytes) != file.sha256 {
+            return Err(invalid("Checksum mismatch, the backup is corrupted."));
+        }
+    }
+
+    if let Some(data) = contents.get("data.json") {
+        serde_json::from_slice::<JsonData>(data).map_err(|_| invalid("data.json is malformed."))?;
+    }
+    if let Some(config) = contents.get("config.json") {
+        serde_json::from_slice::<Config>(config)
+            .map_err(|_| invalid("config.json is malformed."))?;
+    }
+
+    if target.exists() {
+        remove_dir_all(target)?;
+    }
+    create_dir_all(target)?;
+
+    contents.retain(|file, _| !kept_out(file));
+    for (file, bytes) in contents {
+        let relative =
+            safe_relative_path(&file).ok_or_else(|| invalid("Backup contains an unsafe path."))?;
+        let file_path = target.join(relative);
+        if let Some(parent) = file_path.parent() {
+            create_dir_all(parent)?;
+        }
+        File::create(file_path)?.write_all(&bytes)?;
+    }
+
+    Ok(manifest)
+}
+
+/*
+ * pub fn restore_backup(path: &Path, bytes: &[u8]) -> Result<PathBuf, Error> {}
+ *
+ * Validates and unpacks the backup next to the state directory, copies the current KEPT_OUT over,
+ * then moves the current state to snapshots/state-<timestamp> and swaps the restored one in.
+ * Both moves are renames so the state directory is never half written.
+ * Returns where the previous state was snapshotted to.
+ */
+
+pub fn restore_backup(path: &Path, bytes: &[u8]) -> Result<PathBuf, Error> {
+    let parent = path.parent().unwrap_or(Path::new("."));
+    let staging = parent.join("state.restore");
+
+    let unpacked = unpack_backup(bytes, &staging).and_then(|_| {
+        KEPT_OUT
+            .iter()
+            .filter(|kept| path.join(kept).exists())
+            .try_for_each(|kept| copy_entry(&path.join(kept), &staging.join(kept)))
+    });
+    if let Err(error) = unpacked {
+        let _ = remove_dir_all(&staging);
+        return Err(error);
+    }
+
+    let snapshots = parent.join("snapshots");
+    create_dir_all(&snapshots)?;
+    let snapshot = snapshots.join(format!("state-{}", Utc::now().format("%Y%m%dT%H%M%SZ")));
+
+    if path.exists() {
+        rename(path, &snapshot)?;
+    }
+    if let Err(error) = rename(&staging, path) {
+        let _ = rename(&snapshot, path);
+        return Err(error);
+    }
+
+    Ok(snapshot)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn state_dir(name: &str) -> PathBuf {
+        let path = std::env::temp_dir()
+            .join(format!(
+                "backup-test-{}-{}",
+                std::process::id(),
+                rand::random::<u32>()
+            ))
+            .join(name);
+        create_dir_all(path.join("keys")).unwrap();
+        path
+    }
+
+    #[test]
+    fn keys_and_live_stores_stay_out_of_backups() {
+        let path = state_dir("state");
+        std::fs::write(path.join("data.json"), r#"{"state":[]}"#).unwrap();
+        std::fs::write(path.join("keys").join("ledger.key"), "old").unwrap();
+        std::fs::write(path.join("sessions.json"), "{}").unwrap();
+        std::fs::write(path.join("audit.ndjson"), "").unwrap();
+
+        let backup = create_backup(&path).unwrap();
+        let staging = path.with_file_name("unpacked");
+        let manifest = unpack_backup(&backup, &staging).unwrap();
+        let files: Vec<&str> = manifest
+            .files
+            .iter()
+            .map(|file| file.path.as_str())
+            .collect();
+        assert_eq!(files, vec!["data.json"]);
+
+        std::fs::write(path.join("keys").join("ledger.key"), "current").unwrap();
+        restore_backup(&path, &backup).unwrap();
+        assert_eq!(
+            std::fs::read_to_string(path.join("keys").join("ledger.key")).unwrap(),
+            "current"
+        );
+        assert!(path.join("sessions.json").is_file());
+        assert!(path.join("audit.ndjson").is_file());
+
+        std::fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn kept_out_matches_whole_names() {
+        assert!(kept_out("keys"));
+        assert!(kept_out("keys/ledger.key"));
+        assert!(!kept_out("keys.json"));
+        assert!(kept_out("sessions.json"));
+        assert!(kept_out("admin_login.json"));
+        assert!(!kept_out("archive/2024.json.gz"));
+    }
+}
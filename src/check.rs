@@ -0,0 +1,241 @@
+use super::{
+    archive::{archived_years, parse_date, read_archive},
+    data::{JsonData, JsonState},
+    events::read_events,
+};
+use chrono::prelude::*;
+use std::{collections::BTreeMap, fs::File, io::Error, path::Path};
+
+/*
+ * pub struct Issue {
+ *   pub store: String,
+ *   pub entry: Option<usize>,
+ *   pub message: String,
+ * }
+ *
+ * A single problem found in one of the stores under state/,
+ * entry is the index of the offending record when there is one.
+ */
+
+#[derive(Debug)]
+pub struct Issue {
+    pub store: String,
+    pub entry: Option<usize>,
+    pub message: String,
+}
+
+/*
+ * impl std::fmt::Display for Issue {}
+ *
+ * Formats an issue as a single line of the report.
+ */
+
+impl std::fmt::Display for Issue {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.entry {
+            Some(entry) => write!(f, "{} #{}: {}", self.store, entry, self.message),
+            None => write!(f, "{}: {}", self.store, self.message),
+        }
+    }
+}
+
+/*
+ * fn parse_time(time: &str) -> Option<NaiveTime> {}
+ *
+ * Parses the times we store, HH:MM:SS with optional fractional seconds.
+ */
+
+fn parse_time(time: &str) -> Option<NaiveTime> {
+    NaiveTime::parse_from_str(time, "%H:%M:%S%.f").ok()
+}
+
+/*
+ * pub fn check_days(store: &str, entries: &[JsonState]) -> Vec<Issue> {}
+ *
+ * Validates a list of daily records:
+ * dates parse and are strictly increasing (so no duplicates), dotw matches the date,
+ * times parse, and the cumulative counter never goes down.
+ */
+
+pub fn check_days(store: &str, entries: &[JsonState]) -> Vec<Issue> {
+    let mut issues = Vec::new();
+    let mut issue = |entry: usize, message: String| {
+        issues.push(Issue {
+            store: store.to_string(),
+            entry: Some(entry),
+            message,
+        })
+    };
+
+    let mut previous: Option<(NaiveDate, i32)> = None;
+
+    for (index, entry) in entries.iter().enumerate() {
+        let Some(date) = parse_date(&entry.date) else {
+            issue(index, format!("date \"{}\" isn't YYYY-MM-DD", entry.date));
+            continue;
+        };
+
+        if entry.dotw != date.weekday().to_string() {
+            issue(
+                index,
+                format!(
+                    "dotw \"{}\" doesn't match {}, which is a {}",
+                    entry.dotw,
+                    date,
+                    date.weekday()
+                ),
+            );
+        }
+        if parse_time(&entry.last_time).is_none() {
+            issue(index, format!("time \"{}\" doesn't parse", entry.last_time));
+        }
+        if entry.last_count < 0 || entry.count_since_yesterday < 0 {
+            issue(index, "counts can't be negative".to_string());
+        }
+
+        if let Some((previous_date, previous_count)) = previous {
+            if date == previous_date {
+                issue(index, format!("{} appears more than once", date));
+            } else if date < previous_date {
+                issue(
+                    index,
+                    format!(
+                        "{} comes after {}, dates have to increase",
+                        date, previous_date
+                    ),
+                );
+            }
+            if entry.last_count < previous_count {
+                issue(
+                    index,
+                    format!(
+                        "counter went down from {} to {}",
+                        previous_count, entry.last_count
+                    ),
+                );
+            }
+        }
+        previous = Some((date, entry.last_count));
+    }
+
+    issues
+}
+
+/*
+ * pub fn repair_days(entries: &[JsonState]) -> Vec<JsonState> {}
+ *
+ * Builds a repaired copy of a list of daily records.
+ * Records with unparseable dates are dropped, the rest get sorted by date,
+ * duplicates collapse into the one with the highest counter,
+ * dotw is recalculated, broken times become 00:00:00 and the counter is made non-decreasing.
+ */
+
+pub fn repair_days(entries: &[JsonState]) -> Vec<JsonState> {
+    let mut days: BTreeMap<NaiveDate, JsonState> = BTreeMap::new();
+
+    for entry in entries {
+        let Some(date) = parse_date(&entry.date) else {
+            continue;
+        };
+        match days.get(&date) {
+            Some(existing) if existing.last_count >= entry.last_count => {}
+            _ => {
+                days.insert(date, entry.clone());
+            }
+        }
+    }
+
+    let mut repaired = Vec::new();
+    let mut previous_count = 0;
+    for (date, mut entry) in days {
+        entry.date = date.to_string();
+        entry.dotw = date.weekday().to_string();
+        if parse_time(&entry.last_time).is_none() {
+            entry.last_time = "00:00:00".to_string();
+        }
+        entry.last_count = entry.last_count.max(previous_count);
+        entry.count_since_yesterday = entry.count_since_yesterday.max(0);
+        previous_count = entry.last_count;
+        repaired.push(entry);
+    }
+
+    repaired
+}
+
+/*
+ * pub fn check_state(path: &Path) -> Result<Vec<Issue>, Error> {}
+ *
+ * Runs every check over the stores in the state directory:
+ * data.json, each archived year and events.ndjson.
+ * Stores that don't parse at all are reported as a single issue.
+ */
+
+pub fn check_state(path: &Path) -> Result<Vec<Issue>, Error> {
+    let mut issues = Vec::new();
+
+    let data_path = path.join("data.json");
+    if data_path.is_file() {
+        match serde_json::from_reader::<_, JsonData>(File::open(&data_path)?) {
+            Ok(json_data) => issues.extend(check_days("data.json", &json_data.state)),
+            Err(error) => issues.push(Issue {
+                store: "data.json".to_string(),
+                entry: None,
+                message: format!("doesn't parse: {}", error),
+            }),
+        }
+    }
+
+    for year in archived_years(path)? {
+        let store = format!("archive/{}.json.gz", year);
+        match read_archive(path, year) {
+            Ok(json_data) => {
+                issues.extend(check_days(&store, &json_data.state));
+                for (index, entry) in json_data.state.iter().enumerate() {
+                    if matches!(parse_date(&entry.date), Some(date) if date.year() != year) {
+                        issues.push(Issue {
+                            store: store.clone(),
+                            entry: Some(index),
+                            message: format!("{} is archived in the wrong year", entry.date),
+                        });
+                    }
+                }
+            }
+            Err(error) => issues.push(Issue {
+                store,
+                entry: None,
+                message: format!("doesn't parse: {}", error),
+            }),
+        }
+    }
+
+    match read_events(path) {
+        Ok(events) => {
+            let mut previous: Option<DateTime<FixedOffset>> = None;
+            for (index, event) in events.iter().enumerate() {
+                let Ok(time) = DateTime::parse_from_rfc3339(&event.time) else {
+                    issues.push(Issue {
+                        store: "events.ndjson".to_string(),
+                        entry: Some(index),
+                        message: format!("time \"{}\" isn't RFC 3339", event.time),
+                    });
+                    continue;
+                };
+                if matches!(previous, Some(previous) if time < previous) {
+                    issues.push(Issue {
+                        store: "events.ndjson".to_string(),
+                        entry: Some(index),
+                        message: "event is older than the one before it".to_string(),
+                    });
+                }
+                previous = Some(time);
+            }
+        }
+        Err(error) => issues.push(Issue {
+            store: "events.ndjson".to_string(),
+            entry: None,
+            message: error.to_string(),
+        }),
+    }
+
+    Ok(issues)
+}
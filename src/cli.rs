@@ -0,0 +1,287 @@
+use super::{
+    audit::AuditLog,
+    check::{check_days, check_state, repair_days},
+    config::Config,
+    cookies::rotate,
+    creds::{generate_password, Accounts},
+    data::{read_from_json, write_to_json, AppData, JsonData},
+    hashing::configure,
+    import::{import, parse_csv, ConflictPolicy},
+};
+use serde_json::json;
+use std::{
+    fs::File,
+    io::{Error, ErrorKind},
+    path::Path,
+};
+
+/*
+ * const USAGE: &str
+ *
+ * Printed when the subcommand or its arguments can't be parsed.
+ */
+
+const USAGE: &str = "Usage: qrcode-analytic [command]
+
+Without a command the web server is started.
+
+Commands:
+  import <file.csv> [--policy skip|overwrite|add] [--dry-run]
+      Imports daily counts from a CSV with `date` and `count` columns.
+      Stop the server first, it keeps its own copy of the state in memory.
+  check [--repair <output.json>]
+      Validates state/data.json, the archives and the scan events.
+      With --repair a fixed copy of data.json is written to <output.json>.
+  reset-password <username>
+      Gives an account a new generated password and prints it.
+      Existing sessions of the account are logged out, a running server picks it up by itself.
+  rotate-session-key
+      Replaces the key session cookies are encrypted with. Restart the server afterwards,
+      cookies using the old key keep working for session_key_grace_hours and are moved over.";
+
+/*
+ * fn usage(message: &str) -> Error {}
+ *
+ * Prints the problem and the usage, and returns an error so we exit with a failure code.
+ */
+
+fn usage(message: &str) -> Error {
+    eprintln!("{}\n\n{}", message, USAGE);
+    Error::new(ErrorKind::InvalidInput, message.to_string())
+}
+
+/*
+ * pub async fn run(args: &[String]) -> Option<Result<(), Error>> {}
+ *
+ * Runs the subcommand in `args` (without the program name).
+ * Returns None when there is no subcommand and the server should start instead.
+ */
+
+pub async fn run(args: &[String]) -> Option<Result<(), Error>> {
+    let (command, args) = args.split_first()?;
+
+    Some(match command.as_str() {
+        "import" => import_command(args).await,
+        "check" => check_command(args),
+        "reset-password" => reset_password_command(args),
+        "rotate-session-key" => rotate_session_key_command(args),
+        "help" | "--help" | "-h" => {
+            println!("{}", USAGE);
+            Ok(())
+        }
+        _ => Err(usage(&format!("Unknown command \"{}\".", command))),
+    })
+}
+
+/*
+ * async fn import_command(args: &[String]) -> Result<(), Error> {}
+ *
+ * `qrcode-analytic import <file.csv> [--policy skip|overwrite|add] [--dry-run]`
+ * Prints what the import does, and applies it unless --dry-run is passed.
+ */
+
+async fn import_command(args: &[String]) -> Result<(), Error> {
+    let mut file = None;
+    let mut policy = ConflictPolicy::default();
+    let mut dry_run = false;
+
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--dry-run" => dry_run = true,
+            "--policy" => {
+                let value = args
+                    .next()
+                    .ok_or_else(|| usage("--policy needs a value."))?;
+                policy = value.parse().map_err(|message: String| usage(&message))?;
+            }
+            _ if file.is_none() => file = Some(arg.clone()),
+            _ => return Err(usage(&format!("Unexpected argument \"{}\".", arg))),
+        }
+    }
+    let file = file.ok_or_else(|| usage("Missing the CSV file to import."))?;
+
+    let rows = match parse_csv(File::open(&file)?) {
+        Ok(rows) => rows,
+        Err(errors) => {
+            for error in &errors {
+                eprintln!("{}", error);
+            }
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!(
+                    "{} has {} problems, nothing was imported.",
+                    file,
+                    errors.len()
+                ),
+            ));
+        }
+    };
+
+    let path = Path::new("./state");
+    let config = Config::get(path);
+    config.check()?;
+    let json_data = read_from_json(path).await.unwrap_or_default();
+    let mut app_data = AppData::from(json_data);
+
+    let report = import(
+        path,
+        &mut app_data,
+        &rows,
+        policy,
+        config.archive_after_days,
+        !dry_run,
+    )?;
+
+    for conflict in &report.conflicts {
+        if conflict.overflow {
+            println!(
+                "{}: existing {}, imported {} -> left out, the counter would overflow",
+                conflict.date, conflict.existing, conflict.imported
+            );
+            continue;
+        }
+        println!(
+            "{}: existing {}, imported {} -> {}",
+            conflict.date, conflict.existing, conflict.imported, conflict.result
+        );
+    }
+    println!(
+        "{} rows, {} new days, {} conflicts resolved with {:?}.",
+        report.rows,
+        report.added,
+        report.conflicts.len(),
+        policy
+    );
+
+    if report.applied {
+        write_to_json(path, JsonData::from(&app_data)).await?;
+        AuditLog::new(path).record(
+            "cli",
+            "",
+            "import",
+            json!({
+                "file": file,
+                "rows": report.rows,
+                "added": report.added,
+                "conflicts": report.conflicts.len(),
+                "policy": format!("{:?}", policy),
+            }),
+        )?;
+        println!("Import applied.");
+    } else {
+        println!("Dry run, nothing was written.");
+    }
+    Ok(())
+}
+
+/*
+ * fn check_command(args: &[String]) -> Result<(), Error> {}
+ *
+ * `qrcode-analytic check [--repair <output.json>]`
+ * Prints every issue found in the state, exits with a failure code when there are any.
+ */
+
+fn check_command(args: &[String]) -> Result<(), Error> {
+    let repair = match args {
+        [] => None,
+        [flag, output] if flag == "--repair" => Some(output),
+        _ => return Err(usage("check only takes --repair <output.json>.")),
+    };
+
+    let path = Path::new("./state");
+    let issues = check_state(path)?;
+
+    for issue in &issues {
+        println!("{}", issue);
+    }
+
+    if let Some(output) = repair {
+        let json_data: JsonData = serde_json::from_reader(File::open(path.join("data.json"))?)?;
+        let repaired = JsonData {
+            state: repair_days(&json_data.state),
+        };
+        let remaining = check_days("repaired copy", &repaired.state);
+        serde_json::to_writer(File::create(output)?, &repaired)?;
+        println!(
+            "Wrote a repaired copy of data.json with {} of {} days to {}, {} issues left in it.",
+            repaired.state.len(),
+            json_data.state.len(),
+            output,
+            remaining.len()
+        );
+    }
+
+    if issues.is_empty() {
+        println!("No issues found.");
+        return Ok(());
+    }
+    println!("{} issues found.", issues.len());
+    std::process::exit(1);
+}
+
+/*
+ * fn reset_password_command(args: &[String]) -> Result<(), Error> {}
+ *
+ * `qrcode-analytic reset-password <username>`
+ * For admins who are locked out, replaces the account's password with a generated one.
+ */
+
+fn reset_password_command(args: &[String]) -> Result<(), Error> {
+    let [username] = args else {
+        return Err(usage("reset-password takes exactly one username."));
+    };
+
+    configure(&Config::get(Path::new("./state")).password_hash)?;
+    let mut accounts = Accounts::read()?;
+    let Some(login) = accounts
+        .users
+        .iter_mut()
+        .find(|login| login.username == *username)
+    else {
+        return Err(Error::new(
+            ErrorKind::NotFound,
+            format!("There is no account called \"{}\".", username),
+        ));
+    };
+
+    let password = generate_password();
+    login.set_password(&password);
+    accounts.save()?;
+    AuditLog::new(Path::new("./state")).record(
+        "cli",
+        "",
+        "password_reset",
+        json!({ "username": username }),
+    )?;
+
+    println!(
+        "The new password for \"{}\" is:\n\n    {}\n",
+        username, password
+    );
+    println!("This is the only time it's shown, change it after logging in.");
+    Ok(())
+}
+
+/*
+ * fn rotate_session_key_command(args: &[String]) -> Result<(), Error> {}
+ *
+ * `qrcode-analytic rotate-session-key`
+ * Generates a new session key, the old one stays valid for the configured grace period.
+ */
+
+fn rotate_session_key_command(args: &[String]) -> Result<(), Error> {
+    if !args.is_empty() {
+        return Err(usage("rotate-session-key doesn't take any arguments."));
+    }
+
+    let path = Path::new("./state");
+    let expires = rotate(path, &Config::get(path))?;
+
+    println!(
+        "Generated a new session key, the old one is accepted until {}.",
+        chrono::DateTime::<chrono::Local>::from(expires).format("%Y-%m-%d %H:%M")
+    );
+    println!("Restart the server to start using it.");
+    Ok(())
+}
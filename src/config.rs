@@ -0,0 +1,404 @@
+use super::creds::Role;
+use serde::{Deserialize, Serialize};
+use serde_json::{from_reader, to_writer_pretty};
+use std::{
+    collections::BTreeMap,
+    fs::{create_dir_all, File},
+    io::{Error, ErrorKind},
+    path::Path,
+};
+
+/*
+ * pub enum HashAlgorithm {
+ *   Argon2id,
+ *   Bcrypt,
+ * }
+ *
+ * What new password hashes are made with, existing hashes of either kind keep verifying.
+ */
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum HashAlgorithm {
+    Argon2id,
+    Bcrypt,
+}
+
+/*
+ * pub struct PasswordHashConfig {
+ *   pub algorithm: HashAlgorithm,
+ *   pub argon2_memory_kib: u32,
+ *   pub argon2_iterations: u32,
+ *   pub argon2_parallelism: u32,
+ *   pub bcrypt_cost: u32,
+ * }
+ *
+ * How passwords are hashed, see hashing.rs. The Argon2id defaults are OWASP's recommendation.
+ * Hashes made with a different algorithm or parameters are upgraded the next time their owner logs in.
+ */
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct PasswordHashConfig {
+    pub algorithm: HashAlgorithm,
+    pub argon2_memory_kib: u32,
+    pub argon2_iterations: u32,
+    pub argon2_parallelism: u32,
+    pub bcrypt_cost: u32,
+}
+
+/*
+ * impl Default for PasswordHashConfig {}
+ *
+ * Argon2id with 19 MiB of memory, 2 iterations and 1 lane, bcrypt at cost 12.
+ */
+
+impl Default for PasswordHashConfig {
+    fn default() -> Self {
+        PasswordHashConfig {
+            algorithm: HashAlgorithm::Argon2id,
+            argon2_memory_kib: 19 * 1024,
+            argon2_iterations: 2,
+            argon2_parallelism: 1,
+            bcrypt_cost: 12,
+        }
+    }
+}
+
+/*
+ * pub struct OidcConfig {
+ *   pub issuer: String,
+ *   pub client_id: String,
+ *   pub client_secret: Option<String>,
+ *   pub redirect_uri: String,
+ *   pub scopes: Vec<String>,
+ *   pub username_claim: String,
+ *   pub groups_claim: String,
+ *   pub group_roles: BTreeMap<String, Role>,
+ * }
+ *
+ * Single sign-on through an OpenID Connect provider, see oidc.rs.
+ * redirect_uri has to point at /login/oidc/callback and be registered with the provider.
+ * client_secret can be left out of the file and set through QRCODE_OIDC_CLIENT_SECRET instead.
+ * group_roles maps the provider's groups to roles, people in none of them can't log in.
+ * username_claim only names the account on its first login, logins are matched on the issuer and sub claim.
+ */
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct OidcConfig {
+    pub issuer: String,
+    pub client_id: String,
+    pub client_secret: Option<String>,
+    pub redirect_uri: String,
+    pub scopes: Vec<String>,
+    pub username_claim: String,
+    pub groups_claim: String,
+    pub group_roles: BTreeMap<String, Role>,
+}
+
+/*
+ * impl Default for OidcConfig {}
+ *
+ * Everything but the provider details, which have to be filled in.
+ */
+
+impl Default for OidcConfig {
+    fn default() -> Self {
+        OidcConfig {
+            issuer: String::new(),
+            client_id: String::new(),
+            client_secret: None,
+            redirect_uri: String::new(),
+            scopes: vec![
+                "openid".to_string(),
+                "profile".to_string(),
+                "email".to_string(),
+            ],
+            username_claim: "preferred_username".to_string(),
+            groups_claim: "groups".to_string(),
+            group_roles: BTreeMap::new(),
+        }
+    }
+}
+
+/*
+ * pub enum SmtpSecurity {
+ *   None,
+ *   StartTls,
+ *   Tls,
+ * }
+ *
+ * How the SMTP connection is secured. none is only meant for a local mail catcher.
+ */
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum SmtpSecurity {
+    None,
+    StartTls,
+    Tls,
+}
+
+/*
+ * pub struct SmtpConfig {
+ *   pub host: String,
+ *   pub port: u16,
+ *   pub security: SmtpSecurity,
+ *   pub username: Option<String>,
+ *   pub password: Option<String>,
+ *   pub from: String,
+ *   pub reset_url: String,
+ *   pub reset_token_minutes: i64,
+ * }
+ *
+ * The mail server password reset links are sent through, see mail.rs.
+ * reset_url is the page the link opens, the token is appended as ?token=.
+ * password can be left out of the file and set through QRCODE_SMTP_PASSWORD instead.
+ */
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct SmtpConfig {
+    pub host: String,
+    pub port: u16,
+    pub security: SmtpSecurity,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    pub from: String,
+    pub reset_url: String,
+    pub reset_token_minutes: i64,
+}
+
+/*
+ * impl Default for SmtpConfig {}
+ *
+ * STARTTLS on the submission port and links valid for 30 minutes, the server and addresses have to be filled in.
+ */
+
+impl Default for SmtpConfig {
+    fn default() -> Self {
+        SmtpConfig {
+            host: String::new(),
+            port: 587,
+            security: SmtpSecurity::StartTls,
+            username: None,
+            password: None,
+            from: String::new(),
+            reset_url: String::new(),
+            reset_token_minutes: 30,
+        }
+    }
+}
+
+/*
+ * pub struct AllowlistConfig {
+ *   pub dashboard: Vec<String>,
+ *   pub admin_api: Vec<String>,
+ *   pub scan_api: Vec<String>,
+ *   pub trusted_proxies: Vec<String>,
+ * }
+ *
+ * Where each part of the site can be reached from, as CIDR ranges or single addresses, see allowlist.rs.
+ * An empty list lets everyone in, except for admin_api, which falls back to dashboard when it's empty.
+ * trusted_proxies are the reverse proxies whose X-Forwarded-For is believed.
+ */
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(default)]
+pub struct AllowlistConfig {
+    pub dashboard: Vec<String>,
+    pub admin_api: Vec<String>,
+    pub scan_api: Vec<String>,
+    pub trusted_proxies: Vec<String>,
+}
+
+/*
+ * pub struct Config {
+ *   pub archive_after_days: i64,
+ *   pub max_upload_bytes: usize,
+ *   pub event_retention_days: Option<i64>,
+ *   pub daily_retention_days: Option<i64>,
+ *   pub retention_interval_hours: u64,
+ *   pub session_idle_minutes: i64,
+ *   pub session_absolute_hours: i64,
+ *   pub session_key_grace_hours: u64,
+ *   pub session_retention_hours: i64,
+ *   pub secure_cookies: bool,
+ *   pub trusted_origins: Vec<String>,
+ *   pub ip_allowlist: AllowlistConfig,
+ *   pub oidc: Option<OidcConfig>,
+ *   pub smtp: Option<SmtpConfig>,
+ *   pub password_hash: PasswordHashConfig,
+ * }
+ *
+ * Runtime configuration, read from state/config.json.
+ * A retention of null keeps that data forever, a session timeout of 0 turns that timeout off.
+ * session_retention_hours is how long an unused session is kept server-side, it has to outlast the scan cooldown.
+ * secure_cookies only sends the session cookie over HTTPS, turn it on when the site is served over HTTPS.
+ * trusted_origins are the origins (https://host[:port]) state-changing requests may come from,
+ * empty only allows the host a request was sent to, which breaks behind proxies that rewrite Host.
+ * oidc turns on logging in through the school's identity provider, null leaves it off.
+ * smtp turns on emailed password resets, null leaves them off.
+ * Every field has a default so older config files keep working when new options are added.
+ */
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct Config {
+    pub archive_after_days: i64,
+    pub max_upload_bytes: usize,
+    pub event_retention_days: Option<i64>,
+    pub daily_retention_days: Option<i64>,
+    pub retention_interval_hours: u64,
+    pub session_idle_minutes: i64,
+    pub session_absolute_hours: i64,
+    pub session_key_grace_hours: u64,
+    pub session_retention_hours: i64,
+    pub secure_cookies: bool,
+    pub trusted_origins: Vec<String>,
+    pub ip_allowlist: AllowlistConfig,
+    pub oidc: Option<OidcConfig>,
+    pub smtp: Option<SmtpConfig>,
+    pub password_hash: PasswordHashConfig,
+}
+
+/*
+ * impl Default for Config {}
+ *
+ * Initializes Config with the defaults written on first start.
+ */
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            archive_after_days: 90,
+            max_upload_bytes: 64 * 1024 * 1024,
+            event_retention_days: Some(90),
+            daily_retention_days: None,
+            retention_interval_hours: 24,
+            session_idle_minutes: 60,
+            session_absolute_hours: 12,
+            session_key_grace_hours: 24,
+            session_retention_hours: 48,
+            secure_cookies: false,
+            trusted_origins: Vec::new(),
+            ip_allowlist: AllowlistConfig::default(),
+            oidc: None,
+            smtp: None,
+            password_hash: PasswordHashConfig::default(),
+        }
+    }
+}
+
+/*
+ * const MAX_DAYS: i64
+ *
+ * The most days archive_after_days and the retention settings can be set to,
+ * a century is already far more than anyone keeps.
+ */
+
+const MAX_DAYS: i64 = 36_500;
+
+/*
+ * fn check_days(field: &str, days: i64) -> Result<(), Error> {}
+ *
+ * An error naming `field` unless `days` is between 0 and MAX_DAYS.
+ */
+
+fn check_days(field: &str, days: i64) -> Result<(), Error> {
+    if !(0..=MAX_DAYS).contains(&days) {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            format!(
+                "{} in config.json has to be between 0 and {}.",
+                field, MAX_DAYS
+            ),
+        ));
+    }
+    Ok(())
+}
+
+/*
+ * impl Config {
+ *   pub fn get(path: &Path) -> Self {}
+ *   pub fn check(&self) -> Result<(), Error> {}
+ * }
+ *
+ * Assorted implementations accessed through `Config::function(args)`
+ */
+
+impl Config {
+    /*
+     * pub Config::get(path: &Path) -> Self {}
+     *
+     * If config.json exists in the provided state directory it reads and parses it.
+     *
+     * If it doesn't exist it writes the defaults there so they're easy to find and edit.
+     */
+
+    pub fn get(path: &Path) -> Self {
+        let file_path = path.join("config.json");
+
+        if file_path.is_file() {
+            let file = File::open(file_path).expect("Can't open config file.");
+            return from_reader(file).expect("Can't read config file.");
+        }
+
+        let config = Config::default();
+        create_dir_all(path).expect("Can't create state directory.");
+        let file = File::create(file_path).expect("Can't create config file.");
+        if to_writer_pretty(&file, &config).is_err() {
+            panic!("Failed to write default config.")
+        }
+        config
+    }
+
+    /*
+     * pub Config::check(&self) -> Result<(), Error> {}
+     *
+     * Checks the settings nothing else validates, so out of range ones stop startup
+     * instead of failing the first scan that uses them.
+     */
+
+    pub fn check(&self) -> Result<(), Error> {
+        check_days("archive_after_days", self.archive_after_days)?;
+        if let Some(days) = self.event_retention_days {
+            check_days("event_retention_days", days)?;
+        }
+        if let Some(days) = self.daily_retention_days {
+            check_days("daily_retention_days", days)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn days_have_to_be_in_range() {
+        assert!(Config::default().check().is_ok());
+
+        for days in [-1, MAX_DAYS + 1, i64::MAX] {
+            let config = Config {
+                archive_after_days: days,
+                ..Config::default()
+            };
+            assert!(config.check().is_err(), "{days}");
+
+            let config = Config {
+                event_retention_days: Some(days),
+                ..Config::default()
+            };
+            assert!(config.check().is_err(), "{days}");
+
+            let config = Config {
+                daily_retention_days: Some(days),
+                ..Config::default()
+            };
+            assert!(config.check().is_err(), "{days}");
+        }
+    }
+}
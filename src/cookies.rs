@@ -0,0 +1,425 @@
+use super::{
+    config::Config,
+    keys::{generate_key, load_or_create_key, read_key, write_key},
+};
+use cookie::{Cookie, CookieJar, Key};
+use ntex::{
+    http::header::{self, HeaderValue},
+    service::{Middleware, Service, ServiceCtx},
+    web::{Error as WebError, WebRequest, WebResponse},
+};
+use std::{
+    env,
+    fs::{metadata, read_to_string, remove_file},
+    io::{Error, ErrorKind},
+    path::Path,
+    rc::Rc,
+    time::{Duration, SystemTime},
+};
+
+/*
+ * const MIN_KEY_BYTES: usize
+ *
+ * The shortest key the session cookie can be encrypted with, the cookie crate derives its keys from it.
+ */
+
+const MIN_KEY_BYTES: usize = 32;
+
+/*
+ * pub struct SessionKeys {
+ *   pub current: Vec<u8>,
+ *   pub previous: Option<(Vec<u8>, SystemTime)>,
+ * }
+ *
+ * The key session cookies are encrypted with, and the one it replaced with when that stops being accepted.
+ * The current key comes from, in order:
+ * QRCODE_SESSION_KEY (hex), the file named by QRCODE_SESSION_KEY_FILE (hex),
+ * or state/keys/session.key, generated on first start.
+ * The previous key is state/keys/session.previous.key, written by `qrcode-analytic rotate-session-key`,
+ * and is accepted for config.session_key_grace_hours after it was written.
+ */
+
+#[derive(Clone)]
+pub struct SessionKeys {
+    pub current: Vec<u8>,
+    pub previous: Option<(Vec<u8>, SystemTime)>,
+}
+
+/*
+ * fn check_length(source: &str, key: Vec<u8>) -> Result<Vec<u8>, Error> {}
+ *
+ * Makes sure a key from `source` is long enough to encrypt with.
+ */
+
+fn check_length(source: &str, key: Vec<u8>) -> Result<Vec<u8>, Error> {
+    if key.len() < MIN_KEY_BYTES {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            format!(
+                "{} is {} bytes, session keys need at least {}.",
+                source,
+                key.len(),
+                MIN_KEY_BYTES
+            ),
+        ));
+    }
+    Ok(key)
+}
+
+/*
+ * fn decode_key(source: &str, value: &str) -> Result<Vec<u8>, Error> {}
+ *
+ * Decodes a hex key from `source`.
+ */
+
+fn decode_key(source: &str, value: &str) -> Result<Vec<u8>, Error> {
+    let key = hex::decode(value.trim()).map_err(|_| {
+        Error::new(
+            ErrorKind::InvalidData,
+            format!("{} isn't a hex encoded key.", source),
+        )
+    })?;
+    check_length(source, key)
+}
+
+/*
+ * fn key_from_env() -> Result<Option<Vec<u8>>, Error> {}
+ *
+ * The session key set through QRCODE_SESSION_KEY or QRCODE_SESSION_KEY_FILE, if either is.
+ */
+
+fn key_from_env() -> Result<Option<Vec<u8>>, Error> {
+    if let Ok(value) = env::var("QRCODE_SESSION_KEY") {
+        return decode_key("QRCODE_SESSION_KEY", &value).map(Some);
+    }
+    if let Ok(file) = env::var("QRCODE_SESSION_KEY_FILE") {
+        return decode_key(&file, &read_to_string(&file)?).map(Some);
+    }
+    Ok(None)
+}
+
+/*
+ * fn grace(config: &Config) -> Duration {}
+ *
+ * How long a rotated out key keeps being accepted.
+ */
+
+fn grace(config: &Config) -> Duration {
+    Duration::from_secs(config.session_key_grace_hours * 60 * 60)
+}
+
+/*
+ * impl SessionKeys {
+ *   pub fn load(path: &Path, config: &Config) -> Result<Self, Error> {}
+ * }
+ *
+ * Assorted implementations accessed through `SessionKeys::function(args)`
+ */
+
+impl SessionKeys {
+    /*
+     * pub SessionKeys::load(path: &Path, config: &Config) -> Result<Self, Error> {}
+     *
+     * Loads the current key and, while its grace period lasts, the previous one.
+     * An expired previous key is deleted.
+     */
+
+    pub fn load(path: &Path, config: &Config) -> Result<Self, Error> {
+        let current = match key_from_env()? {
+            Some(key) => key,
+            None => check_length(
+                "state/keys/session.key",
+                load_or_create_key(path, "session")?,
+            )?,
+        };
+
+        let previous_path = path.join("keys").join("session.previous.key");
+        let previous = match read_key(path, "session.previous")? {
+            Some(key) => {
+                let expires = metadata(&previous_path)?.modified()? + grace(config);
+                if expires > SystemTime::now() {
+                    Some((key, expires))
+                } else {
+                    remove_file(&previous_path)?;
+                    tracing::info!("The previous session key's grace period is over, removed it.");
+                    None
+                }
+            }
+            None => None,
+        };
+
+        Ok(SessionKeys { current, previous })
+    }
+}
+
+/*
+ * pub fn rotate(path: &Path, config: &Config) -> Result<SystemTime, Error> {}
+ *
+ * Replaces state/keys/session.key with a new key, keeping the old one as session.previous.key
+ * so existing sessions move over to the new key instead of being logged out.
+ * Returns when the old key stops being accepted.
+ * Keys set through the environment have to be rotated wherever they're set.
+ */
+
+pub fn rotate(path: &Path, config: &Config) -> Result<SystemTime, Error> {
+    if env::var_os("QRCODE_SESSION_KEY").is_some()
+        || env::var_os("QRCODE_SESSION_KEY_FILE").is_some()
+    {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            "The session key is set through the environment, rotate it there.",
+        ));
+    }
+
+    let current = load_or_create_key(path, "session")?;
+    write_key(path, "session.previous", &current)?;
+    write_key(path, "session", &generate_key())?;
+
+    Ok(SystemTime::now() + grace(config))
+}
+
+/*
+ * pub fn reencrypt(cookies: &str, name: &str, current: &Key, previous: &Key) -> Option<(String, Cookie<'static>)> {}
+ *
+ * Looks for the `name` cookie in a Cookie header. If it only decrypts with the previous key,
+ * returns the header with that cookie encrypted with the current key instead, and the new cookie.
+ * None when there is nothing to move over.
+ */
+
+pub fn reencrypt(
+    cookies: &str,
+    name: &str,
+    current: &Key,
+    previous: &Key,
+) -> Option<(String, Cookie<'static>)> {
+    let mut session = None;
+    let mut rest = Vec::new();
+    for cookie in Cookie::split_parse(cookies.to_string()).flatten() {
+        if cookie.name() == name && session.is_none() {
+            session = Some(cookie.into_owned());
+        } else {
+            rest.push(cookie.into_owned());
+        }
+    }
+
+    let mut jar = CookieJar::new();
+    jar.add_original(session?);
+    if jar.private(current).get(name).is_some() {
+        return None;
+    }
+    let decrypted = jar.private(previous).get(name)?;
+
+    let mut jar = CookieJar::new();
+    jar.private_mut(current)
+        .add(Cookie::new(name.to_string(), decrypted.value().to_string()));
+    let encrypted = jar.get(name)?.clone();
+
+    rest.push(Cookie::new(name.to_string(), encrypted.value().to_string()));
+    let header = rest
+        .iter()
+        .map(|cookie| format!("{}={}", cookie.name(), cookie.value()))
+        .collect::<Vec<_>>()
+        .join("; ");
+
+    Some((header, encrypted))
+}
+
+/*
+ * pub struct SessionKeyRotation {
+ *   name: String,
+ *   secure: bool,
+ *   current: Key,
+ *   previous: Option<(Key, SystemTime)>,
+ * }
+ *
+ * Middleware that moves session cookies encrypted with the previous key over to the current one.
+ * It has to wrap ServerSession, so the session middleware only ever sees current cookies.
+ */
+
+#[derive(Clone)]
+pub struct SessionKeyRotation {
+    name: String,
+    secure: bool,
+    current: Key,
+    previous: Option<(Key, SystemTime)>,
+}
+
+/*
+ * impl SessionKeyRotation {
+ *   pub fn new(name: &str, keys: &SessionKeys, secure: bool) -> Self {}
+ *   fn rotate<Err>(&self, req: &mut WebRequest<Err>) -> Option<Cookie<'static>> {}
+ * }
+ *
+ * Assorted implementations accessed through `SessionKeyRotation::function(args)`
+ */
+
+impl SessionKeyRotation {
+    /*
+     * pub SessionKeyRotation::new(name: &str, keys: &SessionKeys, secure: bool) -> Self {}
+     *
+     * `name` and `secure` have to match what ServerSession is configured with.
+     */
+
+    pub fn new(name: &str, keys: &SessionKeys, secure: bool) -> Self {
+        SessionKeyRotation {
+            name: name.to_string(),
+            secure,
+            current: Key::derive_from(&keys.current),
+            previous: keys
+                .previous
+                .as_ref()
+                .map(|(key, expires)| (Key::derive_from(key), *expires)),
+        }
+    }
+
+    /*
+     * SessionKeyRotation::rotate<Err>(&self, req: &mut WebRequest<Err>) -> Option<Cookie<'static>> {}
+     *
+     * Rewrites the request's Cookie header when the session cookie uses the previous key,
+     * returning the re-encrypted cookie to send back.
+     */
+
+    fn rotate<Err>(&self, req: &mut WebRequest<Err>) -> Option<Cookie<'static>> {
+        let (previous, expires) = self.previous.as_ref()?;
+        if SystemTime::now() > *expires {
+            return None;
+        }
+
+        let cookies = req
+            .headers()
+            .get_all(header::COOKIE)
+            .filter_map(|value| value.to_str().ok())
+            .collect::<Vec<_>>()
+            .join("; ");
+        let (rewritten, cookie) = reencrypt(&cookies, &self.name, &self.current, previous)?;
+
+        let value = HeaderValue::from_str(&rewritten).ok()?;
+        req.headers_mut().remove(header::COOKIE);
+        req.headers_mut().insert(header::COOKIE, value);
+        Some(cookie)
+    }
+}
+
+/*
+ * impl<S> Middleware<S> for SessionKeyRotation {}
+ *
+ * Wraps a service so every request goes through the rotation first.
+ */
+
+impl<S> Middleware<S> for SessionKeyRotation {
+    type Service = SessionKeyRotationMiddleware<S>;
+
+    fn create(&self, service: S) -> Self::Service {
+        SessionKeyRotationMiddleware {
+            service,
+            inner: Rc::new(self.clone()),
+        }
+    }
+}
+
+/*
+ * pub struct SessionKeyRotationMiddleware<S> {
+ *   service: S,
+ *   inner: Rc<SessionKeyRotation>,
+ * }
+ *
+ * The service SessionKeyRotation creates.
+ */
+
+pub struct SessionKeyRotationMiddleware<S> {
+    service: S,
+    inner: Rc<SessionKeyRotation>,
+}
+
+/*
+ * impl<S, Err> Service<WebRequest<Err>> for SessionKeyRotationMiddleware<S> {}
+ *
+ * Re-encrypts the request's session cookie if needed, and unless the session middleware
+ * already set a new cookie, sends the re-encrypted one back so the browser stores it.
+ */
+
+impl<S, Err> Service<WebRequest<Err>> for SessionKeyRotationMiddleware<S>
+where
+    S: Service<WebRequest<Err>, Response = WebResponse, Error = WebError>,
+{
+    type Response = WebResponse;
+    type Error = WebError;
+
+    ntex::service::forward_poll_ready!(service);
+    ntex::service::forward_poll_shutdown!(service);
+
+    async fn call(
+        &self,
+        mut req: WebRequest<Err>,
+        ctx: ServiceCtx<'_, Self>,
+    ) -> Result<Self::Response, Self::Error> {
+        let rotated = self.inner.rotate(&mut req);
+        let mut res = ctx.call(&self.service, req).await?;
+
+        if let Some(cookie) = rotated {
+            let prefix = format!("{}=", self.inner.name);
+            let already_set = res
+                .headers()
+                .get_all(header::SET_COOKIE)
+                .any(|value| matches!(value.to_str(), Ok(value) if value.starts_with(&prefix)));
+
+            if !already_set {
+                let cookie = Cookie::build((cookie.name().to_string(), cookie.value().to_string()))
+                    .path("/")
+                    .http_only(true)
+                    .secure(self.inner.secure)
+                    .build();
+                if let Ok(value) = HeaderValue::from_str(&cookie.to_string()) {
+                    res.headers_mut().append(header::SET_COOKIE, value);
+                }
+            }
+        }
+
+        Ok(res)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn encrypt(key: &Key, value: &str) -> String {
+        let mut jar = CookieJar::new();
+        jar.private_mut(key)
+            .add(Cookie::new("qrcode", value.to_string()));
+        jar.get("qrcode").unwrap().value().to_string()
+    }
+
+    #[test]
+    fn previous_key_cookies_are_reencrypted() {
+        let current = Key::derive_from(&[1; 64]);
+        let previous = Key::derive_from(&[2; 64]);
+        let header = format!(
+            "theme=dark; qrcode={}",
+            encrypt(&previous, "{\"user\":\"a\"}")
+        );
+
+        let (rewritten, cookie) = reencrypt(&header, "qrcode", &current, &previous).unwrap();
+        assert!(rewritten.starts_with("theme=dark; qrcode="));
+
+        let mut jar = CookieJar::new();
+        jar.add_original(cookie);
+        let decrypted = jar.private(&current).get("qrcode").unwrap();
+        assert_eq!(decrypted.value(), "{\"user\":\"a\"}");
+    }
+
+    #[test]
+    fn current_and_unknown_cookies_are_left_alone() {
+        let current = Key::derive_from(&[1; 64]);
+        let previous = Key::derive_from(&[2; 64]);
+        let other = Key::derive_from(&[3; 64]);
+
+        let header = format!("qrcode={}", encrypt(&current, "{}"));
+        assert!(reencrypt(&header, "qrcode", &current, &previous).is_none());
+
+        let header = format!("qrcode={}", encrypt(&other, "{}"));
+        assert!(reencrypt(&header, "qrcode", &current, &previous).is_none());
+
+        assert!(reencrypt("theme=dark", "qrcode", &current, &previous).is_none());
+    }
+}
@@ -1,42 +1,368 @@
-use bcrypt::{hash, verify, DEFAULT_COST};
+use super::{
+    config::Config,
+    hashing::{hash, is_hash, needs_rehash, verify},
+    keys::load_or_create_key,
+    totp::SecondFactor,
+};
+use hmac::{Hmac, Mac};
 use password_generator::{generate, PasswordType};
 use serde::{Deserialize, Serialize};
 use serde_json::{from_reader, to_writer};
+use sha2::Sha256;
 use std::{
     env::current_dir,
-    fs::{File, OpenOptions},
+    fs::{create_dir_all, metadata, rename, File, OpenOptions},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex, RwLock, RwLockReadGuard},
+    time::SystemTime,
 };
 
+/*
+ * pub enum Role {
+ *   Admin,
+ *   Viewer,
+ * }
+ *
+ * What an account is allowed to do.
+ * Viewers can read the dashboard and /api/get_data, admins can also manage codes, users and data.
+ */
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    #[default]
+    Admin,
+    Viewer,
+}
+
+/*
+ * impl Role {
+ *   pub fn allows(self, required: Role) -> bool {}
+ * }
+ *
+ * Admins can do everything viewers can.
+ */
+
+impl Role {
+    pub fn allows(self, required: Role) -> bool {
+        self == Role::Admin || self == required
+    }
+}
+
 /*
  * pub struct Login {
  *   pub username: String,
- *   pub password: String,
+ *   pub password_hash: String,
+ *   pub role: Role,
+ *   pub session_epoch: u64,
+ *   pub second_factor: Option<SecondFactor>,
+ *   pub external: bool,
+ *   pub subject: Option<String>,
+ *   pub email: Option<String>,
  * }
  *
- * The struct that holds generated Username and Password.
+ * The struct that holds a single account's Username, Password hash and Role.
+ * session_epoch is part of every session token, bumping it logs the account out everywhere.
+ * second_factor is set once the account starts enrolling in TOTP, see totp.rs.
+ * The hash is an Argon2id PHC string or, for accounts that haven't logged in since, a bcrypt one,
+ * both carry their own algorithm and parameters (see hashing.rs).
+ * Files from before hashing stored the plaintext under "password", Accounts::get() migrates those.
+ * Accounts from before roles existed default to Admin.
+ * External accounts log in through the identity provider only (see oidc.rs), they have no password
+ * and their role is taken from the provider's groups on every login.
+ * subject is who the provider says they are (see oidc::Identity), their logins are matched on it
+ * since usernames at the provider can change hands. Accounts from before it was stored get it on their next login.
+ * email is where password reset links go, accounts without one can only be reset by an admin.
  */
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Login {
     pub username: String,
-    pub password: String,
+    #[serde(alias = "password")]
+    pub password_hash: String,
+    #[serde(default)]
+    pub role: Role,
+    #[serde(default)]
+    pub session_epoch: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub second_factor: Option<SecondFactor>,
+    #[serde(default)]
+    pub external: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub subject: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub email: Option<String>,
+}
+
+/*
+ * pub struct Accounts {
+ *   pub users: Vec<Login>,
+ * }
+ *
+ * Every account, stored in admin_login.json in the state directory.
+ */
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct Accounts {
+    pub users: Vec<Login>,
+}
+
+/*
+ * enum AccountsFile {
+ *   Accounts(Accounts),
+ *   Single(Login),
+ * }
+ *
+ * admin_login.json used to hold a single Login, this reads both the old and the new layout.
+ */
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum AccountsFile {
+    Accounts(Accounts),
+    Single(Login),
+}
+
+/*
+ * pub fn generate_password() -> String {}
+ *
+ * A random 32 character password, for new accounts that weren't given one.
+ */
+
+pub fn generate_password() -> String {
+    generate(32, PasswordType::Ascii).expect("Can't generate.")
+}
+
+/*
+ * pub const MIN_PASSWORD_LENGTH: usize
+ *
+ * The shortest password a user can choose for themselves.
+ */
+
+pub const MIN_PASSWORD_LENGTH: usize = 12;
+
+/*
+ * fn accounts_path() -> PathBuf {}
+ *
+ * Where the accounts are stored, admin_login.json in the state directory.
+ */
+
+fn accounts_path() -> PathBuf {
+    let current_dir = current_dir().expect("Can't get current directory");
+    current_dir.join("state").join("admin_login.json")
+}
+
+/*
+ * fn move_legacy_accounts() -> Result<(), std::io::Error> {}
+ *
+ * Older versions kept admin_login.json in the current directory itself,
+ * it's moved into the state directory the first time the accounts are read after an upgrade.
+ */
+
+fn move_legacy_accounts() -> Result<(), std::io::Error> {
+    let path = accounts_path();
+    let legacy_path = current_dir()?.join("admin_login.json");
+    if path.exists() || !legacy_path.is_file() {
+        return Ok(());
+    }
+
+    if let Some(parent) = path.parent() {
+        create_dir_all(parent)?;
+    }
+    rename(legacy_path, path)
+}
+
+/*
+ * impl Accounts {
+ *   pub fn get() -> Self {}
+ *   pub fn read() -> Result<Self, std::io::Error> {}
+ *   pub fn migrate(&mut self) -> usize {}
+ *   pub fn save(&self) -> Result<(), std::io::Error> {}
+ *   pub fn find(&self, username: &str) -> Option<&Login> {}
+ *   pub fn find_by_email(&self, email: &str) -> Option<&Login> {}
+ *   pub fn authenticate(&self, username: &str, password: &str) -> Option<Login> {}
+ *   pub fn admins(&self) -> usize {}
+ * }
+ *
+ * Assorted implementations accessed through `Accounts::function(args)`
+ */
+
+impl Accounts {
+    /*
+     * pub Accounts::get() -> Self {}
+     *
+     * If admin_login.json exists it reads the json and parses it,
+     * rewriting it in the current layout if it still holds a single Login or plaintext passwords.
+     *
+     * If admin_login.json doesn't exist, it generates an "Administrator" account
+     * and prints its password, the only time it's ever shown.
+     */
+
+    pub fn get() -> Self {
+        move_legacy_accounts().expect("Can't move admin_login.json into the state directory.");
+        let path = accounts_path();
+
+        if !path.is_file() {
+            let password = generate_password();
+            let accounts = Accounts {
+                users: vec![Login::new(
+                    "Administrator".to_string(),
+                    &password,
+                    Role::Admin,
+                )],
+            };
+            accounts.save().expect("Failed to generate login.");
+            println!(
+                "Created the account \"Administrator\" with the password:\n\n    {}\n",
+                password
+            );
+            println!("This is the only time it's shown, only a hash of it is stored.");
+            return accounts;
+        }
+
+        Self::read().expect("Can't read admin_login.json.")
+    }
+
+    /*
+     * pub Accounts::read() -> Result<Self, std::io::Error> {}
+     *
+     * Reads and migrates an existing admin_login.json, without panicking on a broken file
+     * so a bad edit can't take down a running server when it hot-reloads.
+     */
+
+    pub fn read() -> Result<Self, std::io::Error> {
+        move_legacy_accounts()?;
+        let file = File::open(accounts_path())?;
+        let (mut accounts, mut changed) = match from_reader(file)? {
+            AccountsFile::Accounts(accounts) => (accounts, false),
+            AccountsFile::Single(login) => (Accounts { users: vec![login] }, true),
+        };
+
+        let migrated = accounts.migrate();
+        if migrated > 0 {
+            tracing::warn!(
+                "Replaced {} plaintext passwords in admin_login.json with hashes.",
+                migrated
+            );
+            changed = true;
+        }
+        if changed {
+            accounts.save()?;
+        }
+        Ok(accounts)
+    }
+
+    /*
+     * pub Accounts::migrate(&mut self) -> usize {}
+     *
+     * Hashes every password that is still stored in plaintext, returning how many there were.
+     */
+
+    pub fn migrate(&mut self) -> usize {
+        let mut migrated = 0;
+        for login in &mut self.users {
+            if !login.external && !is_hash(&login.password_hash) {
+                let password = std::mem::take(&mut login.password_hash);
+                login.set_password(&password);
+                migrated += 1;
+            }
+        }
+        migrated
+    }
+
+    /*
+     * pub Accounts::save(&self) -> Result<(), std::io::Error> {}
+     *
+     * Writes every account to admin_login.json, through a temporary file
+     * so a crash can't leave it empty or half written.
+     */
+
+    pub fn save(&self) -> Result<(), std::io::Error> {
+        let mut options = OpenOptions::new();
+        options.write(true).create(true).truncate(true);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::OpenOptionsExt;
+            options.mode(0o600);
+        }
+
+        let path = accounts_path();
+        if let Some(parent) = path.parent() {
+            create_dir_all(parent)?;
+        }
+        let temp_path = path.with_extension("json.tmp");
+        let file = options.open(&temp_path)?;
+        to_writer(&file, self)?;
+        file.sync_all()?;
+        rename(temp_path, path)
+    }
+
+    /*
+     * pub Accounts::find(&self, username: &str) -> Option<&Login> {}
+     *
+     * Looks an account up by its username.
+     */
+
+    pub fn find(&self, username: &str) -> Option<&Login> {
+        self.users.iter().find(|login| login.username == username)
+    }
+
+    /*
+     * pub Accounts::find_by_email(&self, email: &str) -> Option<&Login> {}
+     *
+     * Looks an account up by its email address, ignoring case.
+     */
+
+    pub fn find_by_email(&self, email: &str) -> Option<&Login> {
+        self.users.iter().find(|login| {
+            matches!(&login.email, Some(address) if address.eq_ignore_ascii_case(email.trim()))
+        })
+    }
+
+    /*
+     * pub Accounts::authenticate(&self, username: &str, password: &str) -> Option<Login> {}
+     *
+     * Checks a submitted username and plaintext password, returning the account when both match.
+     * Unknown usernames still go through a hash so they take as long as a wrong password.
+     */
+
+    pub fn authenticate(&self, username: &str, password: &str) -> Option<Login> {
+        match self.find(username) {
+            Some(login) if !login.external => login.check_password(password).then(|| login.clone()),
+            _ => {
+                let _ = hash(password);
+                None
+            }
+        }
+    }
+
+    /*
+     * pub Accounts::admins(&self) -> usize {}
+     *
+     * How many admin accounts there are, there always has to be at least one.
+     */
+
+    pub fn admins(&self) -> usize {
+        self.users
+            .iter()
+            .filter(|login| login.role == Role::Admin)
+            .count()
+    }
 }
 
 /*
  * impl Login {
- *  pub fn get() -> Self {}
- *  fn new() -> Self {}
- *  pub fn verify(self, password_hash: String, username: String) -> bool {}
- *  pub fn hash(self) -> Self {}
+ *  pub fn new(username: String, password: &str, role: Role) -> Self {}
+ *  pub fn new_external(username: String, subject: String, role: Role) -> Self {}
+ *  pub fn set_password(&mut self, password: &str) {}
+ *  pub fn check_password(&self, password: &str) -> bool {}
+ *  pub fn requires_second_factor(&self) -> bool {}
  * }
  *
  * Assorted implementations accessed through `Login::function(args)`
  * Can also be accessed like this aswell:
  * ```rust
- * let login: Login {
- *   username: "Test".to_string(),
- *   password: "password".to_string(),
- * }
+ * let login = Login::new("Test".to_string(), "password", Role::Viewer);
  *
  * login.function(args)
  * ```
@@ -44,88 +370,539 @@ pub struct Login {
 
 impl Login {
     /*
-     * pub Login::get() -> Self {}
+     * pub Login::new(username: String, password: &str, role: Role) -> Self {}
      *
-     * If admin_login.json exists it reads the json, parses it,
-     * hashes the password, and later returns it.
+     * Creates an account, storing only a hash of the password.
+     * Doesn't store it, that's up to Accounts::save().
+     */
+
+    pub fn new(username: String, password: &str, role: Role) -> Self {
+        let mut login = Login {
+            username,
+            password_hash: String::new(),
+            role,
+            session_epoch: 0,
+            second_factor: None,
+            external: false,
+            subject: None,
+            email: None,
+        };
+        login.set_password(password);
+        login
+    }
+
+    /*
+     * pub Login::new_external(username: String, subject: String, role: Role) -> Self {}
      *
-     * If admin_login.json doesn't exist, it runs Login::new() -> Self {}
+     * Creates an account for someone who logs in through the identity provider, without a password.
      */
 
-    pub fn get() -> Self {
-        let current_dir = current_dir().expect("Can't get current directory");
-        let path = current_dir.join("admin_login.json");
+    pub fn new_external(username: String, subject: String, role: Role) -> Self {
+        Login {
+            username,
+            password_hash: String::new(),
+            role,
+            session_epoch: 0,
+            second_factor: None,
+            external: true,
+            subject: Some(subject),
+            email: None,
+        }
+    }
 
-        if !path.is_file() {
-            return Self::new();
+    /*
+     * pub Login::set_password(&mut self, password: &str) {}
+     *
+     * Replaces the stored hash with one of a new plaintext password.
+     */
+
+    pub fn set_password(&mut self, password: &str) {
+        self.password_hash = hash(password);
+    }
+
+    /*
+     * pub Login::check_password(&self, password: &str) -> bool {}
+     *
+     * Checks a plaintext password against the stored hash.
+     */
+
+    pub fn check_password(&self, password: &str) -> bool {
+        verify(password, &self.password_hash)
+    }
+
+    /*
+     * pub Login::requires_second_factor(&self) -> bool {}
+     *
+     * Whether logging in takes a TOTP or recovery code after the password.
+     */
+
+    pub fn requires_second_factor(&self) -> bool {
+        matches!(&self.second_factor, Some(second_factor) if second_factor.confirmed)
+    }
+}
+
+/*
+ * fn modified_time() -> Option<SystemTime> {}
+ *
+ * When admin_login.json was last written, None if that can't be read.
+ */
+
+fn modified_time() -> Option<SystemTime> {
+    metadata(accounts_path())
+        .and_then(|metadata| metadata.modified())
+        .ok()
+}
+
+/*
+ * pub struct Auth {
+ *   key: Vec<u8>,
+ *   accounts: RwLock<Accounts>,
+ *   modified: Mutex<Option<SystemTime>>,
+ *   idle_timeout: i64,
+ *   absolute_timeout: i64,
+ * }
+ *
+ * Issues and checks the token a logged in session carries, and keeps the accounts in memory
+ * so requests don't have to read admin_login.json. watch_accounts() reloads them when the file changes.
+ * The token is an HMAC-SHA256 over the username, password hash and session epoch, keyed with
+ * state/keys/token.key, so it reveals nothing, can't be forged without the key,
+ * and stops working once the password changes or the account's sessions are revoked.
+ * The timeouts are in seconds, 0 turns them off.
+ */
+
+pub struct Auth {
+    key: Vec<u8>,
+    accounts: RwLock<Accounts>,
+    modified: Mutex<Option<SystemTime>>,
+    idle_timeout: i64,
+    absolute_timeout: i64,
+}
+
+/*
+ * impl Auth {
+ *   pub fn load(path: &Path, config: &Config) -> Result<Self, std::io::Error> {}
+ *   pub fn new(key: Vec<u8>, accounts: Accounts, config: &Config) -> Self {}
+ *   pub fn accounts(&self) -> RwLockReadGuard<'_, Accounts> {}
+ *   pub fn find(&self, username: &str) -> Option<Login> {}
+ *   pub fn authenticate(&self, username: &str, password: &str) -> Option<Login> {}
+ *   pub fn update<T, E>(&self, edit: impl FnOnce(&mut Accounts) -> Result<T, E>) -> Result<Result<T, E>, std::io::Error> {}
+ *   pub fn external_login(&self, subject: &str, username: &str, role: Role) -> Result<Option<Login>, std::io::Error> {}
+ *   pub fn reload_if_changed(&self) -> Result<bool, std::io::Error> {}
+ *   pub fn is_expired(&self, created: i64, seen: i64, now: i64) -> bool {}
+ *   pub fn token(&self, login: &Login) -> String {}
+ *   pub fn verify_token(&self, login: &Login, token: &str) -> bool {}
+ * }
+ *
+ * Assorted implementations accessed through `Auth::function(args)`
+ */
+
+impl Auth {
+    /*
+     * pub Auth::load(path: &Path, config: &Config) -> Result<Self, std::io::Error> {}
+     *
+     * Loads the token key from the state directory, generating it on first start,
+     * and the accounts from admin_login.json, generating the first account if there are none.
+     */
+
+    pub fn load(path: &Path, config: &Config) -> Result<Self, std::io::Error> {
+        let auth = Self::new(load_or_create_key(path, "token")?, Accounts::get(), config);
+        *auth.modified.lock().expect("Auth lock poisoned") = modified_time();
+        Ok(auth)
+    }
+
+    /*
+     * pub Auth::new(key: Vec<u8>, accounts: Accounts, config: &Config) -> Self {}
+     *
+     * Builds an Auth around an existing key and set of accounts, with the session timeouts from config.
+     */
+
+    pub fn new(key: Vec<u8>, accounts: Accounts, config: &Config) -> Self {
+        Auth {
+            key,
+            accounts: RwLock::new(accounts),
+            modified: Mutex::new(None),
+            idle_timeout: config.session_idle_minutes * 60,
+            absolute_timeout: config.session_absolute_hours * 60 * 60,
         }
+    }
+
+    /*
+     * pub Auth::accounts(&self) -> RwLockReadGuard<'_, Accounts> {}
+     *
+     * The cached accounts, don't hold on to it across an await.
+     */
 
-        let file = File::open(path).expect("Can't open file.");
-        let mut json_data: Login = from_reader(file).expect("Can't read json file.");
-        json_data = json_data.hash();
-        json_data
+    pub fn accounts(&self) -> RwLockReadGuard<'_, Accounts> {
+        self.accounts.read().expect("Auth lock poisoned")
     }
 
     /*
-     * Login::new() -> Self {}
+     * pub Auth::find(&self, username: &str) -> Option<Login> {}
      *
-     * Generates admin_login.json for you, default username being "Administrator".
-     * Password is automatically generated, being 32 characters long.
+     * Looks an account up in the cache.
      */
 
-    fn new() -> Self {
-        let current_dir = current_dir().expect("Can't get current directory");
-        let path = current_dir.join("admin_login.json");
+    pub fn find(&self, username: &str) -> Option<Login> {
+        self.accounts().find(username).cloned()
+    }
 
-        let file = if path.is_file() {
-            OpenOptions::new()
-                .write(true)
-                .read(true)
-                .open(path)
-                .expect("Can't open file.")
-        } else {
-            File::create_new(path).expect("Can't create file.")
-        };
+    /*
+     * pub Auth::authenticate(&self, username: &str, password: &str) -> Option<Login> {}
+     *
+     * Accounts::authenticate() against the cache. A hash made with an outdated algorithm or parameters
+     * is replaced with a fresh one while the plaintext is at hand, which logs out the account's
+     * other sessions once since their tokens cover the old hash.
+     * Failing to save the new hash doesn't fail the login, it's tried again next time.
+     * A password changed in the meantime is left alone.
+     */
 
-        let mut login = Login {
-            username: "Administrator".to_string(),
-            password: generate(32, PasswordType::Ascii).expect("Can't generate."),
-        };
+    pub fn authenticate(&self, username: &str, password: &str) -> Option<Login> {
+        let login = self.accounts().authenticate(username, password)?;
+        if !needs_rehash(&login.password_hash) {
+            return Some(login);
+        }
+
+        let password_hash = hash(password);
+        let upgraded = self.update(|accounts| {
+            let account = accounts
+                .users
+                .iter_mut()
+                .find(|account| account.username == login.username)
+                .filter(|account| account.password_hash == login.password_hash)
+                .ok_or(())?;
+            account.password_hash = password_hash;
+            Ok(account.clone())
+        });
 
-        if to_writer(&file, &login).is_err() {
-            panic!("Failed to generate login.")
+        match upgraded {
+            Ok(Ok(upgraded)) => {
+                tracing::info!("Upgraded the password hash of {}.", login.username);
+                Some(upgraded)
+            }
+            Ok(Err(())) => Some(login),
+            Err(error) => {
+                tracing::warn!(
+                    "Failed to upgrade the password hash of {}: {}",
+                    login.username,
+                    error
+                );
+                Some(login)
+            }
         }
+    }
 
-        login = login.hash();
-        login
+    /*
+     * pub Auth::update<T, E>(&self, edit: impl FnOnce(&mut Accounts) -> Result<T, E>) -> Result<Result<T, E>, std::io::Error> {}
+     *
+     * Edits the accounts and writes them to admin_login.json under one lock, so edits running
+     * at the same time, or an outside change the watcher hasn't picked up yet, aren't lost.
+     * Nothing is written when `edit` returns an Err, it's handed back as is.
+     * The watcher doesn't pick the write up as an outside change.
+     * Requests wait on the lock, hash passwords before calling it rather than inside `edit`.
+     */
+
+    pub fn update<T, E>(
+        &self,
+        edit: impl FnOnce(&mut Accounts) -> Result<T, E>,
+    ) -> Result<Result<T, E>, std::io::Error> {
+        let mut modified = self.modified.lock().expect("Auth lock poisoned");
+        let mut cached = self.accounts.write().expect("Auth lock poisoned");
+        let current = modified_time();
+        if current.is_some() && current != *modified {
+            *cached = Accounts::read()?;
+            *modified = modified_time();
+        }
+
+        let mut accounts = cached.clone();
+        let edited = edit(&mut accounts);
+        if edited.is_ok() {
+            accounts.save()?;
+            *modified = modified_time();
+            *cached = accounts;
+        }
+        Ok(edited)
     }
 
     /*
-     * pub Login::verify(self, password_hash: String, username: String) -> bool {}
+     * pub Auth::external_login(&self, subject: &str, username: &str, role: Role) -> Result<Option<Login>, std::io::Error> {}
      *
-     * Verifies provided username and password_hash with it's own values,
-     * and returns a bool whether they match or not.
+     * The account for someone the identity provider vouched for, found by their `subject`,
+     * creating it as `username` on their first login and updating its role to the one their groups map to now.
+     * The username is only what the account is called, a later change at the provider doesn't rename it.
+     * None if another account already has the username, so the provider can't take it over.
      */
 
-    pub fn verify(self, password_hash: String, username: String) -> bool {
-        if username != self.username
-            && !verify(self.password, &password_hash).expect("Can't verify password.")
-        {
-            return false;
+    pub fn external_login(
+        &self,
+        subject: &str,
+        username: &str,
+        role: Role,
+    ) -> Result<Option<Login>, std::io::Error> {
+        let known = self
+            .accounts()
+            .users
+            .iter()
+            .find(|login| login.external && login.subject.as_deref() == Some(subject))
+            .cloned();
+        if let Some(login) = known.filter(|login| login.role == role) {
+            return Ok(Some(login));
+        }
+
+        let login = self.update(|accounts| {
+            let matched = accounts
+                .users
+                .iter()
+                .position(|login| login.external && login.subject.as_deref() == Some(subject))
+                .or_else(|| {
+                    accounts
+                        .users
+                        .iter()
+                        .position(|login| login.username == username)
+                });
+            let Some(index) = matched else {
+                let login = Login::new_external(username.to_string(), subject.to_string(), role);
+                accounts.users.push(login.clone());
+                return Ok(login);
+            };
+
+            let login = &mut accounts.users[index];
+            if !login.external
+                || login
+                    .subject
+                    .as_deref()
+                    .is_some_and(|known| known != subject)
+            {
+                return Err(());
+            }
+            login.subject = Some(subject.to_string());
+            login.role = role;
+            Ok(login.clone())
+        })?;
+        Ok(login.ok())
+    }
+
+    /*
+     * pub Auth::reload_if_changed(&self) -> Result<bool, std::io::Error> {}
+     *
+     * Reloads the cache if admin_login.json has been modified since it was last read or written,
+     * returns whether it did. A missing file keeps the cached accounts.
+     */
+
+    pub fn reload_if_changed(&self) -> Result<bool, std::io::Error> {
+        let mut modified = self.modified.lock().expect("Auth lock poisoned");
+        let current = modified_time();
+        if current.is_none() || current == *modified {
+            return Ok(false);
         }
-        true
+
+        let accounts = Accounts::read()?;
+        *modified = modified_time();
+        *self.accounts.write().expect("Auth lock poisoned") = accounts;
+        Ok(true)
     }
 
     /*
-     * pub Login::hash(self) -> Self {}
+     * pub Auth::is_expired(&self, created: i64, seen: i64, now: i64) -> bool {}
      *
-     * Hashes the password field of Login.
+     * Whether a session that logged in at `created` and was last used at `seen` (unix seconds)
+     * has run past the absolute or the idle timeout.
      */
 
-    pub fn hash(self) -> Self {
-        Login {
-            username: self.username,
-            password: hash(self.password, DEFAULT_COST).expect("Failed to hash password"),
+    pub fn is_expired(&self, created: i64, seen: i64, now: i64) -> bool {
+        (self.absolute_timeout > 0 && now - created > self.absolute_timeout)
+            || (self.idle_timeout > 0 && now - seen > self.idle_timeout)
+    }
+
+    /*
+     * Auth::mac(&self, login: &Login) -> Hmac<Sha256> {}
+     *
+     * The HMAC every token is derived from.
+     */
+
+    fn mac(&self, login: &Login) -> Hmac<Sha256> {
+        let mut mac = Hmac::<Sha256>::new_from_slice(&self.key).expect("HMAC takes any key size");
+        mac.update(login.username.as_bytes());
+        mac.update(b"\n");
+        mac.update(login.password_hash.as_bytes());
+        mac.update(b"\n");
+        mac.update(login.session_epoch.to_string().as_bytes());
+        mac
+    }
+
+    /*
+     * pub Auth::token(&self, login: &Login) -> String {}
+     *
+     * The hex encoded token to store in the session after a successful login.
+     */
+
+    pub fn token(&self, login: &Login) -> String {
+        hex::encode(self.mac(login).finalize().into_bytes())
+    }
+
+    /*
+     * pub Auth::verify_token(&self, login: &Login, token: &str) -> bool {}
+     *
+     * Checks a token from the session in constant time.
+     */
+
+    pub fn verify_token(&self, login: &Login, token: &str) -> bool {
+        match hex::decode(token) {
+            Ok(token) => self.mac(login).verify_slice(&token).is_ok(),
+            Err(_) => false,
+        }
+    }
+}
+
+/*
+ * pub async fn watch_accounts(auth: Arc<Auth>) {}
+ *
+ * Checks admin_login.json for changes every couple of seconds,
+ * so accounts edited by hand or by the CLI apply without a restart.
+ */
+
+pub async fn watch_accounts(auth: Arc<Auth>) {
+    let interval = std::time::Duration::from_secs(2);
+
+    loop {
+        tokio::time::sleep(interval).await;
+        match auth.reload_if_changed() {
+            Ok(true) => tracing::info!("Reloaded accounts from admin_login.json."),
+            Ok(false) => {}
+            Err(error) => tracing::error!("Failed to reload admin_login.json: {}", error),
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn accounts() -> Accounts {
+        Accounts {
+            users: vec![Login::new(
+                "teacher".to_string(),
+                "correct horse",
+                Role::Viewer,
+            )],
+        }
+    }
+
+    #[test]
+    fn correct_credentials_authenticate() {
+        let login = accounts().authenticate("teacher", "correct horse");
+        assert_eq!(
+            login.map(|login| login.username),
+            Some("teacher".to_string())
+        );
+    }
+
+    #[test]
+    fn wrong_user_is_rejected() {
+        assert!(accounts().authenticate("admin", "correct horse").is_none());
+    }
+
+    #[test]
+    fn wrong_password_is_rejected() {
+        assert!(accounts()
+            .authenticate("teacher", "battery staple")
+            .is_none());
+    }
+
+    #[test]
+    fn issued_token_verifies() {
+        let auth = Auth::new(vec![7; 64], Accounts::default(), &Config::default());
+        let login = &accounts().users[0];
+        assert!(auth.verify_token(login, &auth.token(login)));
+    }
+
+    #[test]
+    fn tampered_token_is_rejected() {
+        let auth = Auth::new(vec![7; 64], Accounts::default(), &Config::default());
+        let login = &accounts().users[0];
+        let mut token = auth.token(login).into_bytes();
+        token[0] = if token[0] == b'0' { b'1' } else { b'0' };
+        let token = String::from_utf8(token).unwrap();
+
+        assert!(!auth.verify_token(login, &token));
+        assert!(!auth.verify_token(login, "not even hex"));
+        assert!(!auth.verify_token(login, ""));
+    }
+
+    #[test]
+    fn token_is_bound_to_user_key_and_password() {
+        let auth = Auth::new(vec![7; 64], Accounts::default(), &Config::default());
+        let login = accounts().users[0].clone();
+        let token = auth.token(&login);
+
+        let mut other_user = login.clone();
+        other_user.username = "admin".to_string();
+        assert!(!auth.verify_token(&other_user, &token));
+
+        let mut changed_password = login.clone();
+        changed_password.set_password("battery staple");
+        assert!(!auth.verify_token(&changed_password, &token));
+
+        let mut revoked = login.clone();
+        revoked.session_epoch += 1;
+        assert!(!auth.verify_token(&revoked, &token));
+
+        assert!(
+            !Auth::new(vec![8; 64], Accounts::default(), &Config::default())
+                .verify_token(&login, &token)
+        );
+    }
+
+    #[test]
+    fn sessions_expire() {
+        let config = Config {
+            session_idle_minutes: 30,
+            session_absolute_hours: 8,
+            ..Config::default()
+        };
+        let auth = Auth::new(vec![7; 64], Accounts::default(), &config);
+        let created = 1_000_000;
+
+        assert!(!auth.is_expired(created, created, created + 60));
+        assert!(!auth.is_expired(created, created + 7 * 3600, created + 7 * 3600 + 29 * 60));
+        assert!(auth.is_expired(created, created, created + 31 * 60));
+        assert!(auth.is_expired(created, created + 8 * 3600, created + 8 * 3600 + 1));
+
+        let config = Config {
+            session_idle_minutes: 0,
+            session_absolute_hours: 0,
+            ..Config::default()
+        };
+        let auth = Auth::new(vec![7; 64], Accounts::default(), &config);
+        assert!(!auth.is_expired(0, 0, created));
+    }
+
+    #[test]
+    fn plaintext_passwords_are_migrated() {
+        let mut accounts: Accounts = serde_json::from_str(
+            r#"{"users":[{"username":"teacher","password":"correct horse","role":"viewer"}]}"#,
+        )
+        .unwrap();
+
+        assert_eq!(accounts.migrate(), 1);
+        assert!(is_hash(&accounts.users[0].password_hash));
+        assert!(accounts.authenticate("teacher", "correct horse").is_some());
+        assert_eq!(accounts.migrate(), 0);
+        assert!(!serde_json::to_string(&accounts)
+            .unwrap()
+            .contains("correct horse"));
+    }
+
+    #[test]
+    fn external_accounts_have_no_password() {
+        let mut accounts = Accounts {
+            users: vec![Login::new_external(
+                "teacher".to_string(),
+                "https://idp.example teacher".to_string(),
+                Role::Viewer,
+            )],
+        };
+
+        assert_eq!(accounts.migrate(), 0);
+        assert!(accounts.authenticate("teacher", "").is_none());
+    }
+}
@@ -0,0 +1,334 @@
+use super::{api::Response, config::Config, oidc::random_token};
+use ntex::{
+    http::{header, Method},
+    service::{Middleware, Service, ServiceCtx},
+    web::{Error as WebError, HttpResponse, WebRequest, WebResponse},
+};
+use ntex_session::{Session, UserSession};
+use percent_encoding::percent_decode_str;
+use std::{borrow::Cow, rc::Rc};
+
+/*
+ * pub const TOKEN_HEADER: &str
+ *
+ * The header state-changing requests carry the token from /api/csrf in.
+ */
+
+pub const TOKEN_HEADER: &str = "X-CSRF-Token";
+
+/*
+ * const PROTECTED: [&str; 3]
+ *
+ * The routes, and everything below them, whose state-changing requests are checked.
+ */
+
+const PROTECTED: [&str; 3] = ["/api", "/login", "/logout"];
+
+/*
+ * const EXEMPT: [&str; 1]
+ *
+ * Protected routes that don't rely on the session cookie at all, so there's nothing to forge.
+ * /api/token takes an API key in its body.
+ */
+
+const EXEMPT: [&str; 1] = ["/api/token"];
+
+/*
+ * fn below(path: &str, route: &str) -> bool {}
+ *
+ * Whether `path` is `route` or something under it.
+ */
+
+fn below(path: &str, route: &str) -> bool {
+    path.strip_prefix(route)
+        .is_some_and(|rest| rest.is_empty() || rest.starts_with('/'))
+}
+
+/*
+ * pub fn decoded_path(path: &str) -> Cow<'_, str> {}
+ *
+ * A request path with its percent-encoding undone, which is how the router matches it,
+ * so /%61pi can't slip past checks that look for /api.
+ */
+
+pub fn decoded_path(path: &str) -> Cow<'_, str> {
+    percent_decode_str(path).decode_utf8_lossy()
+}
+
+/*
+ * fn needs_check(method: &Method, path: &str) -> bool {}
+ *
+ * Whether a request has to pass the origin and token checks, `path` has to be decoded already.
+ */
+
+fn needs_check(method: &Method, path: &str) -> bool {
+    !matches!(
+        *method,
+        Method::GET | Method::HEAD | Method::OPTIONS | Method::TRACE
+    ) && PROTECTED.iter().any(|route| below(path, route))
+        && !EXEMPT.iter().any(|route| below(path, route))
+}
+
+/*
+ * pub fn origin_of(url: &str) -> Option<String> {}
+ *
+ * The lowercased scheme://host[:port] of an Origin or Referer value, None for "null" and anything unparsable.
+ */
+
+pub fn origin_of(url: &str) -> Option<String> {
+    let (scheme, rest) = url.trim().split_once("://")?;
+    let authority = rest.split(['/', '?', '#']).next()?;
+    if scheme.is_empty() || authority.is_empty() || authority.contains('@') {
+        return None;
+    }
+    Some(format!("{}://{}", scheme, authority).to_lowercase())
+}
+
+/*
+ * pub fn allowed_origin(origin: &str, trusted: &[String], host: Option<&str>) -> bool {}
+ *
+ * Whether requests from `origin` are let through. With no trusted origins configured,
+ * only the host the request was sent to is, whatever the scheme.
+ */
+
+pub fn allowed_origin(origin: &str, trusted: &[String], host: Option<&str>) -> bool {
+    if !trusted.is_empty() {
+        return trusted
+            .iter()
+            .any(|trusted| origin_of(trusted).as_deref() == Some(origin));
+    }
+    matches!((origin.split_once("://"), host),
+        (Some((_, authority)), Some(host)) if authority.eq_ignore_ascii_case(host))
+}
+
+/*
+ * fn same(a: &[u8], b: &[u8]) -> bool {}
+ *
+ * Compares two tokens without bailing out at the first difference.
+ */
+
+fn same(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (a, b)| diff | (a ^ b)) == 0
+}
+
+/*
+ * pub fn session_token(session: &Session) -> Result<String, WebError> {}
+ *
+ * The session's CSRF token, created the first time it's asked for.
+ * Logging in and out drops it, so a token from before logging in is worth nothing afterwards.
+ */
+
+pub fn session_token(session: &Session) -> Result<String, WebError> {
+    if let Some(token) = session.get::<String>("csrf")? {
+        return Ok(token);
+    }
+    let token = random_token();
+    session.set("csrf", &token)?;
+    Ok(token)
+}
+
+/*
+ * pub struct CsrfProtection {
+ *   trusted: Vec<String>,
+ * }
+ *
+ * Middleware that refuses POST, PUT, PATCH and DELETE requests to /login, /logout and /api
+ * unless they come from a trusted origin and carry the session's token in X-CSRF-Token.
+ * It has to sit inside ServerSession, it reads the token from the session.
+ */
+
+#[derive(Clone)]
+pub struct CsrfProtection {
+    trusted: Vec<String>,
+}
+
+/*
+ * impl CsrfProtection {
+ *   pub fn new(config: &Config) -> Self {}
+ *   fn check<Err>(&self, req: &WebRequest<Err>) -> Option<&'static str> {}
+ * }
+ *
+ * Assorted implementations accessed through `CsrfProtection::function(args)`
+ */
+
+impl CsrfProtection {
+    /*
+     * pub CsrfProtection::new(config: &Config) -> Self {}
+     *
+     * Protection trusting config.trusted_origins.
+     */
+
+    pub fn new(config: &Config) -> Self {
+        CsrfProtection {
+            trusted: config.trusted_origins.clone(),
+        }
+    }
+
+    /*
+     * CsrfProtection::check<Err>(&self, req: &WebRequest<Err>) -> Option<&'static str> {}
+     *
+     * Why the request has to be refused, None when it's fine.
+     * Without an Origin the Referer is checked instead, without either only the token counts,
+     * so scripts that don't send them keep working.
+     */
+
+    fn check<Err>(&self, req: &WebRequest<Err>) -> Option<&'static str> {
+        if !needs_check(req.method(), &decoded_path(req.path())) {
+            return None;
+        }
+
+        let source = req
+            .headers()
+            .get(header::ORIGIN)
+            .or_else(|| req.headers().get(header::REFERER))
+            .map(|value| value.to_str().ok().and_then(origin_of));
+        let host = req
+            .headers()
+            .get(header::HOST)
+            .and_then(|value| value.to_str().ok());
+        match source {
+            Some(Some(origin)) if allowed_origin(&origin, &self.trusted, host) => {}
+            Some(_) => return Some("The request came from a site that isn't trusted."),
+            None => {}
+        }
+
+        let expected = req.get_session().get::<String>("csrf").ok().flatten();
+        let sent = req
+            .headers()
+            .get(TOKEN_HEADER)
+            .and_then(|value| value.to_str().ok());
+        match (expected, sent) {
+            (Some(expected), Some(sent)) if same(expected.as_bytes(), sent.as_bytes()) => None,
+            _ => Some("The CSRF token is missing or wrong, reload the page and try again."),
+        }
+    }
+}
+
+/*
+ * impl<S> Middleware<S> for CsrfProtection {}
+ *
+ * Wraps a service so every request is checked first.
+ */
+
+impl<S> Middleware<S> for CsrfProtection {
+    type Service = CsrfProtectionMiddleware<S>;
+
+    fn create(&self, service: S) -> Self::Service {
+        CsrfProtectionMiddleware {
+            service,
+            inner: Rc::new(self.clone()),
+        }
+    }
+}
+
+/*
+ * pub struct CsrfProtectionMiddleware<S> {
+ *   service: S,
+ *   inner: Rc<CsrfProtection>,
+ * }
+ *
+ * The service CsrfProtection creates.
+ */
+
+pub struct CsrfProtectionMiddleware<S> {
+    service: S,
+    inner: Rc<CsrfProtection>,
+}
+
+/*
+ * impl<S, Err> Service<WebRequest<Err>> for CsrfProtectionMiddleware<S> {}
+ *
+ * Answers refused requests with a 403 in the usual Response shape, passes the rest on.
+ */
+
+impl<S, Err> Service<WebRequest<Err>> for CsrfProtectionMiddleware<S>
+where
+    S: Service<WebRequest<Err>, Response = WebResponse, Error = WebError>,
+{
+    type Response = WebResponse;
+    type Error = WebError;
+
+    ntex::service::forward_poll_ready!(service);
+    ntex::service::forward_poll_shutdown!(service);
+
+    async fn call(
+        &self,
+        req: WebRequest<Err>,
+        ctx: ServiceCtx<'_, Self>,
+    ) -> Result<Self::Response, Self::Error> {
+        if let Some(message) = self.inner.check(&req) {
+            tracing::warn!("Refused {} {}: {}", req.method(), req.path(), message);
+            let response = HttpResponse::Forbidden()
+                .content_type("application/json")
+                .json(&Response {
+                    title: "Forbidden".to_string(),
+                    message: message.to_string(),
+                });
+            return Ok(req.into_response(response));
+        }
+        ctx.call(&self.service, req).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn origins_are_parsed_from_both_headers() {
+        assert_eq!(
+            origin_of("https://QR.School.example"),
+            Some("https://qr.school.example".to_string())
+        );
+        assert_eq!(
+            origin_of("http://localhost:8080/dashboard?tab=users#top"),
+            Some("http://localhost:8080".to_string())
+        );
+        assert_eq!(origin_of("null"), None);
+        assert_eq!(origin_of("https://evil.example@qr.school.example/"), None);
+    }
+
+    #[test]
+    fn only_trusted_origins_or_the_same_host_pass() {
+        let trusted = vec!["https://qr.school.example/".to_string()];
+        assert!(allowed_origin("https://qr.school.example", &trusted, None));
+        assert!(!allowed_origin("http://qr.school.example", &trusted, None));
+        assert!(!allowed_origin(
+            "https://evil.example",
+            &trusted,
+            Some("evil.example")
+        ));
+
+        assert!(allowed_origin(
+            "http://localhost:8080",
+            &[],
+            Some("localhost:8080")
+        ));
+        assert!(!allowed_origin(
+            "http://localhost:8081",
+            &[],
+            Some("localhost:8080")
+        ));
+        assert!(!allowed_origin("http://localhost:8080", &[], None));
+    }
+
+    #[test]
+    fn routes_are_matched_whole() {
+        assert!(below("/api", "/api"));
+        assert!(below("/api/admin/users", "/api"));
+        assert!(!below("/apidocs", "/api"));
+        assert!(below("/login/totp", "/login"));
+    }
+
+    #[test]
+    fn encoded_paths_are_checked_too() {
+        let check = |method: Method, path: &str| needs_check(&method, &decoded_path(path));
+        assert!(check(Method::POST, "/api/admin/users"));
+        assert!(check(Method::POST, "/%61pi/admin/users"));
+        assert!(check(Method::DELETE, "/%6Cogout"));
+        assert!(!check(Method::POST, "/api/token"));
+        assert!(!check(Method::POST, "/%61pi/token"));
+        assert!(!check(Method::GET, "/%61pi/admin/users"));
+        assert!(!check(Method::POST, "/scan"));
+    }
+}
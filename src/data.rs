@@ -43,15 +43,24 @@ pub struct JsonState {
 /*
  * pub struct AppData {
  *   pub state: Vec<AppState>,
+ *   pub version: u64,
+ *   pub last_modified: DateTime<Utc>,
  * }
  *
  * Struct used for managing Data read and written to under the entire program.
  * Holds the data in a Vector (Dynamic Array)
+ *
+ * version and last_modified are bumped on every change through AppData::touch()
+ * and are only used for caching headers, so they never end up in the json.
  */
 
 #[derive(Clone, Serialize)]
 pub struct AppData {
     pub state: Vec<AppState>,
+    #[serde(skip)]
+    pub version: u64,
+    #[serde(skip)]
+    pub last_modified: DateTime<Utc>,
 }
 
 /*
@@ -79,6 +88,99 @@ pub struct AppState {
     pub last_time: String,
 }
 
+/*
+ * impl AppData {
+ *   pub fn touch(&mut self) {}
+ *   pub fn etag(&self, variant: &str) -> String {}
+ * }
+ *
+ * Helpers for keeping track of when the in-memory state last changed.
+ */
+
+impl AppData {
+    /*
+     * pub AppData::touch(&mut self) {}
+     *
+     * Marks the state as changed, call this after every modification of AppData.state.
+     */
+
+    pub fn touch(&mut self) {
+        self.version += 1;
+        self.last_modified = Utc::now();
+    }
+
+    /*
+     * pub AppData::etag(&self, variant: &str) -> String {}
+     *
+     * Builds a quoted ETag out of the version and last_modified,
+     * last_modified is included so tags from before a restart never match.
+     * `variant` tells different representations of the same data apart.
+     */
+
+    pub fn etag(&self, variant: &str) -> String {
+        format!(
+            "\"{:x}-{:x}-{}\"",
+            self.last_modified.timestamp_micros(),
+            self.version,
+            variant
+        )
+    }
+}
+
+/*
+ * impl From<JsonData> for AppData {}
+ *
+ * Converts the data read from JSON into the in-memory representation.
+ */
+
+impl From<JsonData> for AppData {
+    fn from(json_data: JsonData) -> Self {
+        let mut state = Vec::new();
+
+        for entry in json_data.state {
+            state.push(AppState {
+                date: entry.date.clone(),
+                last_date: entry.date,
+                counter: entry.last_count,
+                count_since_yesterday: entry.count_since_yesterday,
+                dotw: entry.dotw,
+                time: entry.last_time.clone(),
+                last_time: entry.last_time,
+            })
+        }
+
+        AppData {
+            state,
+            version: 0,
+            last_modified: Utc::now(),
+        }
+    }
+}
+
+/*
+ * impl From<&AppData> for JsonData {}
+ *
+ * Converts the in-memory representation back into something we can write to JSON.
+ */
+
+impl From<&AppData> for JsonData {
+    fn from(app_data: &AppData) -> Self {
+        let mut state = Vec::new();
+
+        for entry in &app_data.state {
+            state.push(JsonState {
+                date: entry.date.clone(),
+                last_count: entry.counter,
+                count_since_yesterday: entry.count_since_yesterday,
+                dotw: entry.dotw.clone(),
+                last_time: entry.time.clone(),
+            })
+        }
+
+        JsonData { state }
+    }
+}
+
 /*
  * impl Default for JsonState {}
  *
@@ -0,0 +1,110 @@
+use serde::{Deserialize, Serialize};
+use std::{
+    fs::{create_dir_all, read_to_string, rename, File, OpenOptions},
+    io::{BufRead, BufReader, Error, ErrorKind, Write},
+    path::Path,
+};
+
+/*
+ * pub struct ScanEvent {
+ *   pub time: String,
+ *   pub prev: String,
+ *   pub hash: String,
+ * }
+ *
+ * A single successful scan, stored one per line in state/events.ndjson.
+ * Only the time is kept, nothing about who scanned.
+ * prev and hash chain the events together, see ledger.rs. Events from before
+ * the chain existed have them empty until the ledger seals them on startup.
+ */
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ScanEvent {
+    pub time: String,
+    #[serde(default)]
+    pub prev: String,
+    #[serde(default)]
+    pub hash: String,
+}
+
+/*
+ * pub fn append_event(path: &Path, event: &ScanEvent) -> Result<(), Error> {}
+ *
+ * Appends a single event to state/events.ndjson.
+ */
+
+pub fn append_event(path: &Path, event: &ScanEvent) -> Result<(), Error> {
+    create_dir_all(path)?;
+    let mut file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path.join("events.ndjson"))?;
+    let mut line = serde_json::to_vec(event)?;
+    line.push(b'\n');
+    file.write_all(&line)
+}
+
+/*
+ * pub fn read_events(path: &Path) -> Result<Vec<ScanEvent>, Error> {}
+ *
+ * Reads every event from state/events.ndjson, a missing file means no events yet.
+ */
+
+pub fn read_events(path: &Path) -> Result<Vec<ScanEvent>, Error> {
+    let file_path = path.join("events.ndjson");
+    if !file_path.is_file() {
+        return Ok(Vec::new());
+    }
+
+    let mut events = Vec::new();
+    for (index, line) in BufReader::new(File::open(file_path)?).lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let event = serde_json::from_str(&line).map_err(|error| {
+            Error::new(
+                ErrorKind::InvalidData,
+                format!("events.ndjson line {}: {}", index + 1, error),
+            )
+        })?;
+        events.push(event);
+    }
+    Ok(events)
+}
+
+/*
+ * pub fn write_events(path: &Path, events: &[ScanEvent]) -> Result<(), Error> {}
+ *
+ * Replaces state/events.ndjson, through a temporary file so it's never half written.
+ */
+
+pub fn write_events(path: &Path, events: &[ScanEvent]) -> Result<(), Error> {
+    create_dir_all(path)?;
+    let tmp_path = path.join("events.ndjson.tmp");
+
+    let mut file = File::create(&tmp_path)?;
+    for event in events {
+        let mut line = serde_json::to_vec(event)?;
+        line.push(b'\n');
+        file.write_all(&line)?;
+    }
+    file.flush()?;
+
+    rename(tmp_path, path.join("events.ndjson"))
+}
+
+/*
+ * pub fn read_anchor(path: &Path) -> Result<String, Error> {}
+ *
+ * The hash the first event in events.ndjson points back to, as kept in events.anchor
+ * before the ledger signed it into ledger.json. Only read when there's no ledger.json yet.
+ */
+
+pub fn read_anchor(path: &Path) -> Result<String, Error> {
+    let file_path = path.join("events.anchor");
+    if !file_path.is_file() {
+        return Ok(String::new());
+    }
+    Ok(read_to_string(file_path)?.trim().to_string())
+}
@@ -0,0 +1,212 @@
+use super::data::AppState;
+use serde::Serialize;
+use std::io::{Error, ErrorKind};
+
+/*
+ * pub enum Format {
+ *   Json,
+ *   Csv,
+ *   Ndjson,
+ *   MessagePack,
+ * }
+ *
+ * The formats /api/get_data can answer in, picked through `?format=` or the Accept header.
+ */
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Format {
+    Json,
+    Csv,
+    Ndjson,
+    MessagePack,
+}
+
+/*
+ * struct Rows<'a> {
+ *   state: &'a [AppState],
+ * }
+ *
+ * Borrowed version of AppData so JSON and MessagePack keep the `{ "state": [...] }` shape.
+ */
+
+#[derive(Serialize)]
+struct Rows<'a> {
+    state: &'a [AppState],
+}
+
+/*
+ * const NDJSON_CHUNK_ROWS: usize
+ *
+ * How many days go into each chunk of a streamed NDJSON response.
+ */
+
+const NDJSON_CHUNK_ROWS: usize = 256;
+
+/*
+ * fn write_ndjson(rows: &[AppState]) -> Result<Vec<u8>, Error> {}
+ *
+ * One JSON object per line.
+ */
+
+fn write_ndjson(rows: &[AppState]) -> Result<Vec<u8>, Error> {
+    let mut body = Vec::new();
+    for row in rows {
+        serde_json::to_writer(&mut body, row)?;
+        body.push(b'\n');
+    }
+    Ok(body)
+}
+
+/*
+ * pub fn ndjson_chunks(rows: Vec<AppState>) -> impl Iterator<Item = Result<Vec<u8>, Error>> {}
+ *
+ * NDJSON serialized NDJSON_CHUNK_ROWS days at a time as the response is sent,
+ * so long ranges are streamed instead of being built up in memory first.
+ */
+
+pub fn ndjson_chunks(rows: Vec<AppState>) -> impl Iterator<Item = Result<Vec<u8>, Error>> {
+    let mut rows = rows.into_iter();
+    std::iter::from_fn(move || {
+        let chunk: Vec<AppState> = rows.by_ref().take(NDJSON_CHUNK_ROWS).collect();
+        (!chunk.is_empty()).then(|| write_ndjson(&chunk))
+    })
+}
+
+/*
+ * impl Format {
+ *   pub fn from_query(format: &str) -> Option<Self> {}
+ *   fn from_mime(mime: &str) -> Option<Self> {}
+ *   pub fn from_accept(accept: &str) -> Option<Self> {}
+ *   pub fn name(self) -> &'static str {}
+ *   pub fn content_type(self) -> &'static str {}
+ *   pub fn render(self, rows: &[AppState]) -> Result<Vec<u8>, Error> {}
+ * }
+ *
+ * Assorted implementations accessed through `Format::function(args)`
+ */
+
+impl Format {
+    /*
+     * pub Format::from_query(format: &str) -> Option<Self> {}
+     *
+     * Parses the `?format=` query parameter.
+     */
+
+    pub fn from_query(format: &str) -> Option<Self> {
+        match format.to_ascii_lowercase().as_str() {
+            "json" => Some(Format::Json),
+            "csv" => Some(Format::Csv),
+            "ndjson" | "jsonl" => Some(Format::Ndjson),
+            "msgpack" | "messagepack" => Some(Format::MessagePack),
+            _ => None,
+        }
+    }
+
+    /*
+     * Format::from_mime(mime: &str) -> Option<Self> {}
+     *
+     * Maps a single media type to a format, wildcards fall back to JSON.
+     */
+
+    fn from_mime(mime: &str) -> Option<Self> {
+        match mime.to_ascii_lowercase().as_str() {
+            "application/json" | "application/*" | "*/*" => Some(Format::Json),
+            "text/csv" | "text/*" => Some(Format::Csv),
+            "application/x-ndjson" | "application/ndjson" | "application/jsonl" => {
+                Some(Format::Ndjson)
+            }
+            "application/msgpack" | "application/x-msgpack" | "application/vnd.msgpack" => {
+                Some(Format::MessagePack)
+            }
+            _ => None,
+        }
+    }
+
+    /*
+     * pub Format::from_accept(accept: &str) -> Option<Self> {}
+     *
+     * Picks the format with the highest quality value from an Accept header,
+     * earlier entries win ties. None means nothing in the header is supported.
+     */
+
+    pub fn from_accept(accept: &str) -> Option<Self> {
+        let mut best: Option<(Self, f32)> = None;
+
+        for media_range in accept.split(',') {
+            let mut parts = media_range.split(';');
+            let mime = parts.next().unwrap_or_default().trim();
+            let quality = parts
+                .filter_map(|parameter| parameter.trim().strip_prefix("q="))
+                .find_map(|quality| quality.trim().parse::<f32>().ok())
+                .unwrap_or(1.0);
+
+            if quality <= 0.0 {
+                continue;
+            }
+            let Some(format) = Self::from_mime(mime) else {
+                continue;
+            };
+            match best {
+                Some((_, best_quality)) if best_quality >= quality => {}
+                _ => best = Some((format, quality)),
+            }
+        }
+
+        best.map(|(format, _)| format)
+    }
+
+    /*
+     * pub Format::name(self) -> &'static str {}
+     *
+     * Short name of the format, used to tell the ETags of each representation apart.
+     */
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Format::Json => "json",
+            Format::Csv => "csv",
+            Format::Ndjson => "ndjson",
+            Format::MessagePack => "msgpack",
+        }
+    }
+
+    /*
+     * pub Format::content_type(self) -> &'static str {}
+     *
+     * The Content-Type header to answer with.
+     */
+
+    pub fn content_type(self) -> &'static str {
+        match self {
+            Format::Json => "application/json",
+            Format::Csv => "text/csv; charset=utf-8",
+            Format::Ndjson => "application/x-ndjson",
+            Format::MessagePack => "application/msgpack",
+        }
+    }
+
+    /*
+     * pub Format::render(self, rows: &[AppState]) -> Result<Vec<u8>, Error> {}
+     *
+     * Serializes the rows in this format, all at once. /api/get_data streams NDJSON through ndjson_chunks instead.
+     * CSV and NDJSON have one row per day, JSON and MessagePack use the same shape as AppData.
+     */
+
+    pub fn render(self, rows: &[AppState]) -> Result<Vec<u8>, Error> {
+        match self {
+            Format::Json => Ok(serde_json::to_vec(&Rows { state: rows })?),
+            Format::Csv => {
+                let mut writer = csv::Writer::from_writer(Vec::new());
+                for row in rows {
+                    writer.serialize(row)?;
+                }
+                writer
+                    .into_inner()
+                    .map_err(|error| Error::other(error.to_string()))
+            }
+            Format::Ndjson => write_ndjson(rows),
+            Format::MessagePack => rmp_serde::to_vec_named(&Rows { state: rows })
+                .map_err(|error| Error::new(ErrorKind::InvalidData, error.to_string())),
+        }
+    }
+}
@@ -0,0 +1,186 @@
+use super::config::{HashAlgorithm, PasswordHashConfig};
+use argon2::{
+    password_hash::{rand_core::OsRng, PasswordHash, PasswordHasher, PasswordVerifier, SaltString},
+    Algorithm, Argon2, Params, Version,
+};
+use bcrypt::HashParts;
+use std::{
+    io::{Error, ErrorKind},
+    sync::OnceLock,
+};
+
+/*
+ * static POLICY: OnceLock<PasswordHashConfig>
+ *
+ * How new hashes are made, set once at startup by configure().
+ * Hashing happens deep inside account handling, so it isn't passed around.
+ */
+
+static POLICY: OnceLock<PasswordHashConfig> = OnceLock::new();
+
+/*
+ * fn policy() -> &'static PasswordHashConfig {}
+ *
+ * The configured policy, the defaults if configure() was never called.
+ */
+
+fn policy() -> &'static PasswordHashConfig {
+    POLICY.get_or_init(PasswordHashConfig::default)
+}
+
+/*
+ * fn argon2_params(config: &PasswordHashConfig) -> Result<Params, Error> {}
+ *
+ * The Argon2 parameters in `config`, an error when the argon2 crate won't take them.
+ */
+
+fn argon2_params(config: &PasswordHashConfig) -> Result<Params, Error> {
+    Params::new(
+        config.argon2_memory_kib,
+        config.argon2_iterations,
+        config.argon2_parallelism,
+        None,
+    )
+    .map_err(|error| {
+        Error::new(
+            ErrorKind::InvalidInput,
+            format!("Invalid Argon2 parameters in config.json: {}", error),
+        )
+    })
+}
+
+/*
+ * pub fn configure(config: &PasswordHashConfig) -> Result<(), Error> {}
+ *
+ * Sets the policy for the rest of the process, checking the parameters first so bad ones stop startup.
+ * Later calls are ignored.
+ */
+
+pub fn configure(config: &PasswordHashConfig) -> Result<(), Error> {
+    argon2_params(config)?;
+    if !(4..=31).contains(&config.bcrypt_cost) {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            "bcrypt_cost in config.json has to be between 4 and 31.",
+        ));
+    }
+    let _ = POLICY.set(config.clone());
+    Ok(())
+}
+
+/*
+ * pub fn hash(password: &str) -> String {}
+ *
+ * Hashes a password with the configured algorithm, into a PHC string ("$argon2id$v=19$...")
+ * or a bcrypt one ("$2b$12$..."), both carry their own parameters.
+ */
+
+pub fn hash(password: &str) -> String {
+    let config = policy();
+    match config.algorithm {
+        HashAlgorithm::Argon2id => {
+            let params = argon2_params(config).expect("Argon2 parameters are checked by configure");
+            Argon2::new(Algorithm::Argon2id, Version::V0x13, params)
+                .hash_password(password.as_bytes(), &SaltString::generate(&mut OsRng))
+                .expect("Failed to hash password")
+                .to_string()
+        }
+        HashAlgorithm::Bcrypt => {
+            bcrypt::hash(password, config.bcrypt_cost).expect("Failed to hash password")
+        }
+    }
+}
+
+/*
+ * pub fn verify(password: &str, hash: &str) -> bool {}
+ *
+ * Checks a password against a hash of either kind, using the parameters stored in the hash.
+ */
+
+pub fn verify(password: &str, hash: &str) -> bool {
+    if hash.starts_with("$argon2") {
+        return match PasswordHash::new(hash) {
+            Ok(parsed) => Argon2::default()
+                .verify_password(password.as_bytes(), &parsed)
+                .is_ok(),
+            Err(_) => false,
+        };
+    }
+    bcrypt::verify(password, hash).unwrap_or(false)
+}
+
+/*
+ * pub fn is_hash(value: &str) -> bool {}
+ *
+ * Whether a stored password field is a hash we understand rather than legacy plaintext.
+ */
+
+pub fn is_hash(value: &str) -> bool {
+    PasswordHash::new(value).is_ok_and(|parsed| parsed.algorithm == Algorithm::Argon2id.ident())
+        || value.parse::<HashParts>().is_ok()
+}
+
+/*
+ * pub fn needs_rehash(hash: &str) -> bool {}
+ *
+ * Whether a hash was made with another algorithm or other parameters than the configured ones.
+ */
+
+pub fn needs_rehash(hash: &str) -> bool {
+    let config = policy();
+    match config.algorithm {
+        HashAlgorithm::Argon2id => {
+            let Ok(parsed) = PasswordHash::new(hash) else {
+                return true;
+            };
+            let Ok(params) = Params::try_from(&parsed) else {
+                return true;
+            };
+            parsed.algorithm != Algorithm::Argon2id.ident()
+                || parsed.version != Some(Version::V0x13.into())
+                || params.m_cost() != config.argon2_memory_kib
+                || params.t_cost() != config.argon2_iterations
+                || params.p_cost() != config.argon2_parallelism
+        }
+        HashAlgorithm::Bcrypt => match hash.parse::<HashParts>() {
+            Ok(parts) => parts.get_cost() != config.bcrypt_cost,
+            Err(_) => true,
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn both_algorithms_verify() {
+        let argon2 = hash("correct horse");
+        assert!(argon2.starts_with("$argon2id$v=19$m=19456,t=2,p=1$"));
+        assert!(is_hash(&argon2));
+        assert!(verify("correct horse", &argon2));
+        assert!(!verify("wrong horse", &argon2));
+        assert!(!needs_rehash(&argon2));
+
+        let bcrypt = bcrypt::hash("correct horse", 4).unwrap();
+        assert!(is_hash(&bcrypt));
+        assert!(verify("correct horse", &bcrypt));
+        assert!(!verify("wrong horse", &bcrypt));
+        assert!(needs_rehash(&bcrypt));
+
+        assert!(!is_hash("correct horse"));
+        assert!(!verify("correct horse", "correct horse"));
+    }
+
+    #[test]
+    fn weaker_argon2_parameters_need_a_rehash() {
+        let params = Params::new(8 * 1024, 1, 1, None).unwrap();
+        let weak = Argon2::new(Algorithm::Argon2id, Version::V0x13, params)
+            .hash_password(b"correct horse", &SaltString::generate(&mut OsRng))
+            .unwrap()
+            .to_string();
+
+        assert!(verify("correct horse", &weak));
+        assert!(needs_rehash(&weak));
+    }
+}
@@ -1,10 +1,14 @@
-use super::{api::Response, creds::Login};
-use ntex::web::{get, Error as WebError, HttpRequest, HttpResponse};
+use super::{
+    api::{authorize, Access},
+    creds::{Auth, Role},
+};
+use ntex::web::{get, types::State, Error as WebError, HttpRequest, HttpResponse};
 use ntex_files::NamedFile;
 use std::{
     fs::File,
     io::Read,
     path::{Path, PathBuf},
+    sync::Arc,
 };
 
 pub async fn fourofour() -> Result<HttpResponse, WebError> {
@@ -65,35 +69,132 @@ pub async fn login() -> Result<HttpResponse, WebError> {
     return Ok(HttpResponse::Ok().content_type("text/html").body(content));
 }
 
+#[get("/reset_password")]
+pub async fn reset_password_page() -> Result<HttpResponse, WebError> {
+    let mut content = String::new();
+    let reset_path = Path::new("./html").join("reset_password.html");
+
+    let mut file = File::open(reset_path)?;
+    file.read_to_string(&mut content)?;
+    return Ok(HttpResponse::Ok().content_type("text/html").body(content));
+}
+
 #[get("/dashboard")]
-pub async fn dashboard(session: ntex_session::Session) -> Result<HttpResponse, WebError> {
-    let credentials = Login::get();
+pub async fn dashboard(
+    session: ntex_session::Session,
+    auth: State<Arc<Auth>>,
+) -> Result<HttpResponse, WebError> {
     let mut content = String::new();
     let dashboard_path = Path::new("./html").join("dashboard.html");
     let mut file = File::open(dashboard_path)?;
     file.read_to_string(&mut content)?;
 
-    if let Some(hash) = session.get::<String>("hash")? {
-        if let Some(user) = session.get::<String>("user")? {
-            if credentials.verify(hash.clone(), user) {
-                return Ok(HttpResponse::Ok().content_type("text/html").body(content));
-            }
-        }
+    match authorize(&session, &auth, Role::Viewer)? {
+        Access::Granted(_) => Ok(HttpResponse::Ok().content_type("text/html").body(content)),
+        Access::Denied(response) => Ok(response),
+    }
+}
+
+/* static_path(filename: &str) -> Option<PathBuf>
+ * Maps a requested file onto ./html, the only directory served as is, refusing empty, hidden
+ * and parent segments so nothing outside it, like state/ or admin_login.json, can be reached */
+fn static_path(filename: &str) -> Option<PathBuf> {
+    let mut segments = filename.split('/');
+    if segments.next() != Some("html") {
+        return None;
     }
 
-    Ok(HttpResponse::Unauthorized()
-        .content_type("application/json")
-        .json(&Response {
-            title: "Unauthorized".to_string(),
-            message: "Failed to verify token.".to_string(),
-        }))
+    let mut path = PathBuf::from("./html");
+    for segment in segments {
+        if segment.is_empty() || segment.starts_with('.') || segment.contains('\\') {
+            return None;
+        }
+        path.push(segment);
+    }
+    (path != Path::new("./html")).then_some(path)
 }
 
 pub async fn files(req: HttpRequest) -> Result<HttpResponse, WebError> {
-    let path: PathBuf = req.match_info().query("filename").parse()?;
-    let file = NamedFile::open(PathBuf::from("./").join(path));
+    let Some(path) = static_path(req.match_info().query("filename")) else {
+        return fourofour().await;
+    };
+    let file = NamedFile::open(path);
     if file.is_ok() {
         return Ok(file?.into_response(&req));
     }
     fourofour().await
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn serves_only_below_html() {
+        assert_eq!(
+            static_path("html/style.css"),
+            Some(PathBuf::from("./html/style.css"))
+        );
+        assert_eq!(
+            static_path("html/img/logo.png"),
+            Some(PathBuf::from("./html/img/logo.png"))
+        );
+        assert_eq!(static_path("state/keys/session.key"), None);
+        assert_eq!(static_path("admin_login.json"), None);
+        assert_eq!(static_path("Cargo.toml"), None);
+        assert_eq!(static_path(""), None);
+        assert_eq!(static_path("html"), None);
+        assert_eq!(static_path("html/"), None);
+    }
+
+    #[test]
+    fn state_files_are_not_served() {
+        for file in [
+            "api_keys.json",
+            "keys/jwt.key",
+            "audit.ndjson",
+            "sessions.json",
+            "password_resets.json",
+        ] {
+            assert_eq!(static_path(&format!("state/{file}")), None, "{file}");
+            assert_eq!(
+                static_path(&format!("html/../state/{file}")),
+                None,
+                "{file}"
+            );
+        }
+    }
+
+    #[ntex::test]
+    async fn private_files_are_not_found() {
+        use ntex::web::{self, test, App};
+
+        let app = test::init_service(App::new().route("/{filename}*", web::get().to(files))).await;
+        for uri in [
+            "/state/keys/session.key",
+            "/state/keys/jwt.key",
+            "/state/api_keys.json",
+            "/admin_login.json",
+            "/html/../state/keys/session.key",
+            "/html/%2e%2e/admin_login.json",
+        ] {
+            let response =
+                test::call_service(&app, test::TestRequest::with_uri(uri).to_request()).await;
+            assert_eq!(
+                response.status(),
+                ntex::http::StatusCode::NOT_FOUND,
+                "{uri}"
+            );
+        }
+    }
+
+    #[test]
+    fn refuses_escaping_segments() {
+        assert_eq!(static_path("html/../state/keys/session.key"), None);
+        assert_eq!(static_path("html/../admin_login.json"), None);
+        assert_eq!(static_path("html//etc/passwd"), None);
+        assert_eq!(static_path("html/./style.css"), None);
+        assert_eq!(static_path("html/.hidden"), None);
+        assert_eq!(static_path("html/..\\state\\keys\\session.key"), None);
+    }
+}
@@ -0,0 +1,390 @@
+use super::{
+    archive::{compact, parse_date, read_all},
+    data::{AppData, AppState},
+};
+use chrono::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::{collections::BTreeMap, io::Read, path::Path};
+
+/*
+ * pub struct ImportRow {
+ *   pub date: NaiveDate,
+ *   pub count: i32,
+ * }
+ *
+ * A single validated line from an imported CSV, count being the visitors on that day.
+ */
+
+#[derive(Clone, Debug)]
+pub struct ImportRow {
+    pub date: NaiveDate,
+    pub count: i32,
+}
+
+/*
+ * pub enum ConflictPolicy {
+ *   Skip,
+ *   Overwrite,
+ *   Add,
+ * }
+ *
+ * What to do when an imported day already exists in the history:
+ * keep what we have, replace it with the imported count, or add the two together.
+ */
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum ConflictPolicy {
+    #[default]
+    Skip,
+    Overwrite,
+    Add,
+}
+
+/*
+ * impl std::str::FromStr for ConflictPolicy {}
+ *
+ * Parses the policy from the command line.
+ */
+
+impl std::str::FromStr for ConflictPolicy {
+    type Err = String;
+
+    fn from_str(policy: &str) -> Result<Self, Self::Err> {
+        match policy {
+            "skip" => Ok(ConflictPolicy::Skip),
+            "overwrite" => Ok(ConflictPolicy::Overwrite),
+            "add" => Ok(ConflictPolicy::Add),
+            _ => Err(format!(
+                "Unknown conflict policy \"{}\", use skip, overwrite or add.",
+                policy
+            )),
+        }
+    }
+}
+
+/*
+ * pub struct Conflict {
+ *   pub date: String,
+ *   pub existing: i32,
+ *   pub imported: i32,
+ *   pub result: i32,
+ *   pub overflow: bool,
+ * }
+ *
+ * A day that exists both in the history and in the import, with the count it ends up with.
+ * Rows that would push the counter past what it can hold are reported with `overflow` set
+ * and left out, existing being 0 for days that weren't in the history.
+ */
+
+#[derive(Serialize, Debug)]
+pub struct Conflict {
+    pub date: String,
+    pub existing: i32,
+    pub imported: i32,
+    pub result: i32,
+    pub overflow: bool,
+}
+
+/*
+ * pub struct ImportReport {
+ *   pub policy: ConflictPolicy,
+ *   pub rows: usize,
+ *   pub added: usize,
+ *   pub conflicts: Vec<Conflict>,
+ *   pub applied: bool,
+ * }
+ *
+ * Summary of an import, returned both for previews and for applied imports.
+ */
+
+#[derive(Serialize, Debug)]
+pub struct ImportReport {
+    pub policy: ConflictPolicy,
+    pub rows: usize,
+    pub added: usize,
+    pub conflicts: Vec<Conflict>,
+    pub applied: bool,
+}
+
+/*
+ * pub fn parse_csv<R: Read>(reader: R) -> Result<Vec<ImportRow>, Vec<String>> {}
+ *
+ * Reads a CSV with a header containing `date` and `count` columns, in any order and any case.
+ * Dates have to be YYYY-MM-DD and not in the future, counts non-negative, every day may only appear once.
+ * Every problem is collected with its line number instead of stopping at the first.
+ */
+
+pub fn parse_csv<R: Read>(reader: R) -> Result<Vec<ImportRow>, Vec<String>> {
+    let mut reader = csv::ReaderBuilder::new()
+        .trim(csv::Trim::All)
+        .from_reader(reader);
+
+    let headers = reader
+        .headers()
+        .map_err(|error| vec![format!("Can't read header: {}", error)])?
+        .clone();
+    let column = |name: &str| {
+        headers
+            .iter()
+            .position(|header| header.eq_ignore_ascii_case(name))
+    };
+    let (Some(date_column), Some(count_column)) = (column("date"), column("count")) else {
+        return Err(vec![
+            "Header has to contain a `date` and a `count` column.".to_string()
+        ]);
+    };
+
+    let today = Local::now().date_naive();
+    let mut rows: Vec<ImportRow> = Vec::new();
+    let mut errors = Vec::new();
+
+    for (index, record) in reader.records().enumerate() {
+        let line = index + 2;
+        let record = match record {
+            Ok(record) => record,
+            Err(error) => {
+                errors.push(format!("Line {}: {}", line, error));
+                continue;
+            }
+        };
+
+        let date = record.get(date_column).unwrap_or_default();
+        let count = record.get(count_column).unwrap_or_default();
+
+        let Some(date) = parse_date(date) else {
+            errors.push(format!(
+                "Line {}: \"{}\" isn't a YYYY-MM-DD date.",
+                line, date
+            ));
+            continue;
+        };
+        if date > today {
+            errors.push(format!("Line {}: {} is in the future.", line, date));
+            continue;
+        }
+        let count = match count.parse::<i32>() {
+            Ok(count) if count >= 0 => count,
+            _ => {
+                errors.push(format!("Line {}: \"{}\" isn't a valid count.", line, count));
+                continue;
+            }
+        };
+
+        if rows.iter().any(|row| row.date == date) {
+            errors.push(format!("Line {}: {} appears more than once.", line, date));
+            continue;
+        }
+        rows.push(ImportRow { date, count });
+    }
+
+    if !errors.is_empty() {
+        return Err(errors);
+    }
+    rows.sort_by_key(|row| row.date);
+    Ok(rows)
+}
+
+/*
+ * pub fn merge(history: &[AppState], rows: &[ImportRow], policy: ConflictPolicy) -> (Vec<AppState>, ImportReport) {}
+ *
+ * Merges imported daily counts into the history.
+ * The history stores a running counter, so it gets turned into daily counts first,
+ * merged with the import according to `policy`, and turned back into a running counter.
+ * Days that didn't change keep their times and count_since_yesterday.
+ * Rows that would overflow the running counter are skipped and reported as conflicts.
+ */
+
+pub fn merge(
+    history: &[AppState],
+    rows: &[ImportRow],
+    policy: ConflictPolicy,
+) -> (Vec<AppState>, ImportReport) {
+    let mut days: BTreeMap<String, (i32, Option<AppState>)> = BTreeMap::new();
+    let mut previous_counter = 0;
+    for entry in history {
+        days.insert(
+            entry.date.clone(),
+            (entry.counter - previous_counter, Some(entry.clone())),
+        );
+        previous_counter = entry.counter;
+    }
+
+    // Every running counter is at most the sum of the days that added to it.
+    let mut raised: i64 = days
+        .values()
+        .map(|(daily, _)| i64::from(*daily).max(0))
+        .sum();
+    let fits = |raised: i64, before: i32, after: i32| {
+        raised - i64::from(before).max(0) + i64::from(after).max(0) <= i64::from(i32::MAX)
+    };
+
+    let mut report = ImportReport {
+        policy,
+        rows: rows.len(),
+        added: 0,
+        conflicts: Vec::new(),
+        applied: false,
+    };
+
+    for row in rows {
+        let date = row.date.to_string();
+        match days.get_mut(&date) {
+            Some((daily, entry)) => {
+                let result = match policy {
+                    ConflictPolicy::Skip => Some(*daily),
+                    ConflictPolicy::Overwrite => Some(row.count),
+                    ConflictPolicy::Add => daily.checked_add(row.count),
+                }
+                .filter(|result| fits(raised, *daily, *result));
+                report.conflicts.push(Conflict {
+                    date,
+                    existing: *daily,
+                    imported: row.count,
+                    result: result.unwrap_or(*daily),
+                    overflow: result.is_none(),
+                });
+                let Some(result) = result else {
+                    continue;
+                };
+                if result != *daily {
+                    raised += i64::from(result).max(0) - i64::from(*daily).max(0);
+                    *daily = result;
+                    if let Some(entry) = entry {
+                        entry.count_since_yesterday = result;
+                    }
+                }
+            }
+            None if !fits(raised, 0, row.count) => {
+                report.conflicts.push(Conflict {
+                    date,
+                    existing: 0,
+                    imported: row.count,
+                    result: 0,
+                    overflow: true,
+                });
+            }
+            None => {
+                raised += i64::from(row.count);
+                report.added += 1;
+                days.insert(date, (row.count, None));
+            }
+        }
+    }
+
+    let mut merged = Vec::new();
+    let mut counter = 0;
+    let mut last_date = String::new();
+    for (date, (daily, entry)) in days {
+        counter += daily;
+        let entry = match entry {
+            Some(mut entry) => {
+                entry.counter = counter;
+                entry
+            }
+            None => AppState {
+                last_date: last_date.clone(),
+                dotw: parse_date(&date)
+                    .map(|date| date.weekday().to_string())
+                    .unwrap_or_default(),
+                date: date.clone(),
+                counter,
+                count_since_yesterday: daily,
+                time: "00:00:00".to_string(),
+                last_time: "00:00:00".to_string(),
+            },
+        };
+        last_date = date;
+        merged.push(entry);
+    }
+
+    (merged, report)
+}
+
+/*
+ * pub fn import(path: &Path, app_data: &mut AppData, rows: &[ImportRow], policy: ConflictPolicy, archive_after_days: i64, apply: bool) -> Result<ImportReport, std::io::Error> {}
+ *
+ * Runs `merge` against the full history, archived years included.
+ * When `apply` is set the result replaces the in-memory state and gets archived again,
+ * the caller is responsible for writing data.json afterwards.
+ */
+
+pub fn import(
+    path: &Path,
+    app_data: &mut AppData,
+    rows: &[ImportRow],
+    policy: ConflictPolicy,
+    archive_after_days: i64,
+    apply: bool,
+) -> Result<ImportReport, std::io::Error> {
+    let history = read_all(path, &app_data.state)?;
+    let (merged, mut report) = merge(&history, rows, policy);
+
+    if apply {
+        app_data.state = merged;
+        app_data.touch();
+        compact(path, app_data, archive_after_days)?;
+        report.applied = true;
+    }
+
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn day(date: &str, counter: i32) -> AppState {
+        AppState {
+            last_date: date.to_string(),
+            date: date.to_string(),
+            dotw: String::new(),
+            counter,
+            count_since_yesterday: 0,
+            time: "12:00:00".to_string(),
+            last_time: "12:00:00".to_string(),
+        }
+    }
+
+    fn row(date: &str, count: i32) -> ImportRow {
+        ImportRow {
+            date: parse_date(date).unwrap(),
+            count,
+        }
+    }
+
+    #[test]
+    fn counts_are_merged_into_the_running_counter() {
+        let history = vec![day("2024-01-01", 5), day("2024-01-03", 8)];
+        let rows = vec![row("2024-01-02", 2), row("2024-01-03", 4)];
+
+        let (merged, report) = merge(&history, &rows, ConflictPolicy::Add);
+        let counters: Vec<i32> = merged.iter().map(|entry| entry.counter).collect();
+        assert_eq!(counters, vec![5, 7, 14]);
+        assert_eq!(report.added, 1);
+        assert_eq!(report.conflicts[0].result, 7);
+        assert!(!report.conflicts[0].overflow);
+    }
+
+    #[test]
+    fn rows_that_overflow_the_counter_are_left_out() {
+        let history = vec![day("2024-01-01", i32::MAX - 10)];
+        let rows = vec![
+            row("2024-01-01", 20),
+            row("2024-01-02", 5),
+            row("2024-01-03", 20),
+        ];
+
+        let (merged, report) = merge(&history, &rows, ConflictPolicy::Add);
+        let counters: Vec<i32> = merged.iter().map(|entry| entry.counter).collect();
+        assert_eq!(counters, vec![i32::MAX - 10, i32::MAX - 5]);
+        assert_eq!(report.added, 1);
+        let overflowed: Vec<&str> = report
+            .conflicts
+            .iter()
+            .filter(|conflict| conflict.overflow)
+            .map(|conflict| conflict.date.as_str())
+            .collect();
+        assert_eq!(overflowed, vec!["2024-01-01", "2024-01-03"]);
+    }
+}
@@ -0,0 +1,76 @@
+use rand::RngCore;
+use std::{
+    fs::{create_dir_all, read_to_string, OpenOptions},
+    io::{Error, ErrorKind, Write},
+    path::Path,
+};
+
+/*
+ * pub fn write_key(path: &Path, name: &str, key: &[u8]) -> Result<(), Error> {}
+ *
+ * Writes a hex encoded key to state/keys/<name>.key, only readable by the owner on unix.
+ */
+
+pub fn write_key(path: &Path, name: &str, key: &[u8]) -> Result<(), Error> {
+    let dir = path.join("keys");
+    create_dir_all(&dir)?;
+
+    let mut options = OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+
+    let mut file = options.open(dir.join(format!("{}.key", name)))?;
+    file.write_all(hex::encode(key).as_bytes())
+}
+
+/*
+ * pub fn read_key(path: &Path, name: &str) -> Result<Option<Vec<u8>>, Error> {}
+ *
+ * Reads state/keys/<name>.key, None if it doesn't exist.
+ */
+
+pub fn read_key(path: &Path, name: &str) -> Result<Option<Vec<u8>>, Error> {
+    let file_path = path.join("keys").join(format!("{}.key", name));
+    if !file_path.is_file() {
+        return Ok(None);
+    }
+    let key = hex::decode(read_to_string(&file_path)?.trim()).map_err(|_| {
+        Error::new(
+            ErrorKind::InvalidData,
+            format!("{} isn't a hex encoded key.", file_path.display()),
+        )
+    })?;
+    Ok(Some(key))
+}
+
+/*
+ * pub fn generate_key() -> Vec<u8> {}
+ *
+ * 64 random bytes from the OS backed thread rng.
+ */
+
+pub fn generate_key() -> Vec<u8> {
+    let mut key = vec![0; 64];
+    rand::thread_rng().fill_bytes(&mut key);
+    key
+}
+
+/*
+ * pub fn load_or_create_key(path: &Path, name: &str) -> Result<Vec<u8>, Error> {}
+ *
+ * Returns the key stored as state/keys/<name>.key, generating and storing one on first use.
+ */
+
+pub fn load_or_create_key(path: &Path, name: &str) -> Result<Vec<u8>, Error> {
+    if let Some(key) = read_key(path, name)? {
+        return Ok(key);
+    }
+    let key = generate_key();
+    write_key(path, name, &key)?;
+    tracing::info!("Generated a new {} key.", name);
+    Ok(key)
+}
@@ -0,0 +1,780 @@
+use super::{
+    archive::{parse_date, read_all},
+    data::AppState,
+    events::{append_event, read_anchor, read_events, write_events, ScanEvent},
+    keys::load_or_create_key,
+};
+use chrono::prelude::*;
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Serialize};
+use serde_json::{from_reader, to_writer};
+use sha2::Sha256;
+use std::{
+    collections::BTreeMap,
+    fs::{create_dir_all, rename, File, OpenOptions},
+    io::{BufRead, BufReader, Error, ErrorKind, Write},
+    ops::Bound::{Excluded, Included, Unbounded},
+    path::Path,
+};
+
+/*
+ * pub struct Correction {
+ *   pub time: String,
+ *   pub user: String,
+ *   pub date: String,
+ *   pub delta: i32,
+ *   pub reason: String,
+ *   pub prev: String,
+ *   pub hash: String,
+ * }
+ *
+ * An admin edit of a day's count, stored one per line in state/corrections.ndjson.
+ * Corrections are applied on top of data.json when serving the data instead of rewriting it,
+ * and are chained and signed the same way scan events are.
+ */
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Correction {
+    pub time: String,
+    pub user: String,
+    pub date: String,
+    pub delta: i32,
+    pub reason: String,
+    pub prev: String,
+    pub hash: String,
+}
+
+/*
+ * pub struct BrokenLink {
+ *   pub store: String,
+ *   pub entry: usize,
+ *   pub reason: String,
+ * }
+ *
+ * The first place verification found the chain to be broken.
+ */
+
+#[derive(Serialize, Debug)]
+pub struct BrokenLink {
+    pub store: String,
+    pub entry: usize,
+    pub reason: String,
+}
+
+/*
+ * pub struct DayMismatch {
+ *   pub date: String,
+ *   pub stored: i64,
+ *   pub events: i64,
+ * }
+ *
+ * A day whose count in data.json differs from the number of scan events recorded for it.
+ */
+
+#[derive(Serialize, Debug)]
+pub struct DayMismatch {
+    pub date: String,
+    pub stored: i64,
+    pub events: i64,
+}
+
+/*
+ * pub struct Verification {
+ *   pub events: usize,
+ *   pub corrections: usize,
+ *   pub valid: bool,
+ *   pub first_broken: Option<BrokenLink>,
+ *   pub mismatched_days: Vec<DayMismatch>,
+ * }
+ *
+ * Result of walking both chains and comparing the daily counts against the events.
+ */
+
+#[derive(Serialize, Debug)]
+pub struct Verification {
+    pub events: usize,
+    pub corrections: usize,
+    pub valid: bool,
+    pub first_broken: Option<BrokenLink>,
+    pub mismatched_days: Vec<DayMismatch>,
+}
+
+/*
+ * struct ChainHead {
+ *   anchor: String,
+ *   events: usize,
+ *   last_event: String,
+ *   corrections: usize,
+ *   last_correction: String,
+ *   signature: String,
+ * }
+ *
+ * Signed summary of both chains, stored in state/ledger.json and rewritten with every new entry.
+ * The anchor is the hash the first event points back to, so events can't be cut off the start,
+ * and the counts and last hashes mean they can't be cut off the end either.
+ */
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+struct ChainHead {
+    anchor: String,
+    events: usize,
+    last_event: String,
+    corrections: usize,
+    last_correction: String,
+    signature: String,
+}
+
+/*
+ * fn read_head(path: &Path) -> Result<Option<ChainHead>, Error> {}
+ *
+ * Reads state/ledger.json, None before the ledger has been started.
+ */
+
+fn read_head(path: &Path) -> Result<Option<ChainHead>, Error> {
+    let file_path = path.join("ledger.json");
+    if !file_path.is_file() {
+        return Ok(None);
+    }
+    Ok(Some(from_reader(File::open(file_path)?)?))
+}
+
+/*
+ * fn daily_totals(events: &[ScanEvent]) -> BTreeMap<NaiveDate, i64> {}
+ *
+ * How many scan events there are on each day, in the time zone they were recorded in.
+ */
+
+fn daily_totals(events: &[ScanEvent]) -> BTreeMap<NaiveDate, i64> {
+    let mut totals = BTreeMap::new();
+    for event in events {
+        if let Ok(time) = DateTime::parse_from_rfc3339(&event.time) {
+            *totals.entry(time.date_naive()).or_insert(0) += 1;
+        }
+    }
+    totals
+}
+
+/*
+ * pub struct Ledger {
+ *   key: Vec<u8>,
+ *   anchor: String,
+ *   events: usize,
+ *   last_event: String,
+ *   pub corrections: Vec<Correction>,
+ * }
+ *
+ * Keeps the scan events and corrections tamper-evident.
+ * Every entry stores the hash of the one before it and its own HMAC-SHA256 over both,
+ * keyed with state/keys/ledger.key, so editing, removing or reordering entries
+ * breaks the chain unless whoever did it also has the key. Both ends of the chains
+ * are pinned by the signed ChainHead.
+ */
+
+pub struct Ledger {
+    key: Vec<u8>,
+    anchor: String,
+    events: usize,
+    last_event: String,
+    pub corrections: Vec<Correction>,
+}
+
+/*
+ * fn read_corrections(path: &Path) -> Result<Vec<Correction>, Error> {}
+ *
+ * Reads every correction from state/corrections.ndjson, a missing file means none yet.
+ */
+
+fn read_corrections(path: &Path) -> Result<Vec<Correction>, Error> {
+    let file_path = path.join("corrections.ndjson");
+    if !file_path.is_file() {
+        return Ok(Vec::new());
+    }
+
+    let mut corrections = Vec::new();
+    for (index, line) in BufReader::new(File::open(file_path)?).lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let correction = serde_json::from_str(&line).map_err(|error| {
+            Error::new(
+                ErrorKind::InvalidData,
+                format!("corrections.ndjson line {}: {}", index + 1, error),
+            )
+        })?;
+        corrections.push(correction);
+    }
+    Ok(corrections)
+}
+
+/*
+ * impl Ledger {
+ *   pub fn load(path: &Path) -> Result<Self, Error> {}
+ *   fn sign(&self, prev: &str, fields: &[&str]) -> String {}
+ *   fn sign_head(&self, head: &ChainHead) -> String {}
+ *   fn write_head(&self, path: &Path) -> Result<(), Error> {}
+ *   pub fn record_scan(&mut self, path: &Path, time: String) -> Result<(), Error> {}
+ *   pub fn cut_events(&mut self, path: &Path, events: &[ScanEvent], expired: usize) -> Result<(), Error> {}
+ *   pub fn record_correction(&mut self, path: &Path, user: String, date: String, delta: i32, reason: String) -> Result<Correction, Error> {}
+ *   fn check_head(&self, head: Option<&ChainHead>, events: &[ScanEvent], corrections: &[Correction]) -> Option<BrokenLink> {}
+ *   pub fn verify(&self, path: &Path, hot: &[AppState]) -> Result<Verification, Error> {}
+ * }
+ *
+ * Assorted implementations accessed through `Ledger::function(args)`
+ */
+
+impl Ledger {
+    /*
+     * pub Ledger::load(path: &Path) -> Result<Self, Error> {}
+     *
+     * Loads the key and the tip of both chains.
+     * Events written before the chain existed are sealed once, in their current order,
+     * but only when none of them are sealed yet and there's no ledger.json. Anything else means
+     * the file was tampered with, the events are left alone so verify reports the chain as broken.
+     * The first start signs a ChainHead for whatever is on disk at that point.
+     */
+
+    pub fn load(path: &Path) -> Result<Self, Error> {
+        let head = read_head(path)?;
+        let anchor = match &head {
+            Some(head) => head.anchor.clone(),
+            None => read_anchor(path)?,
+        };
+        let mut ledger = Ledger {
+            key: load_or_create_key(path, "ledger")?,
+            last_event: anchor.clone(),
+            anchor,
+            events: 0,
+            corrections: read_corrections(path)?,
+        };
+
+        let mut events = read_events(path)?;
+        let unsealed = events.iter().filter(|event| event.hash.is_empty()).count();
+        if unsealed > 0 && (head.is_some() || unsealed < events.len()) {
+            tracing::error!(
+                "events.ndjson has {} unsealed events in a chain that was already started, refusing to seal them.",
+                unsealed
+            );
+        } else if unsealed > 0 {
+            let mut prev = ledger.anchor.clone();
+            for event in &mut events {
+                event.prev = prev;
+                event.hash = ledger.sign(&event.prev, &[&event.time]);
+                prev = event.hash.clone();
+            }
+            write_events(path, &events)?;
+            tracing::warn!(
+                "Sealed {} scan events written before the ledger existed.",
+                events.len()
+            );
+        }
+        if let Some(event) = events.last() {
+            ledger.last_event = event.hash.clone();
+        }
+        ledger.events = events.len();
+
+        if head.is_none() {
+            ledger.write_head(path)?;
+            tracing::info!("Signed the head of the ledger.");
+        }
+        Ok(ledger)
+    }
+
+    /*
+     * Ledger::sign(&self, prev: &str, fields: &[&str]) -> String {}
+     *
+     * Hex encoded HMAC-SHA256 over the previous hash and the entry's fields, separated by newlines.
+     */
+
+    fn sign(&self, prev: &str, fields: &[&str]) -> String {
+        let mut mac = Hmac::<Sha256>::new_from_slice(&self.key).expect("HMAC takes any key size");
+        mac.update(prev.as_bytes());
+        for field in fields {
+            mac.update(b"\n");
+            mac.update(field.as_bytes());
+        }
+        hex::encode(mac.finalize().into_bytes())
+    }
+
+    /*
+     * Ledger::sign_head(&self, head: &ChainHead) -> String {}
+     *
+     * The signature of a ChainHead. "head" takes the place of the previous hash,
+     * which is never hex, so a head's signature can't pass for an entry's.
+     */
+
+    fn sign_head(&self, head: &ChainHead) -> String {
+        self.sign(
+            "head",
+            &[
+                &head.anchor,
+                &head.events.to_string(),
+                &head.last_event,
+                &head.corrections.to_string(),
+                &head.last_correction,
+            ],
+        )
+    }
+
+    /*
+     * Ledger::write_head(&self, path: &Path) -> Result<(), Error> {}
+     *
+     * Signs the current ends of both chains into state/ledger.json, through a temporary file.
+     */
+
+    fn write_head(&self, path: &Path) -> Result<(), Error> {
+        let mut head = ChainHead {
+            anchor: self.anchor.clone(),
+            events: self.events,
+            last_event: self.last_event.clone(),
+            corrections: self.corrections.len(),
+            last_correction: self
+                .corrections
+                .last()
+                .map(|correction| correction.hash.clone())
+                .unwrap_or_default(),
+            signature: String::new(),
+        };
+        head.signature = self.sign_head(&head);
+
+        create_dir_all(path)?;
+        let tmp_path = path.join("ledger.json.tmp");
+        let file = File::create(&tmp_path)?;
+        to_writer(&file, &head)?;
+        file.sync_all()?;
+        rename(tmp_path, path.join("ledger.json"))
+    }
+
+    /*
+     * pub Ledger::record_scan(&mut self, path: &Path, time: String) -> Result<(), Error> {}
+     *
+     * Seals a scan onto the end of the event chain and appends it to events.ndjson.
+     */
+
+    pub fn record_scan(&mut self, path: &Path, time: String) -> Result<(), Error> {
+        let hash = self.sign(&self.last_event, &[&time]);
+        let event = ScanEvent {
+            time,
+            prev: self.last_event.clone(),
+            hash: hash.clone(),
+        };
+        append_event(path, &event)?;
+        self.last_event = hash;
+        self.events += 1;
+        self.write_head(path)
+    }
+
+    /*
+     * pub Ledger::cut_events(&mut self, path: &Path, events: &[ScanEvent], expired: usize) -> Result<(), Error> {}
+     *
+     * Deletes the first `expired` of `events` from events.ndjson for the retention policy.
+     * Events are only ever cut from the start so the rest of the chain stays verifiable,
+     * the signed anchor moves to the hash the new first event points back to.
+     */
+
+    pub fn cut_events(
+        &mut self,
+        path: &Path,
+        events: &[ScanEvent],
+        expired: usize,
+    ) -> Result<(), Error> {
+        if expired == 0 {
+            return Ok(());
+        }
+        let kept = &events[expired..];
+        write_events(path, kept)?;
+
+        self.anchor = events[expired - 1].hash.clone();
+        self.events = kept.len();
+        self.last_event = kept
+            .last()
+            .map_or_else(|| self.anchor.clone(), |event| event.hash.clone());
+        self.write_head(path)
+    }
+
+    /*
+     * pub Ledger::record_correction(&mut self, path: &Path, user: String, date: String, delta: i32, reason: String) -> Result<Correction, Error> {}
+     *
+     * Seals a correction onto the end of the correction chain and appends it to corrections.ndjson.
+     */
+
+    pub fn record_correction(
+        &mut self,
+        path: &Path,
+        user: String,
+        date: String,
+        delta: i32,
+        reason: String,
+    ) -> Result<Correction, Error> {
+        let time = Utc::now().to_rfc3339();
+        let prev = self
+            .corrections
+            .last()
+            .map(|correction| correction.hash.clone())
+            .unwrap_or_default();
+        let hash = self.sign(&prev, &[&time, &user, &date, &delta.to_string(), &reason]);
+        let correction = Correction {
+            time,
+            user,
+            date,
+            delta,
+            reason,
+            prev,
+            hash,
+        };
+
+        create_dir_all(path)?;
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path.join("corrections.ndjson"))?;
+        let mut line = serde_json::to_vec(&correction)?;
+        line.push(b'\n');
+        file.write_all(&line)?;
+
+        self.corrections.push(correction.clone());
+        self.write_head(path)?;
+        Ok(correction)
+    }
+
+    /*
+     * Ledger::check_head(&self, head: Option<&ChainHead>, events: &[ScanEvent], corrections: &[Correction]) -> Option<BrokenLink> {}
+     *
+     * Checks ledger.json's signature and that both chains still reach as far as it says.
+     * Entries past the signed ends are fine, they're appended before the head is rewritten.
+     */
+
+    fn check_head(
+        &self,
+        head: Option<&ChainHead>,
+        events: &[ScanEvent],
+        corrections: &[Correction],
+    ) -> Option<BrokenLink> {
+        let broken = |store: &str, entry: usize, reason: &str| {
+            Some(BrokenLink {
+                store: store.to_string(),
+                entry,
+                reason: reason.to_string(),
+            })
+        };
+
+        let Some(head) = head else {
+            return broken("ledger.json", 0, "is missing");
+        };
+        if head.signature != self.sign_head(head) {
+            return broken("ledger.json", 0, "signature doesn't match its contents");
+        }
+
+        if events.len() < head.events {
+            return broken(
+                "events.ndjson",
+                events.len(),
+                "events are missing from the end of the chain",
+            );
+        }
+        if head.events > 0 && events[head.events - 1].hash != head.last_event {
+            return broken(
+                "events.ndjson",
+                head.events - 1,
+                "doesn't match the signed end of the chain",
+            );
+        }
+
+        if corrections.len() < head.corrections {
+            return broken(
+                "corrections.ndjson",
+                corrections.len(),
+                "corrections are missing from the end of the chain",
+            );
+        }
+        if head.corrections > 0 && corrections[head.corrections - 1].hash != head.last_correction {
+            return broken(
+                "corrections.ndjson",
+                head.corrections - 1,
+                "doesn't match the signed end of the chain",
+            );
+        }
+        None
+    }
+
+    /*
+     * pub Ledger::verify(&self, path: &Path, hot: &[AppState]) -> Result<Verification, Error> {}
+     *
+     * Walks both chains as they are on disk and reports the first broken link,
+     * either an entry not pointing back to the one before it, a signature that doesn't match
+     * or a chain that doesn't reach the ends signed in ledger.json.
+     * Then recounts every day from the events and compares it with the stored history,
+     * `hot` being the in-memory state. Only days after the first event's day are compared,
+     * earlier ones may have been counted before events were recorded. The stored counts
+     * are compared without corrections, those are chained on their own.
+     */
+
+    pub fn verify(&self, path: &Path, hot: &[AppState]) -> Result<Verification, Error> {
+        let events = read_events(path)?;
+        let corrections = read_corrections(path)?;
+        let head = read_head(path)?;
+
+        let mut first_broken = None;
+
+        let mut prev = head
+            .as_ref()
+            .map_or_else(|| self.anchor.clone(), |head| head.anchor.clone());
+        for (index, event) in events.iter().enumerate() {
+            let reason = if event.hash.is_empty() {
+                Some("was never sealed")
+            } else if event.prev != prev {
+                Some("doesn't point back to the previous event")
+            } else if event.hash != self.sign(&event.prev, &[&event.time]) {
+                Some("signature doesn't match its contents")
+            } else {
+                None
+            };
+            if let Some(reason) = reason {
+                first_broken = Some(BrokenLink {
+                    store: "events.ndjson".to_string(),
+                    entry: index,
+                    reason: reason.to_string(),
+                });
+                break;
+            }
+            prev = event.hash.clone();
+        }
+
+        if first_broken.is_none() {
+            let mut prev = String::new();
+            for (index, correction) in corrections.iter().enumerate() {
+                let signature = self.sign(
+                    &correction.prev,
+                    &[
+                        &correction.time,
+                        &correction.user,
+                        &correction.date,
+                        &correction.delta.to_string(),
+                        &correction.reason,
+                    ],
+                );
+                let reason = if correction.prev != prev {
+                    Some("doesn't point back to the previous correction")
+                } else if correction.hash != signature {
+                    Some("signature doesn't match its contents")
+                } else {
+                    None
+                };
+                if let Some(reason) = reason {
+                    first_broken = Some(BrokenLink {
+                        store: "corrections.ndjson".to_string(),
+                        entry: index,
+                        reason: reason.to_string(),
+                    });
+                    break;
+                }
+                prev = correction.hash.clone();
+            }
+        }
+
+        if first_broken.is_none() {
+            first_broken = self.check_head(head.as_ref(), &events, &corrections);
+        }
+
+        let totals = daily_totals(&events);
+        let history = read_all(path, hot)?;
+        let mut mismatched_days = Vec::new();
+        if let Some(&first_day) = totals.keys().next() {
+            for pair in history.windows(2) {
+                let (Some(previous), Some(date)) =
+                    (parse_date(&pair[0].date), parse_date(&pair[1].date))
+                else {
+                    continue;
+                };
+                if previous < first_day {
+                    continue;
+                }
+                let counted: i64 = totals
+                    .range((Excluded(previous), Included(date)))
+                    .map(|(_, count)| count)
+                    .sum();
+                let stored = i64::from(pair[1].counter) - i64::from(pair[0].counter);
+                if stored != counted {
+                    mismatched_days.push(DayMismatch {
+                        date: pair[1].date.clone(),
+                        stored,
+                        events: counted,
+                    });
+                }
+            }
+
+            let last_day = history.last().and_then(|entry| parse_date(&entry.date));
+            let after = last_day.map_or(Unbounded, Excluded);
+            for (date, count) in totals.range((after, Unbounded)) {
+                mismatched_days.push(DayMismatch {
+                    date: date.to_string(),
+                    stored: 0,
+                    events: *count,
+                });
+            }
+        }
+
+        Ok(Verification {
+            events: events.len(),
+            corrections: corrections.len(),
+            valid: first_broken.is_none() && mismatched_days.is_empty(),
+            first_broken,
+            mismatched_days,
+        })
+    }
+}
+
+/*
+ * pub fn apply_corrections(rows: &mut [AppState], corrections: &[Correction]) {}
+ *
+ * Applies corrections to a list of days sorted by date.
+ * The counter is cumulative, so a correction on a day also shifts the counter of every day after it.
+ */
+
+pub fn apply_corrections(rows: &mut [AppState], corrections: &[Correction]) {
+    for correction in corrections {
+        let Some(corrected) = parse_date(&correction.date) else {
+            continue;
+        };
+        for row in rows.iter_mut() {
+            match parse_date(&row.date) {
+                Some(date) if date == corrected => {
+                    row.counter += correction.delta;
+                    row.count_since_yesterday += correction.delta;
+                }
+                Some(date) if date > corrected => row.counter += correction.delta,
+                _ => {}
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn state_dir() -> std::path::PathBuf {
+        let path = std::env::temp_dir().join(format!(
+            "ledger-test-{}-{}",
+            std::process::id(),
+            rand::random::<u32>()
+        ));
+        create_dir_all(&path).unwrap();
+        path
+    }
+
+    fn day(date: &str, counter: i32) -> AppState {
+        AppState {
+            last_date: date.to_string(),
+            date: date.to_string(),
+            dotw: String::new(),
+            counter,
+            count_since_yesterday: 0,
+            time: "12:00:00".to_string(),
+            last_time: "12:00:00".to_string(),
+        }
+    }
+
+    fn scans(ledger: &mut Ledger, path: &Path, times: &[&str]) {
+        for time in times {
+            ledger.record_scan(path, time.to_string()).unwrap();
+        }
+    }
+
+    #[test]
+    fn cutting_either_end_breaks_the_chain() {
+        let path = state_dir();
+        let mut ledger = Ledger::load(&path).unwrap();
+        scans(
+            &mut ledger,
+            &path,
+            &[
+                "2024-01-01T10:00:00+00:00",
+                "2024-01-02T10:00:00+00:00",
+                "2024-01-02T11:00:00+00:00",
+            ],
+        );
+        let hot = vec![day("2024-01-01", 1), day("2024-01-02", 3)];
+        assert!(ledger.verify(&path, &hot).unwrap().valid);
+
+        let events = read_events(&path).unwrap();
+        write_events(&path, &events[..2]).unwrap();
+        let verification = ledger.verify(&path, &hot).unwrap();
+        assert_eq!(verification.first_broken.unwrap().entry, 2);
+
+        write_events(&path, &events[1..]).unwrap();
+        let verification = ledger.verify(&path, &hot).unwrap();
+        assert_eq!(verification.first_broken.unwrap().entry, 0);
+
+        write_events(&path, &events).unwrap();
+        ledger.cut_events(&path, &events, 1).unwrap();
+        assert!(ledger.verify(&path, &hot).unwrap().valid);
+        assert_eq!(Ledger::load(&path).unwrap().events, 2);
+
+        std::fs::remove_dir_all(path).unwrap();
+    }
+
+    #[test]
+    fn stored_counts_are_checked_against_the_events() {
+        let path = state_dir();
+        let mut ledger = Ledger::load(&path).unwrap();
+        scans(
+            &mut ledger,
+            &path,
+            &[
+                "2024-01-01T10:00:00+00:00",
+                "2024-01-02T10:00:00+00:00",
+                "2024-01-03T10:00:00+00:00",
+            ],
+        );
+
+        let hot = vec![
+            day("2023-12-31", 40),
+            day("2024-01-01", 41),
+            day("2024-01-02", 45),
+            day("2024-01-03", 46),
+        ];
+        let verification = ledger.verify(&path, &hot).unwrap();
+        assert!(verification.first_broken.is_none());
+        assert!(!verification.valid);
+        let dates: Vec<&str> = verification
+            .mismatched_days
+            .iter()
+            .map(|mismatch| mismatch.date.as_str())
+            .collect();
+        assert_eq!(dates, vec!["2024-01-02"]);
+        assert_eq!(verification.mismatched_days[0].stored, 4);
+        assert_eq!(verification.mismatched_days[0].events, 1);
+
+        std::fs::remove_dir_all(path).unwrap();
+    }
+
+    #[test]
+    fn events_are_only_sealed_before_the_chain_is_started() {
+        let path = state_dir();
+        let legacy = |time: &str| ScanEvent {
+            time: time.to_string(),
+            prev: String::new(),
+            hash: String::new(),
+        };
+        write_events(&path, &[legacy("2024-01-01T10:00:00+00:00")]).unwrap();
+        let mut ledger = Ledger::load(&path).unwrap();
+        assert!(!read_events(&path).unwrap()[0].hash.is_empty());
+        scans(&mut ledger, &path, &["2024-01-02T10:00:00+00:00"]);
+
+        let mut events = read_events(&path).unwrap();
+        for event in &mut events {
+            event.hash.clear();
+        }
+        write_events(&path, &events).unwrap();
+        let ledger = Ledger::load(&path).unwrap();
+        assert!(read_events(&path).unwrap()[0].hash.is_empty());
+        let verification = ledger.verify(&path, &[]).unwrap();
+        assert_eq!(
+            verification.first_broken.unwrap().reason,
+            "was never sealed"
+        );
+
+        std::fs::remove_dir_all(path).unwrap();
+    }
+}
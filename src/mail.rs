@@ -0,0 +1,227 @@
+use super::config::{SmtpConfig, SmtpSecurity};
+use lettre::{
+    message::{header::ContentType, Mailbox},
+    transport::smtp::authentication::Credentials,
+    AsyncSmtpTransport, AsyncTransport, Message, Tokio1Executor,
+};
+use std::io::{Error, ErrorKind};
+
+/*
+ * pub struct Mailer {
+ *   config: Option<SmtpConfig>,
+ *   from: Option<Mailbox>,
+ *   transport: Option<AsyncSmtpTransport<Tokio1Executor>>,
+ * }
+ *
+ * Sends password reset links through the SMTP server in config.smtp.
+ * Everything is None when that isn't configured, and resets are turned off.
+ */
+
+pub struct Mailer {
+    config: Option<SmtpConfig>,
+    from: Option<Mailbox>,
+    transport: Option<AsyncSmtpTransport<Tokio1Executor>>,
+}
+
+/*
+ * fn invalid(message: String) -> Error {}
+ *
+ * The error for an smtp section that can't work.
+ */
+
+fn invalid(message: String) -> Error {
+    Error::new(ErrorKind::InvalidInput, message)
+}
+
+/*
+ * impl Mailer {
+ *   pub fn new(config: Option<SmtpConfig>) -> Result<Self, Error> {}
+ *   pub fn enabled(&self) -> bool {}
+ *   pub fn reset_token_minutes(&self) -> i64 {}
+ *   fn reset_link(&self, token: &str) -> String {}
+ *   fn reset_message(&self, to: &str, username: &str, token: &str) -> Result<Message, Error> {}
+ *   pub async fn send_reset(&self, to: &str, username: &str, token: &str) -> Result<(), Error> {}
+ * }
+ *
+ * Assorted implementations accessed through `Mailer::function(args)`
+ */
+
+impl Mailer {
+    /*
+     * pub Mailer::new(config: Option<SmtpConfig>) -> Result<Self, Error> {}
+     *
+     * Sets up the connection pool for `config`, QRCODE_SMTP_PASSWORD takes precedence over the password in the file.
+     * Nothing connects until the first mail is sent, but a section that can't work stops startup.
+     * Has to be called inside the runtime, the connection pool is spawned on it.
+     */
+
+    pub fn new(config: Option<SmtpConfig>) -> Result<Self, Error> {
+        let Some(config) = config else {
+            return Ok(Mailer {
+                config: None,
+                from: None,
+                transport: None,
+            });
+        };
+
+        if config.host.is_empty() || config.reset_url.is_empty() {
+            return Err(invalid(
+                "smtp in config.json needs a host and a reset_url.".to_string(),
+            ));
+        }
+        if config.reset_token_minutes <= 0 {
+            return Err(invalid(
+                "smtp.reset_token_minutes in config.json has to be positive.".to_string(),
+            ));
+        }
+        let from: Mailbox = config.from.parse().map_err(|error| {
+            invalid(format!(
+                "smtp.from in config.json isn't an address: {}",
+                error
+            ))
+        })?;
+
+        let mut builder = match config.security {
+            SmtpSecurity::None => {
+                AsyncSmtpTransport::<Tokio1Executor>::builder_dangerous(&config.host)
+            }
+            SmtpSecurity::StartTls => {
+                AsyncSmtpTransport::<Tokio1Executor>::starttls_relay(&config.host)
+                    .map_err(Error::other)?
+            }
+            SmtpSecurity::Tls => {
+                AsyncSmtpTransport::<Tokio1Executor>::relay(&config.host).map_err(Error::other)?
+            }
+        }
+        .port(config.port);
+
+        let password = std::env::var("QRCODE_SMTP_PASSWORD")
+            .ok()
+            .or_else(|| config.password.clone());
+        if let (Some(username), Some(password)) = (&config.username, password) {
+            builder = builder.credentials(Credentials::new(username.clone(), password));
+        }
+
+        Ok(Mailer {
+            transport: Some(builder.build()),
+            from: Some(from),
+            config: Some(config),
+        })
+    }
+
+    /*
+     * pub Mailer::enabled(&self) -> bool {}
+     *
+     * Whether there is a mail server to send resets through.
+     */
+
+    pub fn enabled(&self) -> bool {
+        self.transport.is_some()
+    }
+
+    /*
+     * pub Mailer::reset_token_minutes(&self) -> i64 {}
+     *
+     * How long a reset link works for.
+     */
+
+    pub fn reset_token_minutes(&self) -> i64 {
+        self.config
+            .as_ref()
+            .map_or(0, |config| config.reset_token_minutes)
+    }
+
+    /*
+     * Mailer::reset_link(&self, token: &str) -> String {}
+     *
+     * The reset page with `token` added to its query string.
+     */
+
+    fn reset_link(&self, token: &str) -> String {
+        let url = self
+            .config
+            .as_ref()
+            .map_or("", |config| config.reset_url.as_str());
+        let separator = if url.contains('?') { '&' } else { '?' };
+        format!("{}{}token={}", url, separator, token)
+    }
+
+    /*
+     * Mailer::reset_message(&self, to: &str, username: &str, token: &str) -> Result<Message, Error> {}
+     *
+     * The plain text mail carrying a reset link for `username` to `to`.
+     */
+
+    fn reset_message(&self, to: &str, username: &str, token: &str) -> Result<Message, Error> {
+        let from = self
+            .from
+            .clone()
+            .ok_or_else(|| invalid("Password resets aren't configured.".to_string()))?;
+        let to: Mailbox = to.parse().map_err(Error::other)?;
+
+        let body = format!(
+            "Hi {},\n\n\
+             Someone asked to reset the password of your QR code analytics account.\n\
+             If that was you, choose a new password here within {} minutes:\n\n\
+             {}\n\n\
+             The link works once. If you didn't ask for this, ignore this mail,\n\
+             your password stays the same.\n",
+            username,
+            self.reset_token_minutes(),
+            self.reset_link(token)
+        );
+
+        Message::builder()
+            .from(from)
+            .to(to)
+            .subject("Reset your password")
+            .header(ContentType::TEXT_PLAIN)
+            .body(body)
+            .map_err(Error::other)
+    }
+
+    /*
+     * pub Mailer::send_reset(&self, to: &str, username: &str, token: &str) -> Result<(), Error> {}
+     *
+     * Mails a reset link for `username` to `to`.
+     */
+
+    pub async fn send_reset(&self, to: &str, username: &str, token: &str) -> Result<(), Error> {
+        let message = self.reset_message(to, username, token)?;
+        let transport = self
+            .transport
+            .as_ref()
+            .ok_or_else(|| invalid("Password resets aren't configured.".to_string()))?;
+        transport.send(message).await.map_err(Error::other)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn reset_mail_carries_the_link() {
+        let mailer = Mailer::new(Some(SmtpConfig {
+            host: "localhost".to_string(),
+            port: 1025,
+            security: SmtpSecurity::None,
+            from: "QR Analytics <noreply@school.example>".to_string(),
+            reset_url: "https://qr.school.example/reset_password".to_string(),
+            ..SmtpConfig::default()
+        }))
+        .unwrap();
+        assert!(mailer.enabled());
+
+        let message = mailer
+            .reset_message("teacher@school.example", "teacher", "abc123")
+            .unwrap();
+        let formatted = String::from_utf8(message.formatted()).unwrap();
+        assert!(formatted.contains("To: teacher@school.example"));
+        assert!(formatted.contains("https://qr.school.example/reset_password?token=abc123"));
+
+        assert!(!Mailer::new(None).unwrap().enabled());
+        assert!(Mailer::new(Some(SmtpConfig::default())).is_err());
+    }
+}
@@ -1,18 +1,67 @@
-use api::{authenticate, can_login, get_state, main_endpoint};
-use creds::Login;
-use data::{read_from_json, AppData, AppState, JsonData};
-use http::{contact, dashboard, files, index, login, privacy};
+use admin::{
+    add_correction, audit_log, backup, enforce_retention, import_csv, list_corrections, restore,
+    retention_report, verify_ledger,
+};
+use allowlist::IpAllowlist;
+use api::{
+    authenticate, can_login, csrf_token, forgot_password, get_state, issue_token, logout,
+    main_endpoint, oidc_callback, oidc_login, reset_password, verify_second_factor,
+};
+use archive::compact;
+use audit::AuditLog;
+use config::Config;
+use cookies::{SessionKeyRotation, SessionKeys};
+use creds::{watch_accounts, Auth};
+use csrf::CsrfProtection;
+use data::{read_from_json, write_to_json, AppData, JsonData};
+use http::{contact, dashboard, files, index, login, privacy, reset_password_page};
+use ledger::Ledger;
+use mail::Mailer;
+use oidc::Oidc;
+use resets::PasswordResets;
+use sessions::{persist_sessions, ServerSession, SessionStore};
+use throttle::{prune_throttle, LoginThrottle};
+use tokens::ApiKeys;
+use users::{
+    change_password, confirm_totp, create_api_key, create_user, delete_user, enroll_totp,
+    list_api_keys, list_sessions, list_users, reset_totp, revoke_api_key, revoke_session,
+    revoke_sessions, update_user,
+};
 
-use ntex::web::{get, middleware, App, HttpServer};
-use ntex_session::CookieSession;
+use ntex::web::{get, middleware, types::PayloadConfig, App, HttpServer};
 
 use std::sync::Arc;
 use tokio::sync::Mutex;
 
+mod admin;
+mod allowlist;
 mod api;
+mod archive;
+mod audit;
+mod backup;
+mod check;
+mod cli;
+mod config;
+mod cookies;
 mod creds;
+mod csrf;
 mod data;
+mod events;
+mod export;
+mod hashing;
 mod http;
+mod import;
+mod keys;
+mod ledger;
+mod mail;
+mod oidc;
+mod resets;
+mod retention;
+mod sessions;
+mod throttle;
+mod tokens;
+mod totp;
+mod users;
 
 /*
  * Main function, the base of the entire website as a whole
@@ -24,33 +73,57 @@ async fn main() -> std::io::Result<()> {
     tracing_subscriber::fmt::init();
     color_eyre::install().expect("Can't install hooks.");
 
-    let _ = Login::get(); // Make sure to generate a login.
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    if let Some(result) = cli::run(&args).await {
+        return result;
+    }
 
     let current_dir = std::env::current_dir()?;
     let state_path = current_dir.join("state");
 
     let last_data = if read_from_json(&state_path).await.is_ok() {
-        read_from_json(&state_path).await?.state
+        read_from_json(&state_path).await?
     } else {
-        JsonData::default().state
+        JsonData::default()
     };
 
-    let app_data = AppData { state: Vec::new() };
-    let mut app_state = app_data.state;
+    let config = Arc::new(Config::get(&state_path));
+    config.check()?;
+    hashing::configure(&config.password_hash)?;
 
-    for entry in last_data {
-        app_state.push(AppState {
-            date: entry.date.clone(),
-            last_date: entry.date,
-            counter: entry.last_count,
-            count_since_yesterday: entry.count_since_yesterday,
-            dotw: entry.dotw,
-            time: entry.last_time.clone(),
-            last_time: entry.last_time,
-        })
+    let mut app_data = AppData::from(last_data);
+    let archived = compact(&state_path, &mut app_data, config.archive_after_days)?;
+    if archived > 0 {
+        tracing::info!(
+            "Archived {} days older than {} days.",
+            archived,
+            config.archive_after_days
+        );
+        write_to_json(&state_path, JsonData::from(&app_data)).await?;
     }
 
-    let state = Arc::new(Mutex::new(AppData { state: app_state }));
+    let state = Arc::new(Mutex::new(app_data));
+    let ledger = Arc::new(Mutex::new(Ledger::load(&state_path)?));
+    let auth = Arc::new(Auth::load(&state_path, &config)?);
+    let session_keys = SessionKeys::load(&state_path, &config)?;
+    let throttle = Arc::new(LoginThrottle::default());
+    let audit = Arc::new(AuditLog::new(&state_path));
+    let oidc = Arc::new(Oidc::new(config.oidc.clone()));
+    let api_keys = Arc::new(ApiKeys::load(&state_path)?);
+    let sessions = Arc::new(SessionStore::load(&state_path, &config)?);
+    let mailer = Arc::new(Mailer::new(config.smtp.clone())?);
+    let resets = Arc::new(PasswordResets::load(&state_path)?);
+    let allowlist = IpAllowlist::new(&config.ip_allowlist)?;
+
+    ntex::rt::spawn(retention::schedule(
+        state.clone(),
+        ledger.clone(),
+        config.clone(),
+        audit.clone(),
+    ));
+    ntex::rt::spawn(watch_accounts(auth.clone()));
+    ntex::rt::spawn(persist_sessions(sessions.clone()));
+    ntex::rt::spawn(prune_throttle(throttle.clone()));
 
     HttpServer::new(move || {
         App::new()
@@ -62,19 +135,70 @@ async fn main() -> std::io::Result<()> {
             .service(index)
             .service(privacy)
             .service(login)
+            .service(reset_password_page)
             .service(dashboard)
             .service(contact)
             .service(main_endpoint)
             .service(get_state)
             .service(can_login)
+            .service(csrf_token)
+            .service(logout)
+            .service(oidc_login)
+            .service(oidc_callback)
+            .service(backup)
+            .service(restore)
+            .service(import_csv)
+            .service(retention_report)
+            .service(enforce_retention)
+            .service(verify_ledger)
+            .service(list_corrections)
+            .service(add_correction)
+            .service(audit_log)
+            .service(list_users)
+            .service(create_user)
+            .service(update_user)
+            .service(delete_user)
+            .service(change_password)
+            .service(revoke_sessions)
+            .service(enroll_totp)
+            .service(confirm_totp)
+            .service(reset_totp)
+            .service(list_api_keys)
+            .service(create_api_key)
+            .service(revoke_api_key)
+            .service(list_sessions)
+            .service(revoke_session)
             .route("/{filename}*", get().to(files))
             .service(authenticate)
+            .service(verify_second_factor)
+            .service(forgot_password)
+            .service(reset_password)
+            .service(issue_token)
             .state(state.clone())
-            .wrap(
-                CookieSession::private(&[0; 128])
-                    .name("qrcode")
-                    .secure(false),
-            )
+            .state(ledger.clone())
+            .state(auth.clone())
+            .state(throttle.clone())
+            .state(audit.clone())
+            .state(oidc.clone())
+            .state(api_keys.clone())
+            .state(sessions.clone())
+            .state(mailer.clone())
+            .state(resets.clone())
+            .state(config.clone())
+            .state(PayloadConfig::new(config.max_upload_bytes))
+            .wrap(CsrfProtection::new(&config))
+            .wrap(ServerSession::new(
+                "qrcode",
+                &session_keys,
+                config.secure_cookies,
+                sessions.clone(),
+            ))
+            .wrap(SessionKeyRotation::new(
+                "qrcode",
+                &session_keys,
+                config.secure_cookies,
+            ))
+            .wrap(allowlist.clone())
             .wrap(ntex::web::middleware::Logger::default())
     })
     .bind("0.0.0.0:8080")?
@@ -0,0 +1,279 @@
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use serde_json::{from_reader, to_writer};
+use sha2::{Digest, Sha256};
+use std::{
+    collections::HashMap,
+    fs::{rename, File, OpenOptions},
+    io::Error,
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+/*
+ * const REQUESTS_PER_IP: usize
+ *
+ * Reset mails one IP can ask for per REQUEST_WINDOW_SECONDS.
+ */
+
+const REQUESTS_PER_IP: usize = 5;
+
+/*
+ * const REQUESTS_PER_ADDRESS: usize
+ *
+ * Reset mails one email address can be sent per REQUEST_WINDOW_SECONDS, so nobody's inbox gets flooded.
+ */
+
+const REQUESTS_PER_ADDRESS: usize = 3;
+
+/*
+ * const REQUEST_WINDOW_SECONDS: i64
+ *
+ * The window reset requests are counted in.
+ */
+
+const REQUEST_WINDOW_SECONDS: i64 = 60 * 60;
+
+/*
+ * struct ResetToken {
+ *   username: String,
+ *   token_hash: String,
+ *   expires: i64,
+ * }
+ *
+ * An outstanding reset link, only the SHA-256 of its token is kept.
+ */
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct ResetToken {
+    username: String,
+    token_hash: String,
+    expires: i64,
+}
+
+/*
+ * fn hash_token(token: &str) -> String {}
+ *
+ * The SHA-256 of a reset token, hex encoded. The tokens are random, a slow hash buys nothing.
+ */
+
+fn hash_token(token: &str) -> String {
+    hex::encode(Sha256::digest(token.as_bytes()))
+}
+
+/*
+ * pub struct PasswordResets {
+ *   path: PathBuf,
+ *   tokens: Mutex<Vec<ResetToken>>,
+ *   requests: Mutex<HashMap<String, Vec<i64>>>,
+ * }
+ *
+ * Outstanding reset links, kept in state/password_resets.json so they survive a restart,
+ * and the recent reset requests per IP and address, kept in memory only.
+ * Each account has at most one working link, asking for a new one replaces it.
+ */
+
+pub struct PasswordResets {
+    path: PathBuf,
+    tokens: Mutex<Vec<ResetToken>>,
+    requests: Mutex<HashMap<String, Vec<i64>>>,
+}
+
+/*
+ * impl PasswordResets {
+ *   pub fn load(path: &Path) -> Result<Self, Error> {}
+ *   fn save(&self, tokens: &[ResetToken]) -> Result<(), Error> {}
+ *   pub fn allow(&self, ip: &str, address: &str, now: i64) -> bool {}
+ *   pub fn issue(&self, username: &str, now: i64, minutes: i64) -> Result<String, Error> {}
+ *   pub fn redeem(&self, token: &str, now: i64) -> Result<Option<String>, Error> {}
+ * }
+ *
+ * Assorted implementations accessed through `PasswordResets::function(args)`
+ */
+
+impl PasswordResets {
+    /*
+     * pub PasswordResets::load(path: &Path) -> Result<Self, Error> {}
+     *
+     * Reads state/password_resets.json if there is one.
+     */
+
+    pub fn load(path: &Path) -> Result<Self, Error> {
+        let file_path = path.join("password_resets.json");
+        let tokens = if file_path.is_file() {
+            from_reader(File::open(file_path)?)?
+        } else {
+            Vec::new()
+        };
+
+        Ok(PasswordResets {
+            path: path.to_path_buf(),
+            tokens: Mutex::new(tokens),
+            requests: Mutex::new(HashMap::new()),
+        })
+    }
+
+    /*
+     * PasswordResets::save(&self, tokens: &[ResetToken]) -> Result<(), Error> {}
+     *
+     * Writes the outstanding links to password_resets.json, only readable by the owner on unix.
+     * Goes through a temporary file so a crash can't leave it empty or half written.
+     */
+
+    fn save(&self, tokens: &[ResetToken]) -> Result<(), Error> {
+        let mut options = OpenOptions::new();
+        options.write(true).create(true).truncate(true);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::OpenOptionsExt;
+            options.mode(0o600);
+        }
+
+        let file_path = self.path.join("password_resets.json");
+        let temp_path = file_path.with_extension("json.tmp");
+        let file = options.open(&temp_path)?;
+        to_writer(&file, tokens)?;
+        file.sync_all()?;
+        rename(temp_path, file_path)
+    }
+
+    /*
+     * pub PasswordResets::allow(&self, ip: &str, address: &str, now: i64) -> bool {}
+     *
+     * Counts a reset request from `ip` for `address`, false once either has asked too often.
+     * Requests count whether or not the address belongs to an account,
+     * so being limited says nothing about which addresses exist.
+     */
+
+    pub fn allow(&self, ip: &str, address: &str, now: i64) -> bool {
+        let mut requests = self.requests.lock().expect("Reset lock poisoned");
+        for times in requests.values_mut() {
+            times.retain(|time| now - time < REQUEST_WINDOW_SECONDS);
+        }
+        requests.retain(|_, times| !times.is_empty());
+
+        let subjects = [
+            (format!("ip:{}", ip), REQUESTS_PER_IP),
+            (
+                format!("address:{}", address.to_lowercase()),
+                REQUESTS_PER_ADDRESS,
+            ),
+        ];
+        if subjects
+            .iter()
+            .any(|(key, limit)| requests.get(key).is_some_and(|times| times.len() >= *limit))
+        {
+            return false;
+        }
+
+        for (key, _) in subjects {
+            requests.entry(key).or_default().push(now);
+        }
+        true
+    }
+
+    /*
+     * pub PasswordResets::issue(&self, username: &str, now: i64, minutes: i64) -> Result<String, Error> {}
+     *
+     * Creates a reset token for `username` that works for `minutes`, replacing any earlier one.
+     */
+
+    pub fn issue(&self, username: &str, now: i64, minutes: i64) -> Result<String, Error> {
+        let mut bytes = [0u8; 32];
+        rand::thread_rng().fill_bytes(&mut bytes);
+        let token = URL_SAFE_NO_PAD.encode(bytes);
+
+        let mut tokens = self.tokens.lock().expect("Reset lock poisoned");
+        tokens.retain(|reset| reset.username != username && reset.expires > now);
+        tokens.push(ResetToken {
+            username: username.to_string(),
+            token_hash: hash_token(&token),
+            expires: now + minutes * 60,
+        });
+        self.save(&tokens)?;
+        Ok(token)
+    }
+
+    /*
+     * pub PasswordResets::redeem(&self, token: &str, now: i64) -> Result<Option<String>, Error> {}
+     *
+     * Uses up a reset token, returning the account it was for.
+     * None for tokens that are unknown, already used or expired.
+     */
+
+    pub fn redeem(&self, token: &str, now: i64) -> Result<Option<String>, Error> {
+        let token_hash = hash_token(token);
+        let mut tokens = self.tokens.lock().expect("Reset lock poisoned");
+        let username = tokens
+            .iter()
+            .find(|reset| reset.token_hash == token_hash && reset.expires > now)
+            .map(|reset| reset.username.clone());
+
+        let before = tokens.len();
+        tokens.retain(|reset| reset.token_hash != token_hash && reset.expires > now);
+        if tokens.len() != before {
+            self.save(&tokens)?;
+        }
+        Ok(username)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn resets() -> (PasswordResets, PathBuf) {
+        let path = std::env::temp_dir().join(format!(
+            "resets-test-{}-{}",
+            std::process::id(),
+            rand::random::<u32>()
+        ));
+        std::fs::create_dir_all(&path).unwrap();
+        (PasswordResets::load(&path).unwrap(), path)
+    }
+
+    #[test]
+    fn tokens_work_once_and_expire() {
+        let (resets, path) = resets();
+
+        let first = resets.issue("teacher", 1000, 30).unwrap();
+        let second = resets.issue("teacher", 1000, 30).unwrap();
+        assert_eq!(resets.redeem(&first, 1001).unwrap(), None);
+        assert_eq!(
+            resets.redeem(&second, 1001).unwrap(),
+            Some("teacher".to_string())
+        );
+        assert_eq!(resets.redeem(&second, 1002).unwrap(), None);
+
+        let expired = resets.issue("teacher", 1000, 30).unwrap();
+        assert_eq!(resets.redeem(&expired, 1000 + 30 * 60).unwrap(), None);
+
+        let kept = resets.issue("admin", 1000, 30).unwrap();
+        assert!(!path.join("password_resets.json.tmp").exists());
+        let reloaded = PasswordResets::load(&path).unwrap();
+        assert_eq!(
+            reloaded.redeem(&kept, 1001).unwrap(),
+            Some("admin".to_string())
+        );
+
+        std::fs::remove_dir_all(path).unwrap();
+    }
+
+    #[test]
+    fn requests_are_limited_per_ip_and_address() {
+        let (resets, path) = resets();
+
+        for _ in 0..REQUESTS_PER_ADDRESS {
+            assert!(resets.allow("10.0.0.1", "Teacher@School.example", 0));
+        }
+        assert!(!resets.allow("10.0.0.2", "teacher@school.example", 10));
+        assert!(resets.allow("10.0.0.1", "other@school.example", 10));
+        assert!(resets.allow("10.0.0.1", "third@school.example", 10));
+        assert!(!resets.allow("10.0.0.1", "fourth@school.example", 10));
+
+        assert!(resets.allow("10.0.0.1", "teacher@school.example", REQUEST_WINDOW_SECONDS));
+
+        std::fs::remove_dir_all(path).unwrap();
+    }
+}
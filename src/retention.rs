@@ -0,0 +1,200 @@
+use super::{
+    archive::{archived_years, parse_date, read_archive, remove_archive, write_archive},
+    audit::AuditLog,
+    config::Config,
+    data::{write_to_json, AppData, JsonData},
+    events::read_events,
+    ledger::Ledger,
+};
+use chrono::{prelude::*, Duration};
+use serde::Serialize;
+use serde_json::json;
+use std::{
+    io::{Error, ErrorKind},
+    path::Path,
+    sync::Arc,
+};
+use tokio::sync::Mutex;
+
+/*
+ * pub struct RetentionReport {
+ *   pub dry_run: bool,
+ *   pub events_cutoff: Option<String>,
+ *   pub events_deleted: usize,
+ *   pub days_cutoff: Option<String>,
+ *   pub days_deleted: usize,
+ * }
+ *
+ * What a retention run deleted, or would delete when it's a dry run.
+ * A cutoff of None means that kind of data is kept forever.
+ */
+
+#[derive(Serialize, Debug)]
+pub struct RetentionReport {
+    pub dry_run: bool,
+    pub events_cutoff: Option<String>,
+    pub events_deleted: usize,
+    pub days_cutoff: Option<String>,
+    pub days_deleted: usize,
+}
+
+/*
+ * fn cutoff(field: &str, days: Option<i64>) -> Result<Option<NaiveDate>, Error> {}
+ *
+ * The first day that is still kept when keeping `days` days, an error naming `field` when that's out of range.
+ */
+
+fn cutoff(field: &str, days: Option<i64>) -> Result<Option<NaiveDate>, Error> {
+    let Some(days) = days else {
+        return Ok(None);
+    };
+    Duration::try_days(days)
+        .and_then(|days| Local::now().date_naive().checked_sub_signed(days))
+        .map(Some)
+        .ok_or_else(|| {
+            Error::new(
+                ErrorKind::InvalidInput,
+                format!("{} is out of range: {}", field, days),
+            )
+        })
+}
+
+/*
+ * pub fn enforce(path: &Path, app_data: &mut AppData, ledger: &mut Ledger, config: &Config, dry_run: bool) -> Result<RetentionReport, Error> {}
+ *
+ * Deletes raw scan events older than config.event_retention_days,
+ * and daily totals older than config.daily_retention_days from both the hot state and the archives.
+ * The latest day always stays since the counter continues from it.
+ * With `dry_run` nothing is touched and the report only counts.
+ * The caller is responsible for writing data.json afterwards.
+ */
+
+pub fn enforce(
+    path: &Path,
+    app_data: &mut AppData,
+    ledger: &mut Ledger,
+    config: &Config,
+    dry_run: bool,
+) -> Result<RetentionReport, Error> {
+    let events_cutoff = cutoff("event_retention_days", config.event_retention_days)?;
+    let days_cutoff = cutoff("daily_retention_days", config.daily_retention_days)?;
+
+    let mut report = RetentionReport {
+        dry_run,
+        events_cutoff: events_cutoff.map(|date| date.to_string()),
+        events_deleted: 0,
+        days_cutoff: days_cutoff.map(|date| date.to_string()),
+        days_deleted: 0,
+    };
+
+    if let Some(events_cutoff) = events_cutoff {
+        let events = read_events(path)?;
+        let expired = events
+            .iter()
+            .take_while(|event| match DateTime::parse_from_rfc3339(&event.time) {
+                Ok(time) => time.date_naive() < events_cutoff,
+                Err(_) => false,
+            })
+            .count();
+        report.events_deleted = expired;
+
+        if !dry_run {
+            ledger.cut_events(path, &events, expired)?;
+        }
+    }
+
+    if let Some(days_cutoff) = days_cutoff {
+        let expired = |date: &str| matches!(parse_date(date), Some(date) if date < days_cutoff);
+
+        let keep_from = app_data.state.len().saturating_sub(1);
+        let hot_expired = app_data.state[..keep_from]
+            .iter()
+            .filter(|entry| expired(&entry.date))
+            .count();
+        report.days_deleted += hot_expired;
+
+        if !dry_run && hot_expired > 0 {
+            let last = app_data.state.pop();
+            app_data.state.retain(|entry| !expired(&entry.date));
+            app_data.state.extend(last);
+            app_data.touch();
+        }
+
+        for year in archived_years(path)? {
+            let mut json_data = read_archive(path, year)?;
+            let total = json_data.state.len();
+            json_data.state.retain(|entry| !expired(&entry.date));
+            let deleted = total - json_data.state.len();
+            report.days_deleted += deleted;
+
+            if dry_run || deleted == 0 {
+                continue;
+            }
+            if json_data.state.is_empty() {
+                remove_archive(path, year)?;
+            } else {
+                write_archive(path, year, &json_data)?;
+            }
+        }
+    }
+
+    Ok(report)
+}
+
+/*
+ * pub async fn schedule(data: Arc<Mutex<AppData>>, ledger: Arc<Mutex<Ledger>>, config: Arc<Config>, audit: Arc<AuditLog>) {}
+ *
+ * Enforces the retention policy every config.retention_interval_hours, starting right away.
+ * An interval of 0 turns the job off, it can still be triggered through /api/admin/retention.
+ * Runs that delete anything are written to the audit log as the "system" user.
+ */
+
+pub async fn schedule(
+    data: Arc<Mutex<AppData>>,
+    ledger: Arc<Mutex<Ledger>>,
+    config: Arc<Config>,
+    audit: Arc<AuditLog>,
+) {
+    if config.retention_interval_hours == 0 {
+        return;
+    }
+    let interval = std::time::Duration::from_secs(config.retention_interval_hours * 60 * 60);
+    let path = Path::new("./state");
+
+    loop {
+        let mut app_data = data.lock().await;
+        let mut ledger = ledger.lock().await;
+        match enforce(path, &mut app_data, &mut ledger, &config, false) {
+            Ok(report) => {
+                if report.days_deleted > 0 {
+                    if let Err(error) = write_to_json(path, JsonData::from(&*app_data)).await {
+                        tracing::error!("Failed to write state after retention: {}", error);
+                    }
+                }
+                if report.events_deleted > 0 || report.days_deleted > 0 {
+                    tracing::info!(
+                        "Retention deleted {} events and {} days.",
+                        report.events_deleted,
+                        report.days_deleted
+                    );
+                    if let Err(error) = audit.record(
+                        "system",
+                        "",
+                        "retention",
+                        json!({
+                            "events_deleted": report.events_deleted,
+                            "days_deleted": report.days_deleted,
+                        }),
+                    ) {
+                        tracing::error!("Failed to write the audit log: {}", error);
+                    }
+                }
+            }
+            Err(error) => tracing::error!("Retention failed: {}", error),
+        }
+        drop(ledger);
+        drop(app_data);
+
+        tokio::time::sleep(interval).await;
+    }
+}
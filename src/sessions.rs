@@ -0,0 +1,742 @@
+use super::{allowlist::ClientIp, config::Config, cookies::SessionKeys};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use chrono::prelude::*;
+use cookie::{time::Duration as CookieDuration, Cookie, CookieJar, Key, SameSite};
+use ntex::{
+    http::header::{self, HeaderValue},
+    service::{Middleware, Service, ServiceCtx},
+    web::{Error as WebError, WebRequest, WebResponse},
+};
+use ntex_session::{Session, SessionStatus};
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use serde_json::{from_reader, to_writer};
+use sha2::{Digest, Sha256};
+use std::{
+    cmp::Reverse,
+    collections::HashMap,
+    fs::{rename, File, OpenOptions},
+    io::Error,
+    path::{Path, PathBuf},
+    rc::Rc,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+};
+
+/*
+ * const SEEN_RESOLUTION: i64
+ *
+ * How far last_seen has to move before the change is worth writing to sessions.json.
+ * The in-memory value is always current.
+ */
+
+const SEEN_RESOLUTION: i64 = 60;
+
+/*
+ * const MAX_SESSIONS: usize
+ *
+ * How many sessions the store holds before starting a new one drops the anonymous session
+ * used longest ago. Every scan starts a session for its cooldown, so without a cap
+ * anyone could fill the memory and sessions.json by scanning with their cookies turned off.
+ * Logged in sessions are never dropped to make room.
+ */
+
+const MAX_SESSIONS: usize = 10_000;
+
+/*
+ * pub struct StoredSession {
+ *   pub created: i64,
+ *   pub last_seen: i64,
+ *   pub ip: String,
+ *   pub user_agent: String,
+ *   pub data: HashMap<String, String>,
+ * }
+ *
+ * A session as kept in state/sessions.json, data holds the JSON encoded values handlers set through Session.
+ * ip and user_agent are from the last request made with it.
+ */
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct StoredSession {
+    pub created: i64,
+    pub last_seen: i64,
+    pub ip: String,
+    pub user_agent: String,
+    pub data: HashMap<String, String>,
+}
+
+/*
+ * pub struct SessionInfo {
+ *   pub id: String,
+ *   pub user: String,
+ *   pub created: i64,
+ *   pub last_seen: i64,
+ *   pub ip: String,
+ *   pub user_agent: String,
+ * }
+ *
+ * What the admin endpoints show of a logged in session.
+ * id is the hash the session is stored under, it can't be used as a cookie.
+ */
+
+#[derive(Serialize, Debug, Clone)]
+pub struct SessionInfo {
+    pub id: String,
+    pub user: String,
+    pub created: i64,
+    pub last_seen: i64,
+    pub ip: String,
+    pub user_agent: String,
+}
+
+/*
+ * fn session_id(token: &str) -> String {}
+ *
+ * The SHA-256 of a cookie's token, hex encoded, which is what sessions are stored under.
+ * Neither sessions.json nor the admin endpoints ever hold a token that could be replayed.
+ */
+
+fn session_id(token: &str) -> String {
+    hex::encode(Sha256::digest(token.as_bytes()))
+}
+
+/*
+ * fn new_token() -> String {}
+ *
+ * 32 random bytes, base64url encoded, for a new session cookie.
+ */
+
+fn new_token() -> String {
+    let mut bytes = [0u8; 32];
+    rand::thread_rng().fill_bytes(&mut bytes);
+    URL_SAFE_NO_PAD.encode(bytes)
+}
+
+/*
+ * impl StoredSession {
+ *   fn user(&self) -> Option<String> {}
+ *   fn anonymous(&self) -> bool {}
+ * }
+ *
+ * Assorted implementations accessed through `StoredSession::function(args)`
+ */
+
+impl StoredSession {
+    /*
+     * StoredSession::user(&self) -> Option<String> {}
+     *
+     * Who is logged in on the session, if anyone is.
+     */
+
+    fn user(&self) -> Option<String> {
+        serde_json::from_str(self.data.get("user")?).ok()
+    }
+
+    /*
+     * StoredSession::anonymous(&self) -> bool {}
+     *
+     * Whether nobody is logged in on the session, like those only holding a scan cooldown.
+     */
+
+    fn anonymous(&self) -> bool {
+        !self.data.contains_key("user")
+    }
+}
+
+/*
+ * pub struct SessionStore {
+ *   path: PathBuf,
+ *   max_idle: i64,
+ *   sessions: Mutex<HashMap<String, StoredSession>>,
+ *   dirty: AtomicBool,
+ * }
+ *
+ * Every session, kept in memory and written to state/sessions.json by persist_sessions().
+ * Sessions unused for config.session_retention_hours are dropped.
+ */
+
+pub struct SessionStore {
+    path: PathBuf,
+    max_idle: i64,
+    sessions: Mutex<HashMap<String, StoredSession>>,
+    dirty: AtomicBool,
+}
+
+/*
+ * impl SessionStore {
+ *   pub fn load(path: &Path, config: &Config) -> Result<Self, Error> {}
+ *   pub fn new(path: &Path, max_idle: i64, sessions: HashMap<String, StoredSession>) -> Self {}
+ *   fn open(&self, id: &str, now: i64, ip: &str, user_agent: &str) -> Option<HashMap<String, String>> {}
+ *   fn insert(&self, id: &str, data: HashMap<String, String>, now: i64, ip: &str, user_agent: &str) {}
+ *   fn update(&self, id: &str, data: HashMap<String, String>) -> bool {}
+ *   pub fn list(&self) -> Vec<SessionInfo> {}
+ *   pub fn remove(&self, id: &str) -> Option<SessionInfo> {}
+ *   pub fn remove_user(&self, username: &str) -> usize {}
+ *   pub fn prune(&self, now: i64) -> usize {}
+ *   pub fn flush(&self) -> Result<bool, Error> {}
+ * }
+ *
+ * Assorted implementations accessed through `SessionStore::function(args)`
+ */
+
+impl SessionStore {
+    /*
+     * pub SessionStore::load(path: &Path, config: &Config) -> Result<Self, Error> {}
+     *
+     * Reads state/sessions.json if there is one, so a restart doesn't log everyone out.
+     */
+
+    pub fn load(path: &Path, config: &Config) -> Result<Self, Error> {
+        let file_path = path.join("sessions.json");
+        let sessions = if file_path.is_file() {
+            from_reader(File::open(file_path)?)?
+        } else {
+            HashMap::new()
+        };
+
+        let store = Self::new(path, config.session_retention_hours * 60 * 60, sessions);
+        store.prune(Utc::now().timestamp());
+        Ok(store)
+    }
+
+    /*
+     * pub SessionStore::new(path: &Path, max_idle: i64, sessions: HashMap<String, StoredSession>) -> Self {}
+     *
+     * Builds a store around existing sessions, written to `path`, forgetting sessions idle for `max_idle` seconds.
+     */
+
+    pub fn new(path: &Path, max_idle: i64, sessions: HashMap<String, StoredSession>) -> Self {
+        SessionStore {
+            path: path.to_path_buf(),
+            max_idle,
+            sessions: Mutex::new(sessions),
+            dirty: AtomicBool::new(false),
+        }
+    }
+
+    /*
+     * SessionStore::open(&self, id: &str, now: i64, ip: &str, user_agent: &str) -> Option<HashMap<String, String>> {}
+     *
+     * The data of session `id`, noting that it was just used from `ip`.
+     * None for sessions that don't exist or idled out.
+     */
+
+    fn open(
+        &self,
+        id: &str,
+        now: i64,
+        ip: &str,
+        user_agent: &str,
+    ) -> Option<HashMap<String, String>> {
+        let mut sessions = self.sessions.lock().expect("Session store poisoned");
+        let session = sessions.get_mut(id)?;
+        if now - session.last_seen > self.max_idle {
+            sessions.remove(id);
+            self.dirty.store(true, Ordering::Relaxed);
+            return None;
+        }
+
+        if now - session.last_seen >= SEEN_RESOLUTION || session.ip != ip {
+            self.dirty.store(true, Ordering::Relaxed);
+        }
+        session.last_seen = now;
+        session.ip = ip.to_string();
+        session.user_agent = user_agent.to_string();
+        Some(session.data.clone())
+    }
+
+    /*
+     * SessionStore::insert(&self, id: &str, data: HashMap<String, String>, now: i64, ip: &str, user_agent: &str) {}
+     *
+     * Starts session `id` holding `data`, dropping the anonymous session used longest ago
+     * when the store is at MAX_SESSIONS.
+     */
+
+    fn insert(
+        &self,
+        id: &str,
+        data: HashMap<String, String>,
+        now: i64,
+        ip: &str,
+        user_agent: &str,
+    ) {
+        let session = StoredSession {
+            created: now,
+            last_seen: now,
+            ip: ip.to_string(),
+            user_agent: user_agent.to_string(),
+            data,
+        };
+        let mut sessions = self.sessions.lock().expect("Session store poisoned");
+        if sessions.len() >= MAX_SESSIONS {
+            let oldest = sessions
+                .iter()
+                .filter(|(_, session)| session.anonymous())
+                .min_by_key(|(_, session)| session.last_seen)
+                .map(|(id, _)| id.clone());
+            if let Some(oldest) = oldest {
+                sessions.remove(&oldest);
+            }
+        }
+        sessions.insert(id.to_string(), session);
+        self.dirty.store(true, Ordering::Relaxed);
+    }
+
+    /*
+     * SessionStore::update(&self, id: &str, data: HashMap<String, String>) -> bool {}
+     *
+     * Replaces the data of session `id`. False when it was revoked while the request was handled,
+     * which leaves it revoked.
+     */
+
+    fn update(&self, id: &str, data: HashMap<String, String>) -> bool {
+        let mut sessions = self.sessions.lock().expect("Session store poisoned");
+        let Some(session) = sessions.get_mut(id) else {
+            return false;
+        };
+        session.data = data;
+        self.dirty.store(true, Ordering::Relaxed);
+        true
+    }
+
+    /*
+     * pub SessionStore::list(&self) -> Vec<SessionInfo> {}
+     *
+     * Every session someone is logged in on, most recently used first.
+     * Sessions that only hold a scan cooldown aren't interesting and are left out.
+     */
+
+    pub fn list(&self) -> Vec<SessionInfo> {
+        let sessions = self.sessions.lock().expect("Session store poisoned");
+        let mut list: Vec<SessionInfo> = sessions
+            .iter()
+            .filter_map(|(id, session)| {
+                Some(SessionInfo {
+                    id: id.clone(),
+                    user: session.user()?,
+                    created: session.created,
+                    last_seen: session.last_seen,
+                    ip: session.ip.clone(),
+                    user_agent: session.user_agent.clone(),
+                })
+            })
+            .collect();
+        list.sort_by_key(|info| Reverse(info.last_seen));
+        list
+    }
+
+    /*
+     * pub SessionStore::remove(&self, id: &str) -> Option<SessionInfo> {}
+     *
+     * Ends session `id`, its cookie stops working right away. Returns who was logged in on it.
+     */
+
+    pub fn remove(&self, id: &str) -> Option<SessionInfo> {
+        let mut sessions = self.sessions.lock().expect("Session store poisoned");
+        let session = sessions.remove(id)?;
+        self.dirty.store(true, Ordering::Relaxed);
+        Some(SessionInfo {
+            id: id.to_string(),
+            user: session.user().unwrap_or_default(),
+            created: session.created,
+            last_seen: session.last_seen,
+            ip: session.ip,
+            user_agent: session.user_agent,
+        })
+    }
+
+    /*
+     * pub SessionStore::remove_user(&self, username: &str) -> usize {}
+     *
+     * Ends every session `username` is logged in on, returning how many there were.
+     */
+
+    pub fn remove_user(&self, username: &str) -> usize {
+        let mut sessions = self.sessions.lock().expect("Session store poisoned");
+        let before = sessions.len();
+        sessions.retain(|_, session| session.user().as_deref() != Some(username));
+        let removed = before - sessions.len();
+        if removed > 0 {
+            self.dirty.store(true, Ordering::Relaxed);
+        }
+        removed
+    }
+
+    /*
+     * pub SessionStore::prune(&self, now: i64) -> usize {}
+     *
+     * Drops sessions idle for longer than the retention, returning how many.
+     */
+
+    pub fn prune(&self, now: i64) -> usize {
+        let mut sessions = self.sessions.lock().expect("Session store poisoned");
+        let before = sessions.len();
+        sessions.retain(|_, session| now - session.last_seen <= self.max_idle);
+        let removed = before - sessions.len();
+        if removed > 0 {
+            self.dirty.store(true, Ordering::Relaxed);
+        }
+        removed
+    }
+
+    /*
+     * pub SessionStore::flush(&self) -> Result<bool, Error> {}
+     *
+     * Writes sessions.json if anything changed since the last write, returning whether it did.
+     * The file holds logged in sessions, so it's only readable by the owner on unix,
+     * and it's replaced in one go so a crash can't leave half of it behind.
+     */
+
+    pub fn flush(&self) -> Result<bool, Error> {
+        if !self.dirty.swap(false, Ordering::Relaxed) {
+            return Ok(false);
+        }
+        let sessions = self
+            .sessions
+            .lock()
+            .expect("Session store poisoned")
+            .clone();
+
+        let result = (|| {
+            let mut options = OpenOptions::new();
+            options.write(true).create(true).truncate(true);
+            #[cfg(unix)]
+            {
+                use std::os::unix::fs::OpenOptionsExt;
+                options.mode(0o600);
+            }
+
+            let temp_path = self.path.join("sessions.json.tmp");
+            let file = options.open(&temp_path)?;
+            to_writer(&file, &sessions)?;
+            file.sync_all()?;
+            rename(temp_path, self.path.join("sessions.json"))
+        })();
+
+        if result.is_err() {
+            self.dirty.store(true, Ordering::Relaxed);
+        }
+        result.map(|_| true)
+    }
+}
+
+/*
+ * pub async fn persist_sessions(store: Arc<SessionStore>) {}
+ *
+ * Drops idle sessions and writes the store to sessions.json every few seconds when it changed.
+ */
+
+pub async fn persist_sessions(store: Arc<SessionStore>) {
+    let interval = std::time::Duration::from_secs(5);
+
+    loop {
+        tokio::time::sleep(interval).await;
+        let pruned = store.prune(Utc::now().timestamp());
+        if pruned > 0 {
+            tracing::info!("Dropped {} idle sessions.", pruned);
+        }
+        if let Err(error) = store.flush() {
+            tracing::error!("Failed to write sessions.json: {}", error);
+        }
+    }
+}
+
+/*
+ * pub struct ServerSession {
+ *   name: String,
+ *   secure: bool,
+ *   key: Key,
+ *   store: Arc<SessionStore>,
+ * }
+ *
+ * Middleware that backs ntex_session::Session with the SessionStore, in place of CookieSession.
+ * The cookie only carries a random token, still encrypted with the session key
+ * so SessionKeyRotation keeps working and a stolen sessions.json can't be turned into cookies.
+ */
+
+#[derive(Clone)]
+pub struct ServerSession {
+    name: String,
+    secure: bool,
+    key: Key,
+    store: Arc<SessionStore>,
+}
+
+/*
+ * impl ServerSession {
+ *   pub fn new(name: &str, keys: &SessionKeys, secure: bool, store: Arc<SessionStore>) -> Self {}
+ *   fn token<Err>(&self, req: &WebRequest<Err>) -> Option<String> {}
+ *   fn cookie(&self, token: &str) -> Option<HeaderValue> {}
+ *   fn removal(&self) -> Option<HeaderValue> {}
+ * }
+ *
+ * Assorted implementations accessed through `ServerSession::function(args)`
+ */
+
+impl ServerSession {
+    /*
+     * pub ServerSession::new(name: &str, keys: &SessionKeys, secure: bool, store: Arc<SessionStore>) -> Self {}
+     *
+     * `name` and `secure` have to match what SessionKeyRotation is configured with.
+     */
+
+    pub fn new(name: &str, keys: &SessionKeys, secure: bool, store: Arc<SessionStore>) -> Self {
+        ServerSession {
+            name: name.to_string(),
+            secure,
+            key: Key::derive_from(&keys.current),
+            store,
+        }
+    }
+
+    /*
+     * ServerSession::token<Err>(&self, req: &WebRequest<Err>) -> Option<String> {}
+     *
+     * The decrypted token of the request's session cookie, if it has a valid one.
+     */
+
+    fn token<Err>(&self, req: &WebRequest<Err>) -> Option<String> {
+        let mut jar = CookieJar::new();
+        for value in req.headers().get_all(header::COOKIE) {
+            let Ok(value) = value.to_str() else {
+                continue;
+            };
+            for cookie in Cookie::split_parse(value.to_string()).flatten() {
+                if cookie.name() == self.name {
+                    jar.add_original(cookie.into_owned());
+                }
+            }
+        }
+        jar.private(&self.key)
+            .get(&self.name)
+            .map(|cookie| cookie.value().to_string())
+    }
+
+    /*
+     * ServerSession::cookie(&self, token: &str) -> Option<HeaderValue> {}
+     *
+     * The Set-Cookie value handing out `token`.
+     */
+
+    fn cookie(&self, token: &str) -> Option<HeaderValue> {
+        let mut jar = CookieJar::new();
+        jar.private_mut(&self.key)
+            .add(Cookie::new(self.name.clone(), token.to_string()));
+        let encrypted = jar.get(&self.name)?;
+
+        let cookie = Cookie::build((self.name.clone(), encrypted.value().to_string()))
+            .path("/")
+            .http_only(true)
+            .secure(self.secure)
+            .same_site(SameSite::Lax)
+            .build();
+        HeaderValue::from_str(&cookie.to_string()).ok()
+    }
+
+    /*
+     * ServerSession::removal(&self) -> Option<HeaderValue> {}
+     *
+     * The Set-Cookie value that deletes the session cookie.
+     */
+
+    fn removal(&self) -> Option<HeaderValue> {
+        let cookie = Cookie::build((self.name.clone(), ""))
+            .path("/")
+            .http_only(true)
+            .secure(self.secure)
+            .max_age(CookieDuration::ZERO)
+            .build();
+        HeaderValue::from_str(&cookie.to_string()).ok()
+    }
+}
+
+/*
+ * impl<S> Middleware<S> for ServerSession {}
+ *
+ * Wraps a service so every request gets its session from the store.
+ */
+
+impl<S> Middleware<S> for ServerSession {
+    type Service = ServerSessionMiddleware<S>;
+
+    fn create(&self, service: S) -> Self::Service {
+        ServerSessionMiddleware {
+            service,
+            inner: Rc::new(self.clone()),
+        }
+    }
+}
+
+/*
+ * pub struct ServerSessionMiddleware<S> {
+ *   service: S,
+ *   inner: Rc<ServerSession>,
+ * }
+ *
+ * The service ServerSession creates.
+ */
+
+pub struct ServerSessionMiddleware<S> {
+    service: S,
+    inner: Rc<ServerSession>,
+}
+
+/*
+ * impl<S, Err> Service<WebRequest<Err>> for ServerSessionMiddleware<S> {}
+ *
+ * Loads the session the cookie points at before the request is handled, and afterwards
+ * saves whatever the handler changed. A new or renewed session gets a fresh token,
+ * so a token set before logging in never ends up on a logged in session.
+ */
+
+impl<S, Err> Service<WebRequest<Err>> for ServerSessionMiddleware<S>
+where
+    S: Service<WebRequest<Err>, Response = WebResponse, Error = WebError>,
+{
+    type Response = WebResponse;
+    type Error = WebError;
+
+    ntex::service::forward_poll_ready!(service);
+    ntex::service::forward_poll_shutdown!(service);
+
+    async fn call(
+        &self,
+        req: WebRequest<Err>,
+        ctx: ServiceCtx<'_, Self>,
+    ) -> Result<Self::Response, Self::Error> {
+        let now = Utc::now().timestamp();
+        let ip = match req.extensions().get::<ClientIp>() {
+            Some(ClientIp(ip)) => ip.to_string(),
+            None => req
+                .peer_addr()
+                .map(|address| address.ip().to_string())
+                .unwrap_or_default(),
+        };
+        let user_agent = req
+            .headers()
+            .get(header::USER_AGENT)
+            .and_then(|value| value.to_str().ok())
+            .unwrap_or_default()
+            .to_string();
+
+        let id = self.inner.token(&req).map(|token| session_id(&token));
+        let data = id
+            .as_deref()
+            .and_then(|id| self.inner.store.open(id, now, &ip, &user_agent));
+        let existing = id.filter(|_| data.is_some());
+        Session::set_session(data.unwrap_or_default().into_iter(), &req);
+
+        let mut res = ctx.call(&self.service, req).await?;
+
+        let set_cookie = match (Session::get_changes(&mut res), existing) {
+            ((SessionStatus::Purged, _), Some(id)) => {
+                self.inner.store.remove(&id);
+                self.inner.removal()
+            }
+            ((SessionStatus::Changed, Some(state)), Some(id)) => {
+                if self.inner.store.update(&id, state.collect()) {
+                    None
+                } else {
+                    self.inner.removal()
+                }
+            }
+            ((SessionStatus::Changed | SessionStatus::Renewed, Some(state)), existing) => {
+                let data: HashMap<String, String> = state.collect();
+                if let Some(id) = existing {
+                    self.inner.store.remove(&id);
+                } else if data.is_empty() {
+                    return Ok(res);
+                }
+                let token = new_token();
+                self.inner
+                    .store
+                    .insert(&session_id(&token), data, now, &ip, &user_agent);
+                self.inner.cookie(&token)
+            }
+            _ => None,
+        };
+
+        if let Some(value) = set_cookie {
+            res.headers_mut().append(header::SET_COOKIE, value);
+        }
+        Ok(res)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn data(user: Option<&str>) -> HashMap<String, String> {
+        let mut data = HashMap::new();
+        data.insert(
+            "session_time".to_string(),
+            "\"2024-01-01T00:00:00Z\"".to_string(),
+        );
+        if let Some(user) = user {
+            data.insert("user".to_string(), format!("\"{}\"", user));
+        }
+        data
+    }
+
+    #[test]
+    fn sessions_are_listed_revoked_and_pruned() {
+        let path = std::env::temp_dir().join(format!("sessions-test-{}", std::process::id()));
+        std::fs::create_dir_all(&path).unwrap();
+        let store = SessionStore::new(&path, 3600, HashMap::new());
+
+        store.insert("a", data(Some("admin")), 100, "10.0.0.1", "firefox");
+        store.insert("b", data(Some("viewer")), 200, "10.0.0.2", "curl");
+        store.insert("c", data(None), 300, "10.0.0.3", "phone");
+
+        let list = store.list();
+        assert_eq!(list.len(), 2);
+        assert_eq!(list[0].user, "viewer");
+        assert_eq!(list[1].ip, "10.0.0.1");
+
+        assert!(store.open("a", 500, "10.0.0.9", "firefox").is_some());
+        assert_eq!(store.list()[0].ip, "10.0.0.9");
+
+        assert_eq!(
+            store.remove("b").map(|info| info.user),
+            Some("viewer".to_string())
+        );
+        assert!(store.open("b", 500, "10.0.0.2", "curl").is_none());
+        assert!(!store.update("b", data(Some("viewer"))));
+
+        assert!(store.flush().unwrap());
+        assert!(!store.flush().unwrap());
+        let config = Config {
+            session_retention_hours: 1_000_000,
+            ..Config::default()
+        };
+        let loaded = SessionStore::load(&path, &config).unwrap();
+        assert_eq!(loaded.list().len(), 1);
+
+        assert_eq!(store.prune(3901), 1);
+        assert!(store.open("a", 3901, "10.0.0.1", "firefox").is_some());
+        assert_eq!(store.remove_user("admin"), 1);
+        assert!(store.list().is_empty());
+
+        std::fs::remove_dir_all(path).unwrap();
+    }
+
+    #[test]
+    fn anonymous_sessions_make_room() {
+        let store = SessionStore::new(&std::env::temp_dir(), 3600, HashMap::new());
+
+        store.insert("admin", data(Some("admin")), 0, "10.0.0.1", "firefox");
+        for index in 1..MAX_SESSIONS as i64 {
+            store.insert(&index.to_string(), data(None), index, "10.0.0.2", "phone");
+        }
+        store.insert("new", data(None), MAX_SESSIONS as i64, "10.0.0.3", "phone");
+
+        let sessions = store.sessions.lock().unwrap();
+        assert_eq!(sessions.len(), MAX_SESSIONS);
+        assert!(sessions.contains_key("admin"));
+        assert!(!sessions.contains_key("1"));
+        assert!(sessions.contains_key("new"));
+    }
+}
@@ -0,0 +1,356 @@
+use chrono::prelude::*;
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
+
+/*
+ * const IP_FREE_ATTEMPTS: u32
+ *
+ * Failed logins from one IP before backoff starts. Higher than per user,
+ * since a whole school can sit behind a single address.
+ */
+
+const IP_FREE_ATTEMPTS: u32 = 10;
+
+/*
+ * const USER_FREE_ATTEMPTS: u32
+ *
+ * Failed logins for one username before backoff starts.
+ */
+
+const USER_FREE_ATTEMPTS: u32 = 3;
+
+/*
+ * const MAX_LOCKOUT_SECONDS: i64
+ *
+ * The longest a lockout gets, however many attempts fail.
+ */
+
+const MAX_LOCKOUT_SECONDS: i64 = 15 * 60;
+
+/*
+ * const FORGET_AFTER_SECONDS: i64
+ *
+ * How long after the last failure the attempts are forgotten.
+ */
+
+const FORGET_AFTER_SECONDS: i64 = 60 * 60;
+
+/*
+ * const MAX_TRACKED: usize
+ *
+ * The most IPs and usernames tracked at once, so a flood of made up usernames can't eat the memory.
+ * When it's reached the one that failed longest ago makes room.
+ */
+
+const MAX_TRACKED: usize = 10_000;
+
+/*
+ * struct Attempts {
+ *   failures: u32,
+ *   last_failure: i64,
+ *   locked_until: i64,
+ * }
+ *
+ * Failed logins tracked for a single IP or username, times are unix seconds.
+ */
+
+#[derive(Debug, Default, Clone)]
+struct Attempts {
+    failures: u32,
+    last_failure: i64,
+    locked_until: i64,
+}
+
+/*
+ * impl Attempts {
+ *   fn forgotten(&self, now: i64) -> bool {}
+ * }
+ *
+ * Assorted implementations accessed through `Attempts::function(args)`
+ */
+
+impl Attempts {
+    fn forgotten(&self, now: i64) -> bool {
+        now - self.last_failure >= FORGET_AFTER_SECONDS
+    }
+}
+
+/*
+ * pub enum Subject<'a> {
+ *   Ip(&'a str),
+ *   User(&'a str),
+ * }
+ *
+ * What failed logins are counted against.
+ */
+
+#[derive(Debug, Clone, Copy)]
+pub enum Subject<'a> {
+    Ip(&'a str),
+    User(&'a str),
+}
+
+/*
+ * impl Subject<'_> {
+ *   fn key(&self) -> String {}
+ *   fn free_attempts(&self) -> u32 {}
+ * }
+ *
+ * Assorted implementations accessed through `Subject::function(args)`
+ */
+
+impl Subject<'_> {
+    fn key(&self) -> String {
+        match self {
+            Subject::Ip(ip) => format!("ip:{}", ip),
+            Subject::User(user) => format!("user:{}", user),
+        }
+    }
+
+    fn free_attempts(&self) -> u32 {
+        match self {
+            Subject::Ip(_) => IP_FREE_ATTEMPTS,
+            Subject::User(_) => USER_FREE_ATTEMPTS,
+        }
+    }
+}
+
+/*
+ * pub struct LoginThrottle {
+ *   attempts: Mutex<HashMap<String, Attempts>>,
+ * }
+ *
+ * Slows down password guessing. After the free attempts every failure locks the IP or username
+ * out for twice as long as the one before, starting at a second, up to MAX_LOCKOUT_SECONDS.
+ * A locked out username only turns away IPs that failed logins themselves,
+ * so guessing someone's password from elsewhere can't keep them from logging in.
+ * Kept in memory only, a restart forgets everything, prune_throttle() drops what's forgotten.
+ */
+
+#[derive(Default)]
+pub struct LoginThrottle {
+    attempts: Mutex<HashMap<String, Attempts>>,
+}
+
+/*
+ * impl LoginThrottle {
+ *   pub fn locked(&self, subjects: &[Subject], now: i64) -> Option<i64> {}
+ *   pub fn failed(&self, subjects: &[Subject], now: i64) -> Option<i64> {}
+ *   pub fn succeeded(&self, subject: Subject) {}
+ *   pub fn prune(&self, now: i64) -> usize {}
+ * }
+ *
+ * Assorted implementations accessed through `LoginThrottle::function(args)`
+ */
+
+impl LoginThrottle {
+    /*
+     * pub LoginThrottle::locked(&self, subjects: &[Subject], now: i64) -> Option<i64> {}
+     *
+     * How many seconds are left of the longest lockout among `subjects`, None if none are locked out.
+     * Usernames are skipped when an IP among `subjects` has no failures of its own.
+     */
+
+    pub fn locked(&self, subjects: &[Subject], now: i64) -> Option<i64> {
+        let attempts = self.attempts.lock().expect("Throttle lock poisoned");
+        let failed = |subject: &Subject| matches!(attempts.get(&subject.key()), Some(attempts) if !attempts.forgotten(now));
+        let clean_ip = subjects
+            .iter()
+            .any(|subject| matches!(subject, Subject::Ip(_)) && !failed(subject));
+
+        subjects
+            .iter()
+            .filter(|subject| !(clean_ip && matches!(subject, Subject::User(_))))
+            .filter_map(|subject| attempts.get(&subject.key()))
+            .map(|attempts| attempts.locked_until - now)
+            .filter(|remaining| *remaining > 0)
+            .max()
+    }
+
+    /*
+     * pub LoginThrottle::failed(&self, subjects: &[Subject], now: i64) -> Option<i64> {}
+     *
+     * Counts a failed login against every subject, returning the longest lockout it caused in seconds.
+     * Lockouts are logged as they happen.
+     */
+
+    pub fn failed(&self, subjects: &[Subject], now: i64) -> Option<i64> {
+        let mut attempts = self.attempts.lock().expect("Throttle lock poisoned");
+
+        let mut longest = None;
+        for subject in subjects {
+            let key = subject.key();
+            if !attempts.contains_key(&key) && attempts.len() >= MAX_TRACKED {
+                let oldest = attempts
+                    .iter()
+                    .min_by_key(|(_, attempts)| attempts.last_failure)
+                    .map(|(key, _)| key.clone());
+                if let Some(oldest) = oldest {
+                    attempts.remove(&oldest);
+                }
+            }
+
+            let entry = attempts.entry(key).or_default();
+            if entry.forgotten(now) {
+                *entry = Attempts::default();
+            }
+            entry.failures += 1;
+            entry.last_failure = now;
+
+            if entry.failures > subject.free_attempts() {
+                let doublings = (entry.failures - subject.free_attempts() - 1).min(20);
+                let lockout = (1i64 << doublings).min(MAX_LOCKOUT_SECONDS);
+                entry.locked_until = now + lockout;
+                tracing::warn!(
+                    "Locked out {} for {} seconds after {} failed logins.",
+                    subject.key(),
+                    lockout,
+                    entry.failures
+                );
+                longest = longest.max(Some(lockout));
+            }
+        }
+        longest
+    }
+
+    /*
+     * pub LoginThrottle::succeeded(&self, subject: Subject) {}
+     *
+     * Forgets the failures of a subject after a successful login.
+     * Only used for usernames, so one valid account can't clear an IP that is guessing others.
+     */
+
+    pub fn succeeded(&self, subject: Subject) {
+        self.attempts
+            .lock()
+            .expect("Throttle lock poisoned")
+            .remove(&subject.key());
+    }
+
+    /*
+     * pub LoginThrottle::prune(&self, now: i64) -> usize {}
+     *
+     * Drops the subjects whose failures are forgotten by now, returns how many.
+     */
+
+    pub fn prune(&self, now: i64) -> usize {
+        let mut attempts = self.attempts.lock().expect("Throttle lock poisoned");
+        let before = attempts.len();
+        attempts.retain(|_, attempts| !attempts.forgotten(now));
+        before - attempts.len()
+    }
+}
+
+/*
+ * pub async fn prune_throttle(throttle: Arc<LoginThrottle>) {}
+ *
+ * Drops forgotten failures every minute, so the throttle only holds what still counts.
+ */
+
+pub async fn prune_throttle(throttle: Arc<LoginThrottle>) {
+    let interval = std::time::Duration::from_secs(60);
+
+    loop {
+        tokio::time::sleep(interval).await;
+        throttle.prune(Utc::now().timestamp());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn backoff_doubles_after_free_attempts() {
+        let throttle = LoginThrottle::default();
+        let subjects = [Subject::User("teacher")];
+        let now = 1_000;
+
+        for _ in 0..USER_FREE_ATTEMPTS {
+            assert_eq!(throttle.failed(&subjects, now), None);
+        }
+        assert_eq!(throttle.locked(&subjects, now), None);
+
+        assert_eq!(throttle.failed(&subjects, now), Some(1));
+        assert_eq!(throttle.failed(&subjects, now), Some(2));
+        assert_eq!(throttle.failed(&subjects, now), Some(4));
+        assert_eq!(throttle.locked(&subjects, now), Some(4));
+        assert_eq!(throttle.locked(&subjects, now + 4), None);
+
+        for _ in 0..30 {
+            throttle.failed(&subjects, now);
+        }
+        assert_eq!(throttle.locked(&subjects, now), Some(MAX_LOCKOUT_SECONDS));
+    }
+
+    #[test]
+    fn success_and_time_forget_failures() {
+        let throttle = LoginThrottle::default();
+        let user = [Subject::User("teacher")];
+
+        for _ in 0..USER_FREE_ATTEMPTS {
+            throttle.failed(&user, 0);
+        }
+        throttle.succeeded(Subject::User("teacher"));
+        assert_eq!(throttle.failed(&user, 0), None);
+
+        for _ in 0..USER_FREE_ATTEMPTS {
+            throttle.failed(&user, 0);
+        }
+        assert_eq!(throttle.failed(&user, FORGET_AFTER_SECONDS), None);
+    }
+
+    #[test]
+    fn ip_and_user_are_tracked_separately() {
+        let throttle = LoginThrottle::default();
+        let now = 0;
+
+        for user in 0..IP_FREE_ATTEMPTS {
+            let user = user.to_string();
+            throttle.failed(&[Subject::Ip("10.0.0.1"), Subject::User(&user)], now);
+        }
+        assert_eq!(throttle.locked(&[Subject::Ip("10.0.0.1")], now), None);
+        assert_eq!(
+            throttle.failed(&[Subject::Ip("10.0.0.1"), Subject::User("new")], now),
+            Some(1)
+        );
+        assert_eq!(throttle.locked(&[Subject::Ip("10.0.0.2")], now), None);
+    }
+
+    #[test]
+    fn locked_username_only_stops_failing_ips() {
+        let throttle = LoginThrottle::default();
+        let attacker = [Subject::Ip("10.0.0.1"), Subject::User("teacher")];
+        let owner = [Subject::Ip("10.0.0.2"), Subject::User("teacher")];
+
+        for _ in 0..=USER_FREE_ATTEMPTS {
+            throttle.failed(&attacker, 0);
+        }
+        assert_eq!(throttle.locked(&attacker, 0), Some(1));
+        assert_eq!(throttle.locked(&owner, 0), None);
+
+        throttle.failed(&owner, 0);
+        assert!(throttle.locked(&owner, 0).is_some());
+    }
+
+    #[test]
+    fn tracking_is_capped_and_pruned() {
+        let throttle = LoginThrottle::default();
+
+        for user in 0..MAX_TRACKED {
+            throttle.failed(&[Subject::User(&user.to_string())], user as i64);
+        }
+        throttle.failed(&[Subject::User("new")], MAX_TRACKED as i64);
+        let attempts = throttle.attempts.lock().unwrap();
+        assert_eq!(attempts.len(), MAX_TRACKED);
+        assert!(!attempts.contains_key("user:0"));
+        assert!(attempts.contains_key("user:new"));
+        drop(attempts);
+
+        let now = MAX_TRACKED as i64 - 1 + FORGET_AFTER_SECONDS;
+        assert_eq!(throttle.prune(now), MAX_TRACKED - 1);
+        assert_eq!(throttle.attempts.lock().unwrap().len(), 1);
+    }
+}
@@ -0,0 +1,516 @@
+use super::keys::load_or_create_key;
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use jsonwebtoken::{decode, encode, Algorithm, DecodingKey, EncodingKey, Header, Validation};
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use serde_json::{from_reader, to_writer};
+use sha2::{Digest, Sha256};
+use std::{
+    fs::{rename, File, OpenOptions},
+    io::{Error, ErrorKind},
+    path::{Path, PathBuf},
+    sync::{RwLock, RwLockReadGuard},
+};
+
+/*
+ * const KEY_PREFIX: &str
+ *
+ * Every API key starts with this, so they're easy to recognise in scripts and secret scanners.
+ */
+
+const KEY_PREFIX: &str = "qra";
+
+/*
+ * const ISSUER: &str
+ *
+ * The iss and aud of our JWTs, tokens from anywhere else are refused.
+ */
+
+const ISSUER: &str = "qrcode-analytic";
+
+/*
+ * pub const ACCESS_TOKEN_SECONDS: i64
+ *
+ * How long a JWT from /api/token is valid for, never past the expiry of the key it came from.
+ */
+
+pub const ACCESS_TOKEN_SECONDS: i64 = 15 * 60;
+
+/*
+ * pub const MAX_KEY_DAYS: i64
+ *
+ * The furthest out an API key's expiry can be set, ten years.
+ */
+
+pub const MAX_KEY_DAYS: i64 = 10 * 366;
+
+/*
+ * pub enum Scope {
+ *   DataRead,
+ *   AuditRead,
+ * }
+ *
+ * What an API key is allowed to read.
+ * data:read is /api/get_data, audit:read is /api/admin/audit.
+ */
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum Scope {
+    #[serde(rename = "data:read")]
+    DataRead,
+    #[serde(rename = "audit:read")]
+    AuditRead,
+}
+
+/*
+ * pub struct ApiKey {
+ *   pub id: String,
+ *   pub name: String,
+ *   pub scopes: Vec<Scope>,
+ *   pub created: i64,
+ *   pub created_by: String,
+ *   pub expires: Option<i64>,
+ *   pub last_used: Option<i64>,
+ *   pub revoked: Option<i64>,
+ *   secret_hash: String,
+ * }
+ *
+ * A named API key as stored in state/api_keys.json, times are unix seconds.
+ * The key itself is "qra_<id>_<secret>", only the SHA-256 of the secret is kept.
+ * Revoked keys stay in the file so it's clear what they were.
+ */
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ApiKey {
+    pub id: String,
+    pub name: String,
+    pub scopes: Vec<Scope>,
+    pub created: i64,
+    pub created_by: String,
+    pub expires: Option<i64>,
+    #[serde(default)]
+    pub last_used: Option<i64>,
+    #[serde(default)]
+    pub revoked: Option<i64>,
+    #[serde(skip)]
+    secret_hash: String,
+}
+
+/*
+ * struct StoredApiKey {
+ *   key: ApiKey,
+ *   secret_hash: String,
+ * }
+ *
+ * ApiKey as written to disk, ApiKey itself never serializes its hash so it can be sent to admins as is.
+ */
+
+#[derive(Serialize, Deserialize)]
+struct StoredApiKey {
+    #[serde(flatten)]
+    key: ApiKey,
+    secret_hash: String,
+}
+
+/*
+ * pub struct Claims {
+ *   pub sub: String,
+ *   pub name: String,
+ *   pub scope: Vec<Scope>,
+ *   pub iss: String,
+ *   pub aud: String,
+ *   pub iat: i64,
+ *   pub exp: i64,
+ * }
+ *
+ * The claims of the JWTs handed out for API keys, sub is the key's id.
+ */
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Claims {
+    pub sub: String,
+    pub name: String,
+    pub scope: Vec<Scope>,
+    pub iss: String,
+    pub aud: String,
+    pub iat: i64,
+    pub exp: i64,
+}
+
+/*
+ * fn hash_secret(secret: &str) -> String {}
+ *
+ * The SHA-256 of a key's secret, hex encoded. The secrets are random, a slow hash buys nothing.
+ */
+
+fn hash_secret(secret: &str) -> String {
+    hex::encode(Sha256::digest(secret.as_bytes()))
+}
+
+/*
+ * fn same(a: &[u8], b: &[u8]) -> bool {}
+ *
+ * Compares two hashes without bailing out at the first difference.
+ */
+
+fn same(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (a, b)| diff | (a ^ b)) == 0
+}
+
+/*
+ * impl ApiKey {
+ *   pub fn is_active(&self, now: i64) -> bool {}
+ * }
+ *
+ * Assorted implementations accessed through `ApiKey::function(args)`
+ */
+
+impl ApiKey {
+    /*
+     * pub ApiKey::is_active(&self, now: i64) -> bool {}
+     *
+     * Whether the key is neither revoked nor expired.
+     */
+
+    pub fn is_active(&self, now: i64) -> bool {
+        self.revoked.is_none() && !matches!(self.expires, Some(expires) if expires <= now)
+    }
+}
+
+/*
+ * pub struct ApiKeys {
+ *   path: PathBuf,
+ *   signing_key: Vec<u8>,
+ *   keys: RwLock<Vec<ApiKey>>,
+ * }
+ *
+ * The API keys, kept in memory and written to state/api_keys.json on every change.
+ * JWTs are HS256 signed with state/keys/jwt.key, rotating that file invalidates every token out there.
+ */
+
+pub struct ApiKeys {
+    path: PathBuf,
+    signing_key: Vec<u8>,
+    keys: RwLock<Vec<ApiKey>>,
+}
+
+/*
+ * impl ApiKeys {
+ *   pub fn load(path: &Path) -> Result<Self, Error> {}
+ *   pub fn new(path: &Path, signing_key: Vec<u8>, keys: Vec<ApiKey>) -> Self {}
+ *   fn save(&self, keys: &[ApiKey]) -> Result<(), Error> {}
+ *   pub fn list(&self) -> RwLockReadGuard<'_, Vec<ApiKey>> {}
+ *   pub fn create(&self, name: String, scopes: Vec<Scope>, created_by: String, expires: Option<i64>, now: i64) -> Result<(ApiKey, String), Error> {}
+ *   pub fn revoke(&self, id: &str, now: i64) -> Result<Option<ApiKey>, Error> {}
+ *   pub fn exchange(&self, key: &str, now: i64) -> Result<Option<(String, i64)>, Error> {}
+ *   pub fn verify(&self, token: &str, scope: Scope, now: i64) -> Option<ApiKey> {}
+ * }
+ *
+ * Assorted implementations accessed through `ApiKeys::function(args)`
+ */
+
+impl ApiKeys {
+    /*
+     * pub ApiKeys::load(path: &Path) -> Result<Self, Error> {}
+     *
+     * Reads state/api_keys.json if there is one, and the JWT signing key, generating it on first start.
+     */
+
+    pub fn load(path: &Path) -> Result<Self, Error> {
+        let file_path = path.join("api_keys.json");
+        let keys = if file_path.is_file() {
+            let stored: Vec<StoredApiKey> = from_reader(File::open(file_path)?)?;
+            stored
+                .into_iter()
+                .map(|stored| ApiKey {
+                    secret_hash: stored.secret_hash,
+                    ..stored.key
+                })
+                .collect()
+        } else {
+            Vec::new()
+        };
+
+        Ok(Self::new(path, load_or_create_key(path, "jwt")?, keys))
+    }
+
+    /*
+     * pub ApiKeys::new(path: &Path, signing_key: Vec<u8>, keys: Vec<ApiKey>) -> Self {}
+     *
+     * Builds ApiKeys around an existing signing key and set of keys, stored in `path`.
+     */
+
+    pub fn new(path: &Path, signing_key: Vec<u8>, keys: Vec<ApiKey>) -> Self {
+        ApiKeys {
+            path: path.to_path_buf(),
+            signing_key,
+            keys: RwLock::new(keys),
+        }
+    }
+
+    /*
+     * ApiKeys::save(&self, keys: &[ApiKey]) -> Result<(), Error> {}
+     *
+     * Writes the keys to api_keys.json, only readable by the owner on unix.
+     * Goes through a temporary file so a crash can't leave it empty or half written.
+     */
+
+    fn save(&self, keys: &[ApiKey]) -> Result<(), Error> {
+        let stored: Vec<StoredApiKey> = keys
+            .iter()
+            .map(|key| StoredApiKey {
+                key: key.clone(),
+                secret_hash: key.secret_hash.clone(),
+            })
+            .collect();
+
+        let mut options = OpenOptions::new();
+        options.write(true).create(true).truncate(true);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::OpenOptionsExt;
+            options.mode(0o600);
+        }
+
+        let file_path = self.path.join("api_keys.json");
+        let temp_path = file_path.with_extension("json.tmp");
+        let file = options.open(&temp_path)?;
+        to_writer(&file, &stored)?;
+        file.sync_all()?;
+        rename(temp_path, file_path)
+    }
+
+    /*
+     * pub ApiKeys::list(&self) -> RwLockReadGuard<'_, Vec<ApiKey>> {}
+     *
+     * Every key, revoked ones included.
+     */
+
+    pub fn list(&self) -> RwLockReadGuard<'_, Vec<ApiKey>> {
+        self.keys.read().expect("API key lock poisoned")
+    }
+
+    /*
+     * pub ApiKeys::create(&self, name: String, scopes: Vec<Scope>, created_by: String, expires: Option<i64>, now: i64) -> Result<(ApiKey, String), Error> {}
+     *
+     * Creates a key and returns it along with the full key string, the only time that is known.
+     */
+
+    pub fn create(
+        &self,
+        name: String,
+        scopes: Vec<Scope>,
+        created_by: String,
+        expires: Option<i64>,
+        now: i64,
+    ) -> Result<(ApiKey, String), Error> {
+        let mut id = [0; 8];
+        let mut secret = [0; 32];
+        rand::thread_rng().fill_bytes(&mut id);
+        rand::thread_rng().fill_bytes(&mut secret);
+        let id = hex::encode(id);
+        let secret = URL_SAFE_NO_PAD.encode(secret);
+
+        let key = ApiKey {
+            id: id.clone(),
+            name,
+            scopes,
+            created: now,
+            created_by,
+            expires,
+            last_used: None,
+            revoked: None,
+            secret_hash: hash_secret(&secret),
+        };
+
+        let mut keys = self.keys.write().expect("API key lock poisoned");
+        let mut updated = keys.clone();
+        updated.push(key.clone());
+        self.save(&updated)?;
+        *keys = updated;
+
+        Ok((key, format!("{}_{}_{}", KEY_PREFIX, id, secret)))
+    }
+
+    /*
+     * pub ApiKeys::revoke(&self, id: &str, now: i64) -> Result<Option<ApiKey>, Error> {}
+     *
+     * Revokes a key, JWTs it was exchanged for stop working right away. None if there's no such key.
+     */
+
+    pub fn revoke(&self, id: &str, now: i64) -> Result<Option<ApiKey>, Error> {
+        let mut keys = self.keys.write().expect("API key lock poisoned");
+        let mut updated = keys.clone();
+        let Some(key) = updated.iter_mut().find(|key| key.id == id) else {
+            return Ok(None);
+        };
+        key.revoked.get_or_insert(now);
+        let key = key.clone();
+
+        self.save(&updated)?;
+        *keys = updated;
+        Ok(Some(key))
+    }
+
+    /*
+     * pub ApiKeys::exchange(&self, key: &str, now: i64) -> Result<Option<(String, i64)>, Error> {}
+     *
+     * Trades an active API key for a signed JWT, returning it with its lifetime in seconds.
+     * None if the key is unknown, wrong, revoked or expired.
+     */
+
+    pub fn exchange(&self, key: &str, now: i64) -> Result<Option<(String, i64)>, Error> {
+        let Some((id, secret)) = key
+            .trim()
+            .strip_prefix(KEY_PREFIX)
+            .and_then(|rest| rest.strip_prefix('_'))
+            .and_then(|rest| rest.split_once('_'))
+        else {
+            return Ok(None);
+        };
+
+        let mut keys = self.keys.write().expect("API key lock poisoned");
+        let mut updated = keys.clone();
+        let Some(api_key) = updated.iter_mut().find(|api_key| api_key.id == id) else {
+            return Ok(None);
+        };
+        if !same(
+            hash_secret(secret).as_bytes(),
+            api_key.secret_hash.as_bytes(),
+        ) || !api_key.is_active(now)
+        {
+            return Ok(None);
+        }
+
+        let exp = match api_key.expires {
+            Some(expires) => expires.min(now + ACCESS_TOKEN_SECONDS),
+            None => now + ACCESS_TOKEN_SECONDS,
+        };
+        let claims = Claims {
+            sub: api_key.id.clone(),
+            name: api_key.name.clone(),
+            scope: api_key.scopes.clone(),
+            iss: ISSUER.to_string(),
+            aud: ISSUER.to_string(),
+            iat: now,
+            exp,
+        };
+        let token = encode(
+            &Header::new(Algorithm::HS256),
+            &claims,
+            &EncodingKey::from_secret(&self.signing_key),
+        )
+        .map_err(|error| Error::new(ErrorKind::InvalidData, error))?;
+
+        api_key.last_used = Some(now);
+        self.save(&updated)?;
+        *keys = updated;
+        Ok(Some((token, exp - now)))
+    }
+
+    /*
+     * pub ApiKeys::verify(&self, token: &str, scope: Scope, now: i64) -> Option<ApiKey> {}
+     *
+     * Checks a JWT from an Authorization header: our signature, issuer and audience, not expired,
+     * carrying `scope`, and from a key that is still active.
+     */
+
+    pub fn verify(&self, token: &str, scope: Scope, now: i64) -> Option<ApiKey> {
+        let mut validation = Validation::new(Algorithm::HS256);
+        validation.set_issuer(&[ISSUER]);
+        validation.set_audience(&[ISSUER]);
+        validation.set_required_spec_claims(&["exp", "iss", "aud", "sub"]);
+        validation.leeway = 0;
+
+        let claims = decode::<Claims>(
+            token,
+            &DecodingKey::from_secret(&self.signing_key),
+            &validation,
+        )
+        .ok()?
+        .claims;
+        if claims.exp <= now || !claims.scope.contains(&scope) {
+            return None;
+        }
+
+        self.list()
+            .iter()
+            .find(|key| key.id == claims.sub)
+            .filter(|key| key.is_active(now) && key.scopes.contains(&scope))
+            .cloned()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn api_keys() -> ApiKeys {
+        let path = std::env::temp_dir().join(format!("api-keys-test-{}", rand::random::<u64>()));
+        std::fs::create_dir_all(&path).unwrap();
+        ApiKeys::new(&path, vec![7; 64], Vec::new())
+    }
+
+    #[test]
+    fn keys_exchange_for_scoped_tokens() {
+        let api_keys = api_keys();
+        let now = chrono::Utc::now().timestamp();
+        let (api_key, secret) = api_keys
+            .create(
+                "info screen".to_string(),
+                vec![Scope::DataRead],
+                "admin".to_string(),
+                None,
+                now,
+            )
+            .unwrap();
+
+        assert!(api_keys.exchange("qra_nope_nope", now).unwrap().is_none());
+        assert!(api_keys
+            .exchange(&format!("qra_{}_wrong", api_key.id), now)
+            .unwrap()
+            .is_none());
+
+        let (token, expires_in) = api_keys.exchange(&secret, now).unwrap().unwrap();
+        assert_eq!(expires_in, ACCESS_TOKEN_SECONDS);
+        assert!(api_keys.verify(&token, Scope::DataRead, now).is_some());
+        assert!(api_keys.verify(&token, Scope::AuditRead, now).is_none());
+        assert!(api_keys
+            .verify(&token, Scope::DataRead, now + ACCESS_TOKEN_SECONDS)
+            .is_none());
+        assert!(!serde_json::to_string(&api_key)
+            .unwrap()
+            .contains(&api_key.secret_hash));
+
+        std::fs::remove_dir_all(&api_keys.path).unwrap();
+    }
+
+    #[test]
+    fn revoked_and_expired_keys_stop_working() {
+        let api_keys = api_keys();
+        let now = chrono::Utc::now().timestamp();
+        let (api_key, secret) = api_keys
+            .create(
+                "script".to_string(),
+                vec![Scope::DataRead],
+                "admin".to_string(),
+                Some(now + 60),
+                now,
+            )
+            .unwrap();
+
+        let (token, expires_in) = api_keys.exchange(&secret, now).unwrap().unwrap();
+        assert_eq!(expires_in, 60);
+        assert!(api_keys.exchange(&secret, now + 60).unwrap().is_none());
+
+        api_keys.revoke(&api_key.id, now).unwrap();
+        assert!(api_keys.verify(&token, Scope::DataRead, now).is_none());
+        assert!(api_keys.exchange(&secret, now).unwrap().is_none());
+
+        let reloaded = ApiKeys::load(&api_keys.path).unwrap();
+        assert_eq!(reloaded.list()[0].revoked, Some(now));
+        assert_eq!(reloaded.list()[0].secret_hash, api_key.secret_hash);
+
+        std::fs::remove_dir_all(&api_keys.path).unwrap();
+    }
+}
@@ -0,0 +1,251 @@
+use rand::{Rng, RngCore};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use totp_rs::{Algorithm, Secret, TOTP};
+
+/*
+ * const ISSUER: &str
+ *
+ * The name authenticator apps list the account under.
+ */
+
+const ISSUER: &str = "qrcode-analytic";
+
+/*
+ * const STEP: u64
+ *
+ * How many seconds a code is valid for, 30 is what every authenticator app expects.
+ */
+
+const STEP: u64 = 30;
+
+/*
+ * const RECOVERY_CODES: usize
+ *
+ * How many recovery codes are handed out when two-factor authentication is turned on.
+ */
+
+const RECOVERY_CODES: usize = 10;
+
+/*
+ * pub struct SecondFactor {
+ *   pub secret: String,
+ *   pub confirmed: bool,
+ *   pub recovery_codes: Vec<String>,
+ *   pub last_step: u64,
+ * }
+ *
+ * An account's TOTP second factor, stored with the account in admin_login.json.
+ * secret is base32 encoded, it only protects logins once a code has confirmed the app is set up.
+ * Recovery codes are stored as SHA-256 hashes and each one works once.
+ * last_step is the time step of the last accepted code, so a code can't be used twice.
+ */
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SecondFactor {
+    pub secret: String,
+    #[serde(default)]
+    pub confirmed: bool,
+    #[serde(default)]
+    pub recovery_codes: Vec<String>,
+    #[serde(default)]
+    pub last_step: u64,
+}
+
+/*
+ * fn hash_code(code: &str) -> String {}
+ *
+ * Hashes a recovery code, ignoring case, spaces and dashes so it can be typed however.
+ */
+
+fn hash_code(code: &str) -> String {
+    let normalized: String = code
+        .chars()
+        .filter(|c| c.is_ascii_alphanumeric())
+        .map(|c| c.to_ascii_lowercase())
+        .collect();
+    hex::encode(Sha256::digest(normalized.as_bytes()))
+}
+
+/*
+ * fn same(a: &[u8], b: &[u8]) -> bool {}
+ *
+ * Compares two codes without bailing out at the first difference.
+ */
+
+fn same(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (a, b)| diff | (a ^ b)) == 0
+}
+
+/*
+ * impl SecondFactor {
+ *   pub fn generate() -> Self {}
+ *   fn totp(&self, username: &str) -> Option<TOTP> {}
+ *   pub fn uri(&self, username: &str) -> Option<String> {}
+ *   pub fn verify(&mut self, username: &str, code: &str, now: u64) -> bool {}
+ *   pub fn generate_recovery_codes(&mut self) -> Vec<String> {}
+ *   pub fn use_recovery_code(&mut self, code: &str) -> bool {}
+ * }
+ *
+ * Assorted implementations accessed through `SecondFactor::function(args)`
+ */
+
+impl SecondFactor {
+    /*
+     * pub SecondFactor::generate() -> Self {}
+     *
+     * A new, unconfirmed second factor with a random 160 bit secret.
+     */
+
+    pub fn generate() -> Self {
+        let mut secret = vec![0; 20];
+        rand::thread_rng().fill_bytes(&mut secret);
+        let secret = match Secret::Raw(secret).to_encoded() {
+            Secret::Encoded(secret) => secret,
+            Secret::Raw(_) => unreachable!("to_encoded always encodes"),
+        };
+
+        SecondFactor {
+            secret,
+            confirmed: false,
+            recovery_codes: Vec::new(),
+            last_step: 0,
+        }
+    }
+
+    /*
+     * SecondFactor::totp(&self, username: &str) -> Option<TOTP> {}
+     *
+     * The TOTP generator for this secret, None if the stored secret isn't valid base32.
+     * Colons aren't allowed in the account name of a provisioning URI, so they become underscores.
+     */
+
+    fn totp(&self, username: &str) -> Option<TOTP> {
+        let secret = Secret::Encoded(self.secret.clone()).to_bytes().ok()?;
+        Some(TOTP::new_unchecked(
+            Algorithm::SHA1,
+            6,
+            1,
+            STEP,
+            secret,
+            Some(ISSUER.to_string()),
+            username.replace(':', "_"),
+        ))
+    }
+
+    /*
+     * pub SecondFactor::uri(&self, username: &str) -> Option<String> {}
+     *
+     * The otpauth:// provisioning URI, to show as a QR code for the authenticator app to scan.
+     */
+
+    pub fn uri(&self, username: &str) -> Option<String> {
+        self.totp(username).map(|totp| totp.get_url())
+    }
+
+    /*
+     * pub SecondFactor::verify(&mut self, username: &str, code: &str, now: u64) -> bool {}
+     *
+     * Checks a code for the time step of `now` (unix seconds) and the ones right before and after it,
+     * to allow for clock drift. Codes from a step at or before the last accepted one are refused.
+     */
+
+    pub fn verify(&mut self, username: &str, code: &str, now: u64) -> bool {
+        let Some(totp) = self.totp(username) else {
+            return false;
+        };
+        let code = code.trim();
+        let current = now / STEP;
+
+        for step in current.saturating_sub(1)..=current + 1 {
+            if step <= self.last_step {
+                continue;
+            }
+            if same(totp.generate(step * STEP).as_bytes(), code.as_bytes()) {
+                self.last_step = step;
+                return true;
+            }
+        }
+        false
+    }
+
+    /*
+     * pub SecondFactor::generate_recovery_codes(&mut self) -> Vec<String> {}
+     *
+     * Replaces the recovery codes with new ones and returns them in plaintext, the only time they're known.
+     */
+
+    pub fn generate_recovery_codes(&mut self) -> Vec<String> {
+        const ALPHABET: &[u8] = b"abcdefghjkmnpqrstuvwxyz23456789";
+        let mut rng = rand::thread_rng();
+
+        let codes: Vec<String> = (0..RECOVERY_CODES)
+            .map(|_| {
+                let code: String = (0..10)
+                    .map(|_| ALPHABET[rng.gen_range(0..ALPHABET.len())] as char)
+                    .collect();
+                format!("{}-{}", &code[..5], &code[5..])
+            })
+            .collect();
+
+        self.recovery_codes = codes.iter().map(|code| hash_code(code)).collect();
+        codes
+    }
+
+    /*
+     * pub SecondFactor::use_recovery_code(&mut self, code: &str) -> bool {}
+     *
+     * Accepts a recovery code and removes it so it can't be used again.
+     */
+
+    pub fn use_recovery_code(&mut self, code: &str) -> bool {
+        let hashed = hash_code(code);
+        match self
+            .recovery_codes
+            .iter()
+            .position(|stored| same(stored.as_bytes(), hashed.as_bytes()))
+        {
+            Some(index) => {
+                self.recovery_codes.remove(index);
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn codes_verify_once() {
+        let mut second_factor = SecondFactor::generate();
+        let now = 1_700_000_000;
+        let code = second_factor.totp("teacher").unwrap().generate(now);
+
+        assert!(!second_factor.verify("teacher", "000000x", now));
+        assert!(second_factor.verify("teacher", &code, now));
+        assert!(!second_factor.verify("teacher", &code, now));
+    }
+
+    #[test]
+    fn recovery_codes_work_once() {
+        let mut second_factor = SecondFactor::generate();
+        let codes = second_factor.generate_recovery_codes();
+        assert_eq!(codes.len(), RECOVERY_CODES);
+
+        assert!(second_factor.use_recovery_code(&codes[0].to_uppercase()));
+        assert!(!second_factor.use_recovery_code(&codes[0]));
+        assert!(second_factor.use_recovery_code(&codes[1].replace('-', "")));
+        assert!(!second_factor.use_recovery_code("aaaaa-aaaaa"));
+    }
+
+    #[test]
+    fn uri_names_the_account() {
+        let second_factor = SecondFactor::generate();
+        let uri = second_factor.uri("a:b").unwrap();
+        assert!(uri.starts_with("otpauth://totp/qrcode-analytic:a_b?"));
+        assert!(uri.contains(&second_factor.secret));
+    }
+}
@@ -0,0 +1,1067 @@
+use super::{
+    api::{authorize, client_ip, Access, Response},
+    audit::AuditLog,
+    creds::{generate_password, Accounts, Auth, Login, Role, MIN_PASSWORD_LENGTH},
+    hashing::hash,
+    sessions::SessionStore,
+    tokens::{ApiKey, ApiKeys, Scope, MAX_KEY_DAYS},
+    totp::SecondFactor,
+};
+use chrono::prelude::*;
+use ntex::{
+    http::ResponseBuilder,
+    web::{
+        delete, get, post, put,
+        types::{Json, Path, State},
+        Error as WebError, HttpRequest, HttpResponse,
+    },
+};
+use ntex_session::Session;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::sync::Arc;
+
+/*
+ * struct UserInfo {
+ *   username: String,
+ *   role: Role,
+ *   external: bool,
+ *   email: Option<String>,
+ * }
+ *
+ * What the user endpoints show of an account, passwords never leave the server.
+ * external accounts log in through the identity provider.
+ */
+
+#[derive(Serialize)]
+struct UserInfo {
+    username: String,
+    role: Role,
+    external: bool,
+    email: Option<String>,
+}
+
+/*
+ * struct CreatedUser {
+ *   username: String,
+ *   role: Role,
+ *   password: Option<String>,
+ * }
+ *
+ * Response for a newly created account, password is only set when it was generated for you.
+ */
+
+#[derive(Serialize)]
+struct CreatedUser {
+    username: String,
+    role: Role,
+    password: Option<String>,
+}
+
+/*
+ * struct NewUser {
+ *   username: String,
+ *   role: Role,
+ *   password: Option<String>,
+ *   email: Option<String>,
+ * }
+ *
+ * The JSON request data for creating an account, leave out password to have one generated.
+ * email is optional, without one only an admin can reset the password.
+ */
+
+#[derive(Deserialize)]
+struct NewUser {
+    username: String,
+    role: Role,
+    password: Option<String>,
+    email: Option<String>,
+}
+
+/*
+ * struct UserUpdate {
+ *   role: Option<Role>,
+ *   password: Option<String>,
+ *   email: Option<String>,
+ * }
+ *
+ * The JSON request data for changing an account, only the provided fields change.
+ * An empty email removes the address.
+ */
+
+#[derive(Deserialize)]
+struct UserUpdate {
+    role: Option<Role>,
+    password: Option<String>,
+    email: Option<String>,
+}
+
+/*
+ * struct PasswordChange {
+ *   current_password: String,
+ *   new_password: String,
+ * }
+ *
+ * The JSON request data for changing your own password.
+ */
+
+#[derive(Deserialize)]
+struct PasswordChange {
+    current_password: String,
+    new_password: String,
+}
+
+/*
+ * struct Enrollment {
+ *   secret: String,
+ *   uri: String,
+ * }
+ *
+ * Response for starting two-factor enrollment, the uri is meant to be shown as a QR code.
+ */
+
+#[derive(Serialize)]
+struct Enrollment {
+    secret: String,
+    uri: String,
+}
+
+/*
+ * struct EnrollmentConfirmation {
+ *   code: String,
+ * }
+ *
+ * The JSON request data for confirming enrollment with a code from the authenticator app.
+ */
+
+#[derive(Deserialize)]
+struct EnrollmentConfirmation {
+    code: String,
+}
+
+/*
+ * struct RecoveryCodes {
+ *   recovery_codes: Vec<String>,
+ * }
+ *
+ * Response for a confirmed enrollment, the only time the recovery codes are shown.
+ */
+
+#[derive(Serialize)]
+struct RecoveryCodes {
+    recovery_codes: Vec<String>,
+}
+
+/*
+ * struct NewApiKey {
+ *   name: String,
+ *   scopes: Vec<Scope>,
+ *   expires_in_days: Option<i64>,
+ * }
+ *
+ * The JSON request data for creating an API key, leave out expires_in_days for a key that doesn't expire.
+ * expires_in_days goes up to MAX_KEY_DAYS.
+ */
+
+#[derive(Deserialize)]
+struct NewApiKey {
+    name: String,
+    scopes: Vec<Scope>,
+    expires_in_days: Option<i64>,
+}
+
+/*
+ * struct CreatedApiKey {
+ *   key: ApiKey,
+ *   api_key: String,
+ * }
+ *
+ * Response for a newly created API key, the only time api_key is shown.
+ */
+
+#[derive(Serialize)]
+struct CreatedApiKey {
+    #[serde(flatten)]
+    key: ApiKey,
+    api_key: String,
+}
+
+/*
+ * fn check_email(accounts: &Accounts, username: &str, email: &str) -> Result<Option<String>, HttpResponse> {}
+ *
+ * Validates an email address for `username`'s account, None for an empty one.
+ * Addresses are unique, a reset link has to go to exactly one account.
+ */
+
+fn check_email(
+    accounts: &Accounts,
+    username: &str,
+    email: &str,
+) -> Result<Option<String>, HttpResponse> {
+    let email = email.trim();
+    if email.is_empty() {
+        return Ok(None);
+    }
+
+    let valid = email.len() <= 254
+        && !email.chars().any(char::is_whitespace)
+        && matches!(email.split_once('@'), Some((local, domain))
+            if !local.is_empty() && domain.contains('.') && !domain.contains('@'));
+    if !valid {
+        return Err(respond(
+            HttpResponse::BadRequest(),
+            "Bad Request",
+            "That isn't an email address.",
+        ));
+    }
+    if matches!(accounts.find_by_email(email), Some(login) if login.username != username) {
+        return Err(respond(
+            HttpResponse::Conflict(),
+            "Conflict",
+            "Another account already uses that email address.",
+        ));
+    }
+    Ok(Some(email.to_string()))
+}
+
+/*
+ * pub fn respond(response: ResponseBuilder, title: &str, message: &str) -> HttpResponse {}
+ *
+ * Shorthand for answering with the default Response shape.
+ */
+
+pub fn respond(mut response: ResponseBuilder, title: &str, message: &str) -> HttpResponse {
+    response.content_type("application/json").json(&Response {
+        title: title.to_string(),
+        message: message.to_string(),
+    })
+}
+
+/*
+ * https://url.tld/api/admin/users
+ *
+ * Lists every account and its role.
+ */
+
+#[get("/api/admin/users")]
+pub async fn list_users(
+    session: Session,
+    auth: State<Arc<Auth>>,
+) -> Result<HttpResponse, WebError> {
+    if let Access::Denied(response) = authorize(&session, &auth, Role::Admin)? {
+        return Ok(response);
+    }
+
+    let users: Vec<UserInfo> = auth
+        .accounts()
+        .users
+        .iter()
+        .map(|login| UserInfo {
+            username: login.username.clone(),
+            role: login.role,
+            external: login.external,
+            email: login.email.clone(),
+        })
+        .collect();
+
+    Ok(HttpResponse::Ok()
+        .content_type("application/json")
+        .json(&users))
+}
+
+/*
+ * https://url.tld/api/admin/users - POST
+ *
+ * Creates an account, generating a password when none is provided.
+ * A provided one has to be at least MIN_PASSWORD_LENGTH characters, like everywhere else.
+ */
+
+#[post("/api/admin/users")]
+pub async fn create_user(
+    req: HttpRequest,
+    session: Session,
+    auth: State<Arc<Auth>>,
+    audit: State<Arc<AuditLog>>,
+    json: Json<NewUser>,
+) -> Result<HttpResponse, WebError> {
+    let admin = match authorize(&session, &auth, Role::Admin)? {
+        Access::Granted(login) => login,
+        Access::Denied(response) => return Ok(response),
+    };
+
+    let username = json.username.trim().to_string();
+    if username.is_empty() || username.len() > 64 {
+        return Ok(respond(
+            HttpResponse::BadRequest(),
+            "Bad Request",
+            "Usernames have to be between 1 and 64 characters.",
+        ));
+    }
+    if json
+        .password
+        .as_ref()
+        .is_some_and(|password| password.chars().count() < MIN_PASSWORD_LENGTH)
+    {
+        return Ok(respond(
+            HttpResponse::BadRequest(),
+            "Bad Request",
+            &format!(
+                "Passwords have to be at least {} characters.",
+                MIN_PASSWORD_LENGTH
+            ),
+        ));
+    }
+
+    let (password, generated) = match &json.password {
+        Some(password) => (password.clone(), None),
+        None => {
+            let password = generate_password();
+            (password.clone(), Some(password))
+        }
+    };
+    let mut login = Login::new(username, &password, json.role);
+
+    let created = auth.update(|accounts| {
+        if accounts.find(&login.username).is_some() {
+            return Err(respond(
+                HttpResponse::Conflict(),
+                "Conflict",
+                "An account with that username already exists.",
+            ));
+        }
+        login.email = check_email(
+            accounts,
+            &login.username,
+            json.email.as_deref().unwrap_or(""),
+        )?;
+        accounts.users.push(login.clone());
+        Ok(login)
+    })?;
+    let login = match created {
+        Ok(login) => login,
+        Err(response) => return Ok(response),
+    };
+
+    audit.record(
+        &admin.username,
+        &client_ip(&req),
+        "user_created",
+        json!({ "username": login.username, "role": login.role }),
+    )?;
+    tracing::info!(
+        "{} created the account {} as {:?}.",
+        admin.username,
+        login.username,
+        login.role
+    );
+
+    Ok(HttpResponse::Created()
+        .content_type("application/json")
+        .json(&CreatedUser {
+            username: login.username,
+            role: login.role,
+            password: generated,
+        }))
+}
+
+/*
+ * https://url.tld/api/admin/users/{username} - PUT
+ *
+ * Changes an account's role, password and/or email address.
+ * A new password has to be at least MIN_PASSWORD_LENGTH characters.
+ * The last admin can't be demoted, so there is always someone able to manage accounts.
+ */
+
+#[put("/api/admin/users/{username}")]
+pub async fn update_user(
+    req: HttpRequest,
+    session: Session,
+    auth: State<Arc<Auth>>,
+    audit: State<Arc<AuditLog>>,
+    username: Path<String>,
+    json: Json<UserUpdate>,
+) -> Result<HttpResponse, WebError> {
+    let admin = match authorize(&session, &auth, Role::Admin)? {
+        Access::Granted(login) => login,
+        Access::Denied(response) => return Ok(response),
+    };
+
+    if json
+        .password
+        .as_ref()
+        .is_some_and(|password| password.chars().count() < MIN_PASSWORD_LENGTH)
+    {
+        return Ok(respond(
+            HttpResponse::BadRequest(),
+            "Bad Request",
+            &format!(
+                "Passwords have to be at least {} characters.",
+                MIN_PASSWORD_LENGTH
+            ),
+        ));
+    }
+
+    let password_hash = json.password.as_deref().map(hash);
+    let updated = auth.update(|accounts| {
+        let admins = accounts.admins();
+        let email = json
+            .email
+            .as_deref()
+            .map(|email| check_email(accounts, &username, email))
+            .transpose()?;
+        let Some(login) = accounts
+            .users
+            .iter_mut()
+            .find(|login| login.username == *username)
+        else {
+            return Err(respond(
+                HttpResponse::NotFound(),
+                "Not Found",
+                "There is no account with that username.",
+            ));
+        };
+
+        if let Some(role) = json.role {
+            if login.role == Role::Admin && role != Role::Admin && admins == 1 {
+                return Err(respond(
+                    HttpResponse::Conflict(),
+                    "Conflict",
+                    "The last admin can't be demoted.",
+                ));
+            }
+            login.role = role;
+        }
+        if let Some(password_hash) = password_hash {
+            login.password_hash = password_hash;
+        }
+        if let Some(email) = email {
+            login.email = email;
+        }
+        Ok(())
+    })?;
+    if let Err(response) = updated {
+        return Ok(response);
+    }
+
+    audit.record(
+        &admin.username,
+        &client_ip(&req),
+        "user_updated",
+        json!({
+            "username": *username,
+            "role": json.role,
+            "password_changed": json.password.is_some(),
+            "email": json.email,
+        }),
+    )?;
+    tracing::info!("{} updated the account {}.", admin.username, *username);
+
+    Ok(respond(
+        HttpResponse::Ok(),
+        "Updated",
+        "The account has been updated.",
+    ))
+}
+
+/*
+ * https://url.tld/api/admin/users/{username} - DELETE
+ *
+ * Deletes an account, except for the last admin.
+ */
+
+#[delete("/api/admin/users/{username}")]
+pub async fn delete_user(
+    req: HttpRequest,
+    session: Session,
+    auth: State<Arc<Auth>>,
+    audit: State<Arc<AuditLog>>,
+    sessions: State<Arc<SessionStore>>,
+    username: Path<String>,
+) -> Result<HttpResponse, WebError> {
+    let admin = match authorize(&session, &auth, Role::Admin)? {
+        Access::Granted(login) => login,
+        Access::Denied(response) => return Ok(response),
+    };
+
+    let deleted = auth.update(|accounts| {
+        let Some(login) = accounts.find(&username) else {
+            return Err(respond(
+                HttpResponse::NotFound(),
+                "Not Found",
+                "There is no account with that username.",
+            ));
+        };
+
+        if login.role == Role::Admin && accounts.admins() == 1 {
+            return Err(respond(
+                HttpResponse::Conflict(),
+                "Conflict",
+                "The last admin can't be deleted.",
+            ));
+        }
+
+        accounts.users.retain(|login| login.username != *username);
+        Ok(())
+    })?;
+    if let Err(response) = deleted {
+        return Ok(response);
+    }
+    sessions.remove_user(&username);
+
+    audit.record(
+        &admin.username,
+        &client_ip(&req),
+        "user_deleted",
+        json!({ "username": *username }),
+    )?;
+    tracing::info!("{} deleted the account {}.", admin.username, *username);
+
+    Ok(respond(
+        HttpResponse::Ok(),
+        "Deleted",
+        "The account has been deleted.",
+    ))
+}
+
+/*
+ * https://url.tld/api/account/password - POST
+ *
+ * Changes the logged in account's own password, after confirming the current one.
+ * Every other session of the account stops working since their tokens cover the old hash,
+ * this session gets a fresh token so it stays logged in.
+ */
+
+#[post("/api/account/password")]
+pub async fn change_password(
+    req: HttpRequest,
+    session: Session,
+    auth: State<Arc<Auth>>,
+    audit: State<Arc<AuditLog>>,
+    json: Json<PasswordChange>,
+) -> Result<HttpResponse, WebError> {
+    let login = match authorize(&session, &auth, Role::Viewer)? {
+        Access::Granted(login) => login,
+        Access::Denied(response) => return Ok(response),
+    };
+
+    if login.external {
+        return Ok(respond(
+            HttpResponse::Conflict(),
+            "Conflict",
+            "Your account logs in through the identity provider, change your password there.",
+        ));
+    }
+    if !login.check_password(&json.current_password) {
+        return Ok(respond(
+            HttpResponse::Forbidden(),
+            "Forbidden",
+            "The current password is wrong.",
+        ));
+    }
+    if json.new_password.chars().count() < MIN_PASSWORD_LENGTH {
+        return Ok(respond(
+            HttpResponse::BadRequest(),
+            "Bad Request",
+            &format!(
+                "Passwords have to be at least {} characters.",
+                MIN_PASSWORD_LENGTH
+            ),
+        ));
+    }
+
+    let password_hash = hash(&json.new_password);
+    let updated = auth.update(|accounts| {
+        let Some(account) = accounts
+            .users
+            .iter_mut()
+            .find(|account| account.username == login.username)
+        else {
+            return Err(respond(
+                HttpResponse::NotFound(),
+                "Not Found",
+                "Your account doesn't exist anymore.",
+            ));
+        };
+        account.password_hash = password_hash;
+        Ok(account.clone())
+    })?;
+    let updated = match updated {
+        Ok(updated) => updated,
+        Err(response) => return Ok(response),
+    };
+
+    session.set("token", auth.token(&updated))?;
+
+    audit.record(
+        &login.username,
+        &client_ip(&req),
+        "password_changed",
+        json!({}),
+    )?;
+    tracing::info!("{} changed their password.", login.username);
+
+    Ok(respond(
+        HttpResponse::Ok(),
+        "Password changed",
+        "Your password has been changed, other sessions have been logged out.",
+    ))
+}
+
+/*
+ * https://url.tld/api/admin/users/{username}/sessions - DELETE
+ *
+ * Logs an account out everywhere by bumping its session epoch, which every token includes,
+ * and ends its sessions in the store.
+ */
+
+#[delete("/api/admin/users/{username}/sessions")]
+pub async fn revoke_sessions(
+    req: HttpRequest,
+    session: Session,
+    auth: State<Arc<Auth>>,
+    audit: State<Arc<AuditLog>>,
+    sessions: State<Arc<SessionStore>>,
+    username: Path<String>,
+) -> Result<HttpResponse, WebError> {
+    let admin = match authorize(&session, &auth, Role::Admin)? {
+        Access::Granted(login) => login,
+        Access::Denied(response) => return Ok(response),
+    };
+
+    let revoked = auth.update(|accounts| {
+        let Some(login) = accounts
+            .users
+            .iter_mut()
+            .find(|login| login.username == *username)
+        else {
+            return Err(respond(
+                HttpResponse::NotFound(),
+                "Not Found",
+                "There is no account with that username.",
+            ));
+        };
+        login.session_epoch += 1;
+        Ok(())
+    })?;
+    if let Err(response) = revoked {
+        return Ok(response);
+    }
+    let ended = sessions.remove_user(&username);
+
+    audit.record(
+        &admin.username,
+        &client_ip(&req),
+        "sessions_revoked",
+        json!({ "username": *username, "sessions": ended }),
+    )?;
+    tracing::info!("{} revoked every session of {}.", admin.username, *username);
+
+    Ok(respond(
+        HttpResponse::Ok(),
+        "Revoked",
+        "Every session of the account has been logged out.",
+    ))
+}
+
+/*
+ * https://url.tld/api/account/totp - POST
+ *
+ * Starts two-factor enrollment for the logged in account with a new secret.
+ * Nothing changes at login until the enrollment is confirmed.
+ */
+
+#[post("/api/account/totp")]
+pub async fn enroll_totp(
+    session: Session,
+    auth: State<Arc<Auth>>,
+) -> Result<HttpResponse, WebError> {
+    let login = match authorize(&session, &auth, Role::Viewer)? {
+        Access::Granted(login) => login,
+        Access::Denied(response) => return Ok(response),
+    };
+    if login.external {
+        return Ok(respond(
+            HttpResponse::Conflict(),
+            "Conflict",
+            "Your account logs in through the identity provider, set up two-factor authentication there.",
+        ));
+    }
+    if login.requires_second_factor() {
+        return Ok(respond(
+            HttpResponse::Conflict(),
+            "Conflict",
+            "Two-factor authentication is already on, ask an admin to reset it first.",
+        ));
+    }
+
+    let second_factor = SecondFactor::generate();
+    let Some(uri) = second_factor.uri(&login.username) else {
+        return Ok(HttpResponse::InternalServerError().finish());
+    };
+    let secret = second_factor.secret.clone();
+
+    let enrolled = auth.update(|accounts| {
+        let Some(account) = accounts
+            .users
+            .iter_mut()
+            .find(|account| account.username == login.username)
+        else {
+            return Err(respond(
+                HttpResponse::NotFound(),
+                "Not Found",
+                "Your account doesn't exist anymore.",
+            ));
+        };
+        if account.requires_second_factor() {
+            return Err(respond(
+                HttpResponse::Conflict(),
+                "Conflict",
+                "Two-factor authentication is already on, ask an admin to reset it first.",
+            ));
+        }
+        account.second_factor = Some(second_factor);
+        Ok(())
+    })?;
+    if let Err(response) = enrolled {
+        return Ok(response);
+    }
+
+    Ok(HttpResponse::Ok()
+        .content_type("application/json")
+        .json(&Enrollment { secret, uri }))
+}
+
+/*
+ * https://url.tld/api/account/totp/confirm - POST
+ *
+ * Finishes enrollment with a code from the authenticator app, turning two-factor authentication on
+ * and handing out the recovery codes.
+ */
+
+#[post("/api/account/totp/confirm")]
+pub async fn confirm_totp(
+    req: HttpRequest,
+    session: Session,
+    auth: State<Arc<Auth>>,
+    audit: State<Arc<AuditLog>>,
+    json: Json<EnrollmentConfirmation>,
+) -> Result<HttpResponse, WebError> {
+    let login = match authorize(&session, &auth, Role::Viewer)? {
+        Access::Granted(login) => login,
+        Access::Denied(response) => return Ok(response),
+    };
+
+    let now = Utc::now().timestamp() as u64;
+    let confirmed = auth.update(|accounts| {
+        let Some(second_factor) = accounts
+            .users
+            .iter_mut()
+            .find(|account| account.username == login.username)
+            .and_then(|account| account.second_factor.as_mut())
+            .filter(|second_factor| !second_factor.confirmed)
+        else {
+            return Err(respond(
+                HttpResponse::Conflict(),
+                "Conflict",
+                "There is no enrollment to confirm, start one first.",
+            ));
+        };
+
+        if !second_factor.verify(&login.username, &json.code, now) {
+            return Err(respond(
+                HttpResponse::BadRequest(),
+                "Bad Request",
+                "That code isn't valid, check the time on your device.",
+            ));
+        }
+        second_factor.confirmed = true;
+        Ok(second_factor.generate_recovery_codes())
+    })?;
+    let recovery_codes = match confirmed {
+        Ok(recovery_codes) => recovery_codes,
+        Err(response) => return Ok(response),
+    };
+
+    audit.record(
+        &login.username,
+        &client_ip(&req),
+        "second_factor_enabled",
+        json!({}),
+    )?;
+    tracing::info!("{} turned on two-factor authentication.", login.username);
+
+    Ok(HttpResponse::Ok()
+        .content_type("application/json")
+        .json(&RecoveryCodes { recovery_codes }))
+}
+
+/*
+ * https://url.tld/api/admin/users/{username}/totp - DELETE
+ *
+ * Turns off another account's second factor, for when they lost their device and recovery codes.
+ */
+
+#[delete("/api/admin/users/{username}/totp")]
+pub async fn reset_totp(
+    req: HttpRequest,
+    session: Session,
+    auth: State<Arc<Auth>>,
+    audit: State<Arc<AuditLog>>,
+    username: Path<String>,
+) -> Result<HttpResponse, WebError> {
+    let admin = match authorize(&session, &auth, Role::Admin)? {
+        Access::Granted(login) => login,
+        Access::Denied(response) => return Ok(response),
+    };
+
+    let reset = auth.update(|accounts| {
+        let Some(login) = accounts
+            .users
+            .iter_mut()
+            .find(|login| login.username == *username)
+        else {
+            return Err(respond(
+                HttpResponse::NotFound(),
+                "Not Found",
+                "There is no account with that username.",
+            ));
+        };
+        login.second_factor = None;
+        Ok(())
+    })?;
+    if let Err(response) = reset {
+        return Ok(response);
+    }
+
+    audit.record(
+        &admin.username,
+        &client_ip(&req),
+        "second_factor_reset",
+        json!({ "username": *username }),
+    )?;
+    tracing::info!(
+        "{} reset the second factor of {}.",
+        admin.username,
+        *username
+    );
+
+    Ok(respond(
+        HttpResponse::Ok(),
+        "Reset",
+        "Two-factor authentication is off for the account, they can enroll again.",
+    ))
+}
+
+/*
+ * https://url.tld/api/admin/api_keys
+ *
+ * Lists every API key, revoked and expired ones included. The keys themselves are never shown again.
+ */
+
+#[get("/api/admin/api_keys")]
+pub async fn list_api_keys(
+    session: Session,
+    auth: State<Arc<Auth>>,
+    api_keys: State<Arc<ApiKeys>>,
+) -> Result<HttpResponse, WebError> {
+    if let Access::Denied(response) = authorize(&session, &auth, Role::Admin)? {
+        return Ok(response);
+    }
+
+    let keys = api_keys.list().clone();
+
+    Ok(HttpResponse::Ok()
+        .content_type("application/json")
+        .json(&keys))
+}
+
+/*
+ * https://url.tld/api/admin/api_keys - POST
+ *
+ * Creates a named API key with the given scopes, for scripts and info screens to exchange at /api/token.
+ */
+
+#[post("/api/admin/api_keys")]
+pub async fn create_api_key(
+    req: HttpRequest,
+    session: Session,
+    auth: State<Arc<Auth>>,
+    audit: State<Arc<AuditLog>>,
+    api_keys: State<Arc<ApiKeys>>,
+    json: Json<NewApiKey>,
+) -> Result<HttpResponse, WebError> {
+    let admin = match authorize(&session, &auth, Role::Admin)? {
+        Access::Granted(login) => login,
+        Access::Denied(response) => return Ok(response),
+    };
+
+    let name = json.name.trim().to_string();
+    if name.is_empty() || name.len() > 64 {
+        return Ok(respond(
+            HttpResponse::BadRequest(),
+            "Bad Request",
+            "Names have to be between 1 and 64 characters.",
+        ));
+    }
+    if json.scopes.is_empty() {
+        return Ok(respond(
+            HttpResponse::BadRequest(),
+            "Bad Request",
+            "An API key needs at least one scope.",
+        ));
+    }
+    if matches!(json.expires_in_days, Some(days) if days <= 0 || days > MAX_KEY_DAYS) {
+        return Ok(respond(
+            HttpResponse::BadRequest(),
+            "Bad Request",
+            &format!(
+                "expires_in_days has to be between 1 and {}, leave it out for a key that doesn't expire.",
+                MAX_KEY_DAYS
+            ),
+        ));
+    }
+
+    let now = Utc::now().timestamp();
+    let expires = json.expires_in_days.map(|days| now + days * 24 * 60 * 60);
+    let (key, api_key) = api_keys.create(
+        name,
+        json.scopes.clone(),
+        admin.username.clone(),
+        expires,
+        now,
+    )?;
+
+    audit.record(
+        &admin.username,
+        &client_ip(&req),
+        "api_key_created",
+        json!({ "id": key.id, "name": key.name, "scopes": key.scopes, "expires": key.expires }),
+    )?;
+    tracing::info!(
+        "{} created the API key {} ({}).",
+        admin.username,
+        key.name,
+        key.id
+    );
+
+    Ok(HttpResponse::Created()
+        .content_type("application/json")
+        .json(&CreatedApiKey { key, api_key }))
+}
+
+/*
+ * https://url.tld/api/admin/api_keys/{id} - DELETE
+ *
+ * Revokes an API key, tokens it was exchanged for stop working right away.
+ */
+
+#[delete("/api/admin/api_keys/{id}")]
+pub async fn revoke_api_key(
+    req: HttpRequest,
+    session: Session,
+    auth: State<Arc<Auth>>,
+    audit: State<Arc<AuditLog>>,
+    api_keys: State<Arc<ApiKeys>>,
+    id: Path<String>,
+) -> Result<HttpResponse, WebError> {
+    let admin = match authorize(&session, &auth, Role::Admin)? {
+        Access::Granted(login) => login,
+        Access::Denied(response) => return Ok(response),
+    };
+
+    let Some(key) = api_keys.revoke(&id, Utc::now().timestamp())? else {
+        return Ok(respond(
+            HttpResponse::NotFound(),
+            "Not Found",
+            "There is no API key with that id.",
+        ));
+    };
+
+    audit.record(
+        &admin.username,
+        &client_ip(&req),
+        "api_key_revoked",
+        json!({ "id": key.id, "name": key.name }),
+    )?;
+    tracing::info!(
+        "{} revoked the API key {} ({}).",
+        admin.username,
+        key.name,
+        key.id
+    );
+
+    Ok(respond(
+        HttpResponse::Ok(),
+        "Revoked",
+        "The API key has been revoked.",
+    ))
+}
+
+/*
+ * https://url.tld/api/admin/sessions
+ *
+ * Lists every session someone is logged in on, with when and from where it was last used.
+ */
+
+#[get("/api/admin/sessions")]
+pub async fn list_sessions(
+    session: Session,
+    auth: State<Arc<Auth>>,
+    sessions: State<Arc<SessionStore>>,
+) -> Result<HttpResponse, WebError> {
+    if let Access::Denied(response) = authorize(&session, &auth, Role::Admin)? {
+        return Ok(response);
+    }
+
+    Ok(HttpResponse::Ok()
+        .content_type("application/json")
+        .json(&sessions.list()))
+}
+
+/*
+ * https://url.tld/api/admin/sessions/{id} - DELETE
+ *
+ * Ends a single session, whoever uses it is logged out on their next request.
+ */
+
+#[delete("/api/admin/sessions/{id}")]
+pub async fn revoke_session(
+    req: HttpRequest,
+    session: Session,
+    auth: State<Arc<Auth>>,
+    audit: State<Arc<AuditLog>>,
+    sessions: State<Arc<SessionStore>>,
+    id: Path<String>,
+) -> Result<HttpResponse, WebError> {
+    let admin = match authorize(&session, &auth, Role::Admin)? {
+        Access::Granted(login) => login,
+        Access::Denied(response) => return Ok(response),
+    };
+
+    let Some(revoked) = sessions.remove(&id) else {
+        return Ok(respond(
+            HttpResponse::NotFound(),
+            "Not Found",
+            "There is no session with that id.",
+        ));
+    };
+
+    audit.record(
+        &admin.username,
+        &client_ip(&req),
+        "session_revoked",
+        json!({ "id": revoked.id, "username": revoked.user, "ip": revoked.ip }),
+    )?;
+    tracing::info!(
+        "{} revoked a session of {} from {}.",
+        admin.username,
+        revoked.user,
+        revoked.ip
+    );
+
+    Ok(respond(
+        HttpResponse::Ok(),
+        "Revoked",
+        "The session has been logged out.",
+    ))
+}